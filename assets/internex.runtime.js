@@ -76,6 +76,7 @@
   var _xhrOpen          = XMLHttpRequest.prototype.open;
   var _WebSocket        = window.WebSocket;
   var _EventSource      = window.EventSource;
+  var _WebTransport     = window.WebTransport;
   var _sendBeacon       = navigator.sendBeacon
                             ? navigator.sendBeacon.bind(navigator) : null;
   var _Worker           = window.Worker;
@@ -283,6 +284,34 @@
     return html;
   }
 
+  // ---- Trusted Types ------------------------------------------------------
+  // Pages that enforce `require-trusted-types-for 'script'` hand the DOM
+  // sinks TrustedHTML / TrustedScriptURL objects, which rewriteHtml and
+  // rewriteUrl would pass through untouched.  Rewriting one means turning it
+  // into a string and back, which takes a policy of our own; the rewritten
+  // CSP allows its name (TRUSTED_TYPES_POLICY in csp.rs).  Plain strings
+  // stay strings, so the page's default policy still sees them.
+  var _tt       = window.trustedTypes;
+  var _ttPolicy = null;
+  try {
+    if (_tt && _tt.createPolicy) {
+      _ttPolicy = _tt.createPolicy("internex", {
+        createHTML:      function (s) { return s; },
+        createScriptURL: function (s) { return s; },
+      });
+    }
+  } catch (_) { /* policy name refused by the page's CSP */ }
+
+  function trustedHtml(html) {
+    if (_ttPolicy && _tt.isHTML(html)) return _ttPolicy.createHTML(rewriteHtml(String(html)));
+    return rewriteHtml(html);
+  }
+
+  function trustedScriptUrl(url) {
+    if (_ttPolicy && _tt.isScriptURL(url)) return _ttPolicy.createScriptURL(rewriteUrl(String(url)));
+    return rewriteUrl(url);
+  }
+
   // ---- CSS url(…) rewriter ------------------------------------------------
   var _cssUrlRe = /url\(\s*["']?([^"')\s]+?)["']?\s*\)/gi;
 
@@ -311,6 +340,53 @@
     return css;
   }
 
+  // ---- Tagged templates (lit-html, styled-components, …) ------------------
+  var _htmlUrlTailRe = /(?:src|href|action|poster|formaction|background|data|cite)\s*=\s*["']?$/i;
+  var _cssUrlTailRe  = /url\(\s*["']?$/i;
+  var _taggedCache   = typeof WeakMap === "function" ? new WeakMap() : null;
+
+  /** Wrap a template tag so URLs in its static strings, and string
+   *  interpolations in URL position, route through the proxy.  The rewritten
+   *  strings array is cached per original, since lit-html keys its template
+   *  cache on that array's identity. */
+  function tagged(tag, kind) {
+    if (typeof tag !== "function") return tag;
+    var rewrite = kind === "css" ? rewriteCssText : rewriteHtml;
+    var urlTail = kind === "css" ? _cssUrlTailRe : _htmlUrlTailRe;
+    return function (strings) {
+      if (!strings || !strings.raw) return tag.apply(this, arguments);
+      var out = _taggedCache && _taggedCache.get(strings);
+      if (!out) {
+        out = Array.prototype.map.call(strings, rewrite);
+        out.raw = Object.freeze(Array.prototype.map.call(strings.raw, rewrite));
+        Object.freeze(out);
+        if (_taggedCache) _taggedCache.set(strings, out);
+      }
+      var args = [out];
+      for (var i = 1; i < arguments.length; i++) {
+        var v = arguments[i];
+        if (typeof v === "string" && urlTail.test(strings[i - 1])) v = rewriteUrl(v);
+        args.push(v);
+      }
+      return tag.apply(this, args);
+    };
+  }
+
+  /**
+   * Upstream value of a `document` / `location` property the JS rewriter
+   * routed here (`document.baseURI`, `location.origin`, …).  Any other
+   * object is read as-is, so a shadowing local stays untouched.
+   */
+  function upstream(obj, prop) {
+    var v = obj[prop];
+    if (obj === window.location && (prop === "origin" || prop === "host" || prop === "hostname")) {
+      var u = targetURL();
+      return u ? u[prop] : v;
+    }
+    if (obj === window.document && typeof v === "string") return decodeUrl(v);
+    return v;
+  }
+
   // ---- Expose globally ----------------------------------------------------
   window.__internex_rewrite_url = rewriteUrl;
   window.__internex_decode_url  = decodeUrl;
@@ -319,8 +395,13 @@
     base:       getBaseURL(),
     encode:     rewriteUrl,
     decode:     decodeUrl,
+    unrewriteUrl: decodeUrl,
     rewriteUrl: rewriteUrl,
+    rewriteSrcset: rewriteSrcset,
     rewriteHtml:rewriteHtml,
+    rewriteCss: rewriteCssValue,
+    tagged:     tagged,
+    upstream:   upstream,
     scope:      function (_ctx, fn) { return fn; },
   };
 
@@ -376,6 +457,14 @@
     window.EventSource.CLOSED     = 2;
   }
 
+  // WebTransport
+  if (_WebTransport) {
+    window.WebTransport = function (url, opts) {
+      return new _WebTransport(rewriteUrl(url), opts);
+    };
+    window.WebTransport.prototype = _WebTransport.prototype;
+  }
+
   // sendBeacon
   if (_sendBeacon) {
     navigator.sendBeacon = function (url, data) {
@@ -385,11 +474,11 @@
 
   // Worker / SharedWorker
   if (_Worker) {
-    window.Worker = function (url, opts) { return new _Worker(rewriteUrl(url), opts); };
+    window.Worker = function (url, opts) { return new _Worker(trustedScriptUrl(url), opts); };
     window.Worker.prototype = _Worker.prototype;
   }
   if (_SharedWorker) {
-    window.SharedWorker = function (url, opts) { return new _SharedWorker(rewriteUrl(url), opts); };
+    window.SharedWorker = function (url, opts) { return new _SharedWorker(trustedScriptUrl(url), opts); };
     window.SharedWorker.prototype = _SharedWorker.prototype;
   }
 
@@ -430,7 +519,7 @@
               return typeof v === "string" ? decodeUrl(v) : v;
             }
           : undefined,
-        set: function (v) { d.set.call(this, rewriteUrl(v)); },
+        set: function (v) { d.set.call(this, trustedScriptUrl(v)); },
         configurable: true,
         enumerable: true,
       });
@@ -500,7 +589,7 @@
       } catch (_) { /* */ }
       return _setAttribute.call(this, name, value);
     }
-    if (URL_ATTR_SET.has(lower))     value = rewriteUrl(value);
+    if (URL_ATTR_SET.has(lower))     value = trustedScriptUrl(value);
     else if (lower === "srcset" || lower === "imagesrcset")     value = rewriteSrcset(value);
     else if (lower === "style")      value = rewriteCssValue(value);
     return _setAttribute.call(this, name, value);
@@ -544,7 +633,7 @@
   if (_d_innerHTML && _d_innerHTML.set) {
     Object.defineProperty(Element.prototype, "innerHTML", {
       get: _d_innerHTML.get,
-      set: function (html) { _d_innerHTML.set.call(this, rewriteHtml(html)); },
+      set: function (html) { _d_innerHTML.set.call(this, trustedHtml(html)); },
       configurable: true, enumerable: true,
     });
   }
@@ -553,7 +642,7 @@
   if (_d_outerHTML && _d_outerHTML.set) {
     Object.defineProperty(Element.prototype, "outerHTML", {
       get: _d_outerHTML.get,
-      set: function (html) { _d_outerHTML.set.call(this, rewriteHtml(html)); },
+      set: function (html) { _d_outerHTML.set.call(this, trustedHtml(html)); },
       configurable: true, enumerable: true,
     });
   }
@@ -563,7 +652,7 @@
     try {
       Object.defineProperty(ShadowRoot.prototype, "innerHTML", {
         get: _d_shadowInnerHTML.get,
-        set: function (html) { _d_shadowInnerHTML.set.call(this, rewriteHtml(html)); },
+        set: function (html) { _d_shadowInnerHTML.set.call(this, trustedHtml(html)); },
         configurable: true, enumerable: true,
       });
     } catch (_) { /* */ }
@@ -571,18 +660,18 @@
 
   // --- insertAdjacentHTML --------------------------------------------------
   Element.prototype.insertAdjacentHTML = function (pos, html) {
-    return _insertAdjacentHTML.call(this, pos, rewriteHtml(html));
+    return _insertAdjacentHTML.call(this, pos, trustedHtml(html));
   };
 
   // --- document.write / writeln --------------------------------------------
   document.write = function () {
     var a = new Array(arguments.length);
-    for (var i = 0; i < a.length; i++) a[i] = rewriteHtml(arguments[i]);
+    for (var i = 0; i < a.length; i++) a[i] = trustedHtml(arguments[i]);
     return _docWrite.apply(document, a);
   };
   document.writeln = function () {
     var a = new Array(arguments.length);
-    for (var i = 0; i < a.length; i++) a[i] = rewriteHtml(arguments[i]);
+    for (var i = 0; i < a.length; i++) a[i] = trustedHtml(arguments[i]);
     return _docWriteln.apply(document, a);
   };
 
@@ -645,13 +734,13 @@
    * ═══════════════════════════════════════════════════════════════════════ */
 
   DOMParser.prototype.parseFromString = function (str, type) {
-    if (type && type.indexOf("html") !== -1) str = rewriteHtml(str);
+    if (type && type.indexOf("html") !== -1) str = trustedHtml(str);
     return _DOMParse.call(this, str, type);
   };
 
   if (_createFrag) {
     Range.prototype.createContextualFragment = function (html) {
-      return _createFrag.call(this, rewriteHtml(html));
+      return _createFrag.call(this, trustedHtml(html));
     };
   }
 
@@ -662,7 +751,8 @@
   // history
   History.prototype.pushState = function (state, title, url) {
     if (url) {
-      var abs = resolveAbsolute(String(url));
+      // The JS rewriter may already have routed url through rewriteUrl.
+      var abs = resolveAbsolute(decodeUrl(String(url)));
       if (abs) setBase(abs);
       url = rewriteUrl(String(url));
     }
@@ -670,7 +760,8 @@
   };
   History.prototype.replaceState = function (state, title, url) {
     if (url) {
-      var abs = resolveAbsolute(String(url));
+      // The JS rewriter may already have routed url through rewriteUrl.
+      var abs = resolveAbsolute(decodeUrl(String(url)));
       if (abs) setBase(abs);
       url = rewriteUrl(String(url));
     }
@@ -958,7 +1049,10 @@
       var v = _getAttribute.call(el, attr);
       if (!v) return;
       var r = rewriteUrl(v);
-      if (r !== v) _setAttribute.call(el, attr, r);
+      if (r === v) return;
+      // A <script src> under Trusted Types only takes a TrustedScriptURL.
+      if (_ttPolicy && el.tagName === "SCRIPT" && attr === "src") r = _ttPolicy.createScriptURL(r);
+      _setAttribute.call(el, attr, r);
     });
 
     // srcset
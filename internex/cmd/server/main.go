@@ -5,7 +5,9 @@ import (
 	"net/http"
 	"os"
 	"path/filepath"
+	"strconv"
 
+	"internex/internal/rewriter"
 	"internex/internal/transport"
 )
 
@@ -22,6 +24,15 @@ func main() {
 	}
 	transport.ProxyOrigin = "http://" + host + ":" + port
 
+	// Shared by every instance behind one cache, so each recognises the
+	// others' rewritten output.  Unset disables the rewritten marker.
+	rewriter.MarkerSecret = os.Getenv("MARKER_SECRET")
+
+	// Serve data: URLs above this many bytes from /blob/<id> (0 = never).
+	if n, err := strconv.Atoi(os.Getenv("DATA_URL_THRESHOLD")); err == nil && n > 0 {
+		transport.DataURLThreshold = n
+	}
+
 	// Determine assets directory (default: ../../../assets relative to binary).
 	assetsDir := os.Getenv("ASSETS_DIR")
 	if assetsDir == "" {
@@ -7,17 +7,31 @@ package rewriter
 #include <stdlib.h>
 
 extern char* rewrite_html(const char* input);
+extern char* rewrite_html_resources(const char* input);
+extern unsigned char* rewrite_html_bytes(const char* input, const unsigned char* content, size_t len, size_t* out_len);
 extern char* rewrite_css(const char* input);
+extern unsigned char* rewrite_css_bytes(const char* input, const unsigned char* content, size_t len, size_t* out_len);
 extern char* rewrite_js(const char* input);
+extern unsigned char* rewrite_js_bytes(const char* input, const unsigned char* content, size_t len, size_t* out_len);
+extern char* rewrite_json(const char* input);
+extern char* rewrite_hls(const char* input);
+extern char* rewrite_dash(const char* input);
+extern char* rewrite_appcache(const char* input);
+extern char* rewrite_vtt(const char* input);
+extern char* rewrite_with_blobs(const char* input);
+extern char* rewrite_url(const char* input);
+extern char* decode_url_ffi(const char* input);
+extern char* internex_version(void);
 extern void  free_string(char* ptr);
+extern void  free_bytes(unsigned char* ptr, size_t len);
 */
 import "C"
 
 import (
+	"bytes"
 	"encoding/json"
 	"fmt"
 	"io"
-	"strings"
 	"unsafe"
 )
 
@@ -30,11 +44,23 @@ const (
 	JS
 )
 
+// MarkerSecret keys the marker the rewriter appends to rewritten documents
+// so that a second pass over them is a no-op.  Every proxy instance that
+// shares a cache or chains to another should use the same secret.  Empty
+// disables the marker, and every pass rewrites in full.
+var MarkerSecret string
+
 // rewriteInput is the JSON envelope sent to the Rust FFI functions.
 type rewriteInput struct {
-	ProxyOrigin string `json:"proxy_origin"`
-	BaseURL     string `json:"base_url"`
-	Content     string `json:"content"`
+	ProxyOrigin  string `json:"proxy_origin"`
+	BaseURL      string `json:"base_url"`
+	Content      string `json:"content"`
+	MarkerSecret string `json:"marker_secret,omitempty"`
+}
+
+// newInput builds the envelope for content, carrying MarkerSecret.
+func newInput(proxyOrigin, baseURL, content string) rewriteInput {
+	return rewriteInput{ProxyOrigin: proxyOrigin, BaseURL: baseURL, Content: content, MarkerSecret: MarkerSecret}
 }
 
 // RewriteHTML rewrites an HTML document through the Rust rewriter.
@@ -42,28 +68,253 @@ func RewriteHTML(proxyOrigin, baseURL, content string) string {
 	return callRewrite("html", proxyOrigin, baseURL, content)
 }
 
+// HTMLOptions turns off inline transforms for deployments whose client-side
+// runtime handles inline scripts, styles and event handlers itself.  URL
+// attributes are always rewritten.  The zero value rewrites everything.
+type HTMLOptions struct {
+	SkipInlineScripts bool
+	SkipInlineStyles  bool
+	SkipEventHandlers bool
+
+	// CSP is the upstream Content-Security-Policy header.  Its style nonce
+	// is stamped onto <style> elements whose content was rewritten.
+	CSP string
+}
+
+// RewriteHTMLWithOptions is RewriteHTML with the settings in opts.
+func RewriteHTMLWithOptions(proxyOrigin, baseURL, content string, opts HTMLOptions) string {
+	payload, err := json.Marshal(struct {
+		rewriteInput
+		RewriteInlineScripts bool   `json:"rewrite_inline_scripts"`
+		RewriteInlineStyles  bool   `json:"rewrite_inline_styles"`
+		RewriteEventHandlers bool   `json:"rewrite_event_handlers"`
+		CSP                  string `json:"csp,omitempty"`
+	}{
+		newInput(proxyOrigin, baseURL, content),
+		!opts.SkipInlineScripts,
+		!opts.SkipInlineStyles,
+		!opts.SkipEventHandlers,
+		opts.CSP,
+	})
+	if err != nil {
+		return content
+	}
+	return callRewritePayload("html", payload, content)
+}
+
+// Resource is a sub-resource URL discovered while rewriting HTML.  URL is the
+// original, pre-proxy absolute URL.
+type Resource struct {
+	URL  string `json:"url"`
+	Kind string `json:"kind"`
+}
+
+// RewriteHTMLWithResources rewrites an HTML document and also returns the
+// sub-resources it loads, e.g. for cache warming, and the number of URLs it
+// rewrote.  On failure the content is returned unchanged with no resources.
+func RewriteHTMLWithResources(proxyOrigin, baseURL, content string) (string, []Resource, int) {
+	raw := callRewrite("html_resources", proxyOrigin, baseURL, content)
+
+	var out struct {
+		HTML          string     `json:"html"`
+		Resources     []Resource `json:"resources"`
+		URLsRewritten int        `json:"urls_rewritten"`
+	}
+	if err := json.Unmarshal([]byte(raw), &out); err != nil {
+		return content, nil, 0
+	}
+	return out.HTML, out.Resources, out.URLsRewritten
+}
+
+// RewriteHTMLBytes rewrites an HTML document in any charset.  charset is the
+// Content-Type charset, or "" to sniff it from the document.  The result is
+// always UTF-8; on failure the body is returned as-is.
+func RewriteHTMLBytes(proxyOrigin, baseURL, charset string, body []byte) []byte {
+	return callRewriteBytes("html", proxyOrigin, baseURL, charset, body)
+}
+
 // RewriteCSS rewrites a CSS stylesheet through the Rust rewriter.
 func RewriteCSS(proxyOrigin, baseURL, content string) string {
 	return callRewrite("css", proxyOrigin, baseURL, content)
 }
 
+// RewriteCSSBytes is RewriteCSS for a raw body, e.g. straight out of a
+// decompressor.  charset is the Content-Type charset, or "" for UTF-8.  The
+// result is always UTF-8; on failure the body is returned as-is.
+func RewriteCSSBytes(proxyOrigin, baseURL, charset string, body []byte) []byte {
+	return callRewriteBytes("css", proxyOrigin, baseURL, charset, body)
+}
+
 // RewriteJS rewrites JavaScript source through the Rust rewriter.
 func RewriteJS(proxyOrigin, baseURL, content string) string {
 	return callRewrite("js", proxyOrigin, baseURL, content)
 }
 
+// RewriteJSBytes is RewriteJS for a raw body; see RewriteCSSBytes.
+func RewriteJSBytes(proxyOrigin, baseURL, charset string, body []byte) []byte {
+	return callRewriteBytes("js", proxyOrigin, baseURL, charset, body)
+}
+
+// RewriteJSON rewrites absolute URLs inside a JSON document through the Rust
+// rewriter.  This is opt-in: callers decide which responses carry URLs.
+func RewriteJSON(proxyOrigin, baseURL, content string) string {
+	return callRewrite("json", proxyOrigin, baseURL, content)
+}
+
+// RewriteHLS rewrites an HLS (.m3u8) playlist through the Rust rewriter.
+func RewriteHLS(proxyOrigin, baseURL, content string) string {
+	return callRewrite("hls", proxyOrigin, baseURL, content)
+}
+
+// RewriteDASH rewrites a DASH (.mpd) manifest through the Rust rewriter.
+func RewriteDASH(proxyOrigin, baseURL, content string) string {
+	return callRewrite("dash", proxyOrigin, baseURL, content)
+}
+
+// RewriteAppCache rewrites a legacy AppCache (text/cache-manifest) manifest
+// through the Rust rewriter.
+func RewriteAppCache(proxyOrigin, baseURL, content string) string {
+	return callRewrite("appcache", proxyOrigin, baseURL, content)
+}
+
+// RewriteVTT rewrites a WebVTT (.vtt) text track through the Rust rewriter.
+func RewriteVTT(proxyOrigin, baseURL, content string) string {
+	return callRewrite("vtt", proxyOrigin, baseURL, content)
+}
+
+// Blob is a large `data:` URL payload that RewriteWithBlobs moved out of a
+// document.  The rewritten document refers to it as <proxyOrigin>/blob/<ID>.
+type Blob struct {
+	ID   string `json:"id"`
+	MIME string `json:"mime"`
+	Data []byte `json:"data"`
+}
+
+// RewriteWithBlobs rewrites an "html", "css", "js" or "json" document like
+// the per-kind functions, but replaces `data:` URLs longer than maxDataLen
+// bytes with blob URLs and returns their payloads for the caller to serve.
+// On failure the content is returned unchanged with no blobs.
+func RewriteWithBlobs(kind, proxyOrigin, baseURL, content string, maxDataLen int) (string, []Blob) {
+	payload, err := json.Marshal(struct {
+		rewriteInput
+		Kind       string `json:"kind"`
+		MaxDataLen int    `json:"max_data_len"`
+	}{
+		newInput(proxyOrigin, baseURL, content),
+		kind,
+		maxDataLen,
+	})
+	if err != nil {
+		return content, nil
+	}
+	raw := callRewritePayload("with_blobs", payload, "")
+	if raw == "" {
+		return content, nil
+	}
+
+	var out struct {
+		Content string `json:"content"`
+		Blobs   []Blob `json:"blobs"`
+	}
+	if err := json.Unmarshal([]byte(raw), &out); err != nil {
+		return content, nil
+	}
+	return out.Content, out.Blobs
+}
+
+// RewriteURL routes a single URL, resolved against baseURL, through the
+// proxy, e.g. for a computed redirect.  ok is false when the URL cannot be
+// proxied (file: URLs, empty input).
+func RewriteURL(proxyOrigin, baseURL, rawURL string) (string, bool) {
+	payload, err := json.Marshal(struct {
+		ProxyOrigin string `json:"proxy_origin"`
+		BaseURL     string `json:"base_url"`
+		URL         string `json:"url"`
+	}{proxyOrigin, baseURL, rawURL})
+	if err != nil {
+		return "", false
+	}
+
+	cInput := C.CString(string(payload))
+	defer C.free(unsafe.Pointer(cInput))
+
+	cResult := C.rewrite_url(cInput)
+	if cResult == nil {
+		return "", false
+	}
+	defer C.free_string(cResult)
+
+	return C.GoString(cResult), true
+}
+
+// DecodeURL turns a `url=` query value back into the upstream URL using the
+// same decoding as the Rust rewriter.  ok is false when the value does not
+// decode to an absolute URL.
+func DecodeURL(encoded string) (string, bool) {
+	return callDecodeURL(map[string]string{"encoded": encoded})
+}
+
+// DecodeURLWithBase is DecodeURL, but relative values are resolved against
+// base.
+func DecodeURLWithBase(encoded, base string) (string, bool) {
+	return callDecodeURL(map[string]string{"encoded": encoded, "base": base})
+}
+
+// DecodeProxyURL is DecodeURL for a request to proxyOrigin: nested proxy
+// URLs are peeled only when they belong to proxyOrigin; another host's
+// /proxy?url= is kept.
+func DecodeProxyURL(proxyOrigin, encoded string) (string, bool) {
+	return callDecodeURL(map[string]string{"proxy_origin": proxyOrigin, "encoded": encoded})
+}
+
+// DecodeProxyURLWithBase is DecodeProxyURL, but relative values are resolved
+// against base.
+func DecodeProxyURLWithBase(proxyOrigin, encoded, base string) (string, bool) {
+	return callDecodeURL(map[string]string{"proxy_origin": proxyOrigin, "encoded": encoded, "base": base})
+}
+
+func callDecodeURL(input map[string]string) (string, bool) {
+	payload, err := json.Marshal(input)
+	if err != nil {
+		return "", false
+	}
+
+	cInput := C.CString(string(payload))
+	defer C.free(unsafe.Pointer(cInput))
+
+	cResult := C.decode_url_ffi(cInput)
+	if cResult == nil {
+		return "", false
+	}
+	defer C.free_string(cResult)
+
+	return C.GoString(cResult), true
+}
+
+// Version returns the version string of the loaded Rust rewriter library.
+func Version() string {
+	cResult := C.internex_version()
+	if cResult == nil {
+		return ""
+	}
+	defer C.free_string(cResult)
+
+	return C.GoString(cResult)
+}
+
 // callRewrite marshals the input into JSON, calls the given Rust FFI function,
 // converts the result back to a Go string, and frees the Rust-allocated memory.
 func callRewrite(kind string, proxyOrigin, baseURL, content string) string {
-	payload, err := json.Marshal(rewriteInput{
-		ProxyOrigin: proxyOrigin,
-		BaseURL:     baseURL,
-		Content:     content,
-	})
+	payload, err := json.Marshal(newInput(proxyOrigin, baseURL, content))
 	if err != nil {
 		return content
 	}
+	return callRewritePayload(kind, payload, content)
+}
 
+// callRewritePayload sends an already-marshalled envelope to the Rust FFI
+// function for kind, returning content unchanged on failure.
+func callRewritePayload(kind string, payload []byte, content string) string {
 	cInput := C.CString(string(payload))
 	defer C.free(unsafe.Pointer(cInput))
 
@@ -71,10 +322,24 @@ func callRewrite(kind string, proxyOrigin, baseURL, content string) string {
 	switch kind {
 	case "html":
 		cResult = C.rewrite_html(cInput)
+	case "html_resources":
+		cResult = C.rewrite_html_resources(cInput)
 	case "css":
 		cResult = C.rewrite_css(cInput)
 	case "js":
 		cResult = C.rewrite_js(cInput)
+	case "json":
+		cResult = C.rewrite_json(cInput)
+	case "hls":
+		cResult = C.rewrite_hls(cInput)
+	case "dash":
+		cResult = C.rewrite_dash(cInput)
+	case "appcache":
+		cResult = C.rewrite_appcache(cInput)
+	case "vtt":
+		cResult = C.rewrite_vtt(cInput)
+	case "with_blobs":
+		cResult = C.rewrite_with_blobs(cInput)
 	default:
 		return content
 	}
@@ -95,23 +360,60 @@ func Rewrite(kind ContentKind, src io.Reader) (io.Reader, error) {
 		return nil, fmt.Errorf("rewriter: reading source: %w", err)
 	}
 
-	content := string(body)
-
 	// TODO: plumb proxy_origin and base_url from the request context.
 	proxyOrigin := "http://localhost:8080"
 	baseURL := ""
 
-	var result string
+	var result []byte
 	switch kind {
 	case HTML:
-		result = callRewrite("html", proxyOrigin, baseURL, content)
+		result = callRewriteBytes("html", proxyOrigin, baseURL, "", body)
 	case CSS:
-		result = callRewrite("css", proxyOrigin, baseURL, content)
+		result = callRewriteBytes("css", proxyOrigin, baseURL, "", body)
 	case JS:
-		result = callRewrite("js", proxyOrigin, baseURL, content)
+		result = callRewriteBytes("js", proxyOrigin, baseURL, "", body)
+	default:
+		result = body
+	}
+
+	return bytes.NewReader(result), nil
+}
+
+// callRewriteBytes passes body to the byte-oriented Rust FFI function for
+// kind as a pointer+length, so bodies with interior NULs or in a non-UTF-8
+// charset survive the boundary, and copies the result back into Go memory.
+func callRewriteBytes(kind, proxyOrigin, baseURL, charset string, body []byte) []byte {
+	payload, err := json.Marshal(struct {
+		ProxyOrigin  string `json:"proxy_origin"`
+		BaseURL      string `json:"base_url"`
+		Charset      string `json:"charset,omitempty"`
+		MarkerSecret string `json:"marker_secret,omitempty"`
+	}{proxyOrigin, baseURL, charset, MarkerSecret})
+	if err != nil || len(body) == 0 {
+		return body
+	}
+
+	cInput := C.CString(string(payload))
+	defer C.free(unsafe.Pointer(cInput))
+	cBody := C.CBytes(body)
+	defer C.free(cBody)
+
+	var outLen C.size_t
+	var cResult *C.uchar
+	switch kind {
+	case "html":
+		cResult = C.rewrite_html_bytes(cInput, (*C.uchar)(cBody), C.size_t(len(body)), &outLen)
+	case "css":
+		cResult = C.rewrite_css_bytes(cInput, (*C.uchar)(cBody), C.size_t(len(body)), &outLen)
+	case "js":
+		cResult = C.rewrite_js_bytes(cInput, (*C.uchar)(cBody), C.size_t(len(body)), &outLen)
 	default:
-		result = content
+		return body
+	}
+	if cResult == nil {
+		return body
 	}
+	defer C.free_bytes(cResult, outLen)
 
-	return strings.NewReader(result), nil
+	return C.GoBytes(unsafe.Pointer(cResult), C.int(outLen))
 }
@@ -0,0 +1,131 @@
+package transport
+
+import (
+	"container/list"
+	"mime"
+	"net/http"
+	"strings"
+	"sync"
+
+	"internex/internal/rewriter"
+)
+
+// ---------------------------------------------------------------------------
+// Blob store — large data: URLs moved out of rewritten documents
+// ---------------------------------------------------------------------------
+
+// DataURLThreshold is the size in bytes above which a data: URL in a
+// rewritten HTML, CSS or JS document is served from /blob/<id> instead of
+// staying inline.  Zero keeps every data: URL inline.
+var DataURLThreshold int
+
+// blobKinds are the content categories whose data: URLs can be extracted,
+// mapped to the rewriter kind for each.
+var blobKinds = map[ContentCategory]string{
+	ContentHTML: "html",
+	ContentCSS:  "css",
+	ContentJS:   "js",
+}
+
+// BlobStore holds the payloads of extracted data: URLs keyed by their
+// content id (a SHA-256 of the payload), evicting the least recently used
+// ones once their total size passes a byte budget.  It is safe for
+// concurrent use.
+type BlobStore struct {
+	mu     sync.Mutex
+	budget int
+	size   int
+	order  *list.List // front = most recently used; values are rewriter.Blob
+	blobs  map[string]*list.Element
+}
+
+// Global default blob store.
+var DefaultBlobs = NewBlobStore(64 << 20)
+
+// NewBlobStore creates an empty blob store holding at most budget bytes of
+// payload.
+func NewBlobStore(budget int) *BlobStore {
+	return &BlobStore{
+		budget: budget,
+		order:  list.New(),
+		blobs:  make(map[string]*list.Element),
+	}
+}
+
+// Put stores blobs under their ids.  An id that is already stored keeps its
+// first payload, and blobs with an active media type (HTML, SVG, script)
+// or larger than the whole budget are dropped.
+func (s *BlobStore) Put(blobs []rewriter.Blob) {
+	s.mu.Lock()
+	defer s.mu.Unlock()
+	for _, b := range blobs {
+		if _, ok := s.blobs[b.ID]; ok || !isPassiveMIME(b.MIME) || len(b.Data) > s.budget {
+			continue
+		}
+		s.blobs[b.ID] = s.order.PushFront(b)
+		s.size += len(b.Data)
+		for s.size > s.budget {
+			oldest := s.order.Back()
+			evicted := s.order.Remove(oldest).(rewriter.Blob)
+			delete(s.blobs, evicted.ID)
+			s.size -= len(evicted.Data)
+		}
+	}
+}
+
+// Get returns the blob stored under id.
+func (s *BlobStore) Get(id string) (rewriter.Blob, bool) {
+	s.mu.Lock()
+	defer s.mu.Unlock()
+	el, ok := s.blobs[id]
+	if !ok {
+		return rewriter.Blob{}, false
+	}
+	s.order.MoveToFront(el)
+	return el.Value.(rewriter.Blob), true
+}
+
+// isPassiveMIME reports whether a payload of this media type cannot run
+// script when served from the proxy's own origin.
+func isPassiveMIME(contentType string) bool {
+	essence, _, err := mime.ParseMediaType(contentType)
+	if err != nil {
+		return false
+	}
+	kind, sub, _ := strings.Cut(essence, "/")
+	switch kind {
+	case "image":
+		return !strings.HasPrefix(sub, "svg")
+	case "audio", "video", "font":
+		return true
+	}
+	switch essence {
+	case "text/plain", "application/font-woff", "application/octet-stream":
+		return true
+	}
+	return false
+}
+
+// ---------- /blob/<id> ----------
+
+func handleBlob(w http.ResponseWriter, r *http.Request) {
+	blob, ok := DefaultBlobs.Get(r.PathValue("id"))
+	if !ok {
+		http.Error(w, "Not found", http.StatusNotFound)
+		return
+	}
+	w.Header().Set("Content-Type", blob.MIME)
+	w.Header().Set("X-Content-Type-Options", "nosniff")
+	// The id is a hash of the payload and never reassigned, so the
+	// response never changes.
+	w.Header().Set("Cache-Control", "public, max-age=31536000, immutable")
+	w.Write(blob.Data)
+}
+
+// rewriteExtractingBlobs rewrites content of the given kind, storing any
+// data: URLs above DataURLThreshold in DefaultBlobs.
+func rewriteExtractingBlobs(kind, baseURL, content string) string {
+	result, blobs := rewriter.RewriteWithBlobs(kind, ProxyOrigin, baseURL, content, DataURLThreshold)
+	DefaultBlobs.Put(blobs)
+	return result
+}
@@ -0,0 +1,85 @@
+package transport
+
+import (
+	"net/http"
+	"net/http/httptest"
+	"strings"
+	"testing"
+
+	"internex/internal/rewriter"
+)
+
+func TestBlobRouteServesStoredPayload(t *testing.T) {
+	DefaultBlobs.Put([]rewriter.Blob{{ID: "00ff", MIME: "image/png", Data: []byte("png")}})
+	mux := NewMux()
+
+	rec := httptest.NewRecorder()
+	mux.ServeHTTP(rec, httptest.NewRequest("GET", "/blob/00ff", nil))
+	if rec.Code != http.StatusOK || rec.Body.String() != "png" {
+		t.Fatalf("GET /blob/00ff = %d %q, want 200 \"png\"", rec.Code, rec.Body.String())
+	}
+	if ct := rec.Header().Get("Content-Type"); ct != "image/png" {
+		t.Fatalf("Content-Type = %q, want image/png", ct)
+	}
+	if got := rec.Header().Get("X-Content-Type-Options"); got != "nosniff" {
+		t.Fatalf("X-Content-Type-Options = %q, want nosniff", got)
+	}
+
+	rec = httptest.NewRecorder()
+	mux.ServeHTTP(rec, httptest.NewRequest("GET", "/blob/missing", nil))
+	if rec.Code != http.StatusNotFound {
+		t.Fatalf("GET /blob/missing = %d, want 404", rec.Code)
+	}
+}
+
+func TestBlobStoreKeepsFirstPayloadAndRefusesActiveTypes(t *testing.T) {
+	s := NewBlobStore(1 << 10)
+	s.Put([]rewriter.Blob{{ID: "a", MIME: "image/png", Data: []byte("first")}})
+	s.Put([]rewriter.Blob{{ID: "a", MIME: "text/html", Data: []byte("second")}})
+	if b, _ := s.Get("a"); string(b.Data) != "first" || b.MIME != "image/png" {
+		t.Fatalf("stored blob was replaced: %+v", b)
+	}
+	for _, mime := range []string{"text/html", "image/svg+xml", "text/javascript", "application/xhtml+xml"} {
+		s.Put([]rewriter.Blob{{ID: mime, MIME: mime, Data: []byte("x")}})
+		if _, ok := s.Get(mime); ok {
+			t.Fatalf("active type %s was stored", mime)
+		}
+	}
+}
+
+func TestBlobStoreEvictsLeastRecentlyUsed(t *testing.T) {
+	s := NewBlobStore(10)
+	s.Put([]rewriter.Blob{{ID: "a", MIME: "image/png", Data: []byte("aaaa")}})
+	s.Put([]rewriter.Blob{{ID: "b", MIME: "image/png", Data: []byte("bbbb")}})
+	s.Get("a")
+	s.Put([]rewriter.Blob{{ID: "c", MIME: "image/png", Data: []byte("cccc")}})
+	if _, ok := s.Get("b"); ok {
+		t.Fatal("least recently used blob was kept")
+	}
+	if _, ok := s.Get("a"); !ok {
+		t.Fatal("recently used blob was evicted")
+	}
+	s.Put([]rewriter.Blob{{ID: "big", MIME: "image/png", Data: make([]byte, 11)}})
+	if _, ok := s.Get("big"); ok {
+		t.Fatal("blob larger than the budget was stored")
+	}
+}
+
+func TestRewriteExtractingBlobsStoresPayloads(t *testing.T) {
+	DataURLThreshold = 16
+	defer func() { DataURLThreshold = 0 }()
+
+	css := "a { background: url(data:image/png;base64,aGVsbG8gd29ybGQ=) }"
+	result := rewriteExtractingBlobs("css", "https://example.com/", css)
+	_, blobs := rewriter.RewriteWithBlobs("css", ProxyOrigin, "https://example.com/", css, DataURLThreshold)
+	if len(blobs) != 1 {
+		t.Fatalf("got %d blobs, want 1", len(blobs))
+	}
+	stored, ok := DefaultBlobs.Get(blobs[0].ID)
+	if !ok || string(stored.Data) != "hello world" {
+		t.Fatalf("blob %s not stored: %+v", blobs[0].ID, stored)
+	}
+	if want := ProxyOrigin + "/blob/" + blobs[0].ID; !strings.Contains(result, want) {
+		t.Fatalf("rewritten CSS %q does not reference %s", result, want)
+	}
+}
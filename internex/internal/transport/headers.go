@@ -15,6 +15,10 @@ const (
 	ContentHTML
 	ContentCSS
 	ContentJS
+	ContentHLS
+	ContentDASH
+	ContentAppCache
+	ContentVTT
 )
 
 // DetectContentType extracts the media type from an HTTP header set.
@@ -36,6 +40,16 @@ func Categorize(mediaType string) ContentCategory {
 		return ContentCSS
 	case strings.Contains(mediaType, "javascript"):
 		return ContentJS
+	case mediaType == "application/vnd.apple.mpegurl",
+		mediaType == "application/x-mpegurl",
+		mediaType == "audio/mpegurl":
+		return ContentHLS
+	case mediaType == "application/dash+xml":
+		return ContentDASH
+	case mediaType == "text/cache-manifest":
+		return ContentAppCache
+	case mediaType == "text/vtt":
+		return ContentVTT
 	default:
 		return ContentOther
 	}
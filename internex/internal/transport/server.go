@@ -18,6 +18,7 @@ var AssetsDir string
 func NewMux() *http.ServeMux {
 	mux := http.NewServeMux()
 	mux.HandleFunc("GET /proxy", handleProxy)
+	mux.HandleFunc("GET /blob/{id}", handleBlob)
 	mux.HandleFunc("POST /rewrite/html", handleRewriteHTML)
 	mux.HandleFunc("POST /rewrite/css", handleRewriteCSS)
 	mux.HandleFunc("POST /rewrite/js", handleRewriteJS)
@@ -28,7 +29,7 @@ func NewMux() *http.ServeMux {
 // ---------- /proxy?url=<encoded> ----------
 
 func handleProxy(w http.ResponseWriter, r *http.Request) {
-	raw := r.URL.Query().Get("url")
+	raw, _ := RawQueryParam(r.URL.RawQuery, "url")
 	if raw == "" {
 		http.Error(w, "missing 'url' query parameter", http.StatusBadRequest)
 		return
@@ -93,15 +94,27 @@ func handleProxy(w http.ResponseWriter, r *http.Request) {
 	content := string(body)
 	var result string
 
-	switch category {
-	case ContentHTML:
-		result = rewriter.RewriteHTML(ProxyOrigin, targetURL, content)
-	case ContentCSS:
-		result = rewriter.RewriteCSS(ProxyOrigin, targetURL, content)
-	case ContentJS:
-		result = rewriter.RewriteJS(ProxyOrigin, targetURL, content)
-	default:
-		result = content
+	if kind, ok := blobKinds[category]; ok && DataURLThreshold > 0 {
+		result = rewriteExtractingBlobs(kind, targetURL, content)
+	} else {
+		switch category {
+		case ContentHTML:
+			result = rewriter.RewriteHTML(ProxyOrigin, targetURL, content)
+		case ContentCSS:
+			result = rewriter.RewriteCSS(ProxyOrigin, targetURL, content)
+		case ContentJS:
+			result = rewriter.RewriteJS(ProxyOrigin, targetURL, content)
+		case ContentHLS:
+			result = rewriter.RewriteHLS(ProxyOrigin, targetURL, content)
+		case ContentDASH:
+			result = rewriter.RewriteDASH(ProxyOrigin, targetURL, content)
+		case ContentAppCache:
+			result = rewriter.RewriteAppCache(ProxyOrigin, targetURL, content)
+		case ContentVTT:
+			result = rewriter.RewriteVTT(ProxyOrigin, targetURL, content)
+		default:
+			result = content
+		}
 	}
 
 	// Remove Content-Length since the rewritten size may differ.
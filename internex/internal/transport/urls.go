@@ -3,6 +3,8 @@ package transport
 import (
 	"net/url"
 	"strings"
+
+	"internex/internal/rewriter"
 )
 
 // ProxyOrigin is the base URL of *our* proxy server.
@@ -25,11 +27,26 @@ func EncodeProxyPath(targetURL string) string {
 	return "/proxy?url=" + url.QueryEscape(targetURL)
 }
 
-// DecodeProxyURL extracts the original target URL from the `url`
-// query parameter value.  Returns the decoded URL and true on success.
+// RawQueryParam returns the value of the first `name` parameter in
+// rawQuery exactly as sent, without percent-decoding it.  The `url`
+// parameter must be decoded once, by DecodeProxyURL: url.Values would
+// decode it first and turn an upstream `%26` into a real `&`.
+func RawQueryParam(rawQuery, name string) (string, bool) {
+	for _, part := range strings.Split(rawQuery, "&") {
+		if value, ok := strings.CutPrefix(part, name+"="); ok {
+			return value, true
+		}
+	}
+	return "", false
+}
+
+// DecodeProxyURL extracts the original target URL from the raw (still
+// percent-encoded) `url` query parameter value, see RawQueryParam.
+// Returns the decoded URL and true on success.  Decoding is done by the
+// Rust rewriter so it mirrors its encoder exactly.
 func DecodeProxyURL(encoded string) (string, bool) {
-	decoded, err := url.QueryUnescape(encoded)
-	if err != nil {
+	decoded, ok := rewriter.DecodeProxyURL(ProxyOrigin, encoded)
+	if !ok {
 		return "", false
 	}
 	parsed, err := url.Parse(decoded)
@@ -0,0 +1,41 @@
+package transport
+
+import (
+	"net/http/httptest"
+	"testing"
+)
+
+func TestRawQueryParamKeepsEncoding(t *testing.T) {
+	r := httptest.NewRequest("GET", "/proxy?url=https://x.example/a?q=a%2526b%253D2%26c=1&v=2", nil)
+	raw, ok := RawQueryParam(r.URL.RawQuery, "url")
+	if !ok {
+		t.Fatal("url parameter not found")
+	}
+	if want := "https://x.example/a?q=a%2526b%253D2%26c=1"; raw != want {
+		t.Fatalf("RawQueryParam = %q, want %q", raw, want)
+	}
+	if _, ok := RawQueryParam(r.URL.RawQuery, "missing"); ok {
+		t.Fatal("missing parameter reported as present")
+	}
+}
+
+func TestDecodeProxyURLKeepsEncodedSeparators(t *testing.T) {
+	target := "https://x.example/a?q=a%26b%3D2&c=1"
+	r := httptest.NewRequest("GET", "/proxy?url=https://x.example/a?q=a%2526b%253D2%26c=1", nil)
+	raw, _ := RawQueryParam(r.URL.RawQuery, "url")
+	got, ok := DecodeProxyURL(raw)
+	if !ok {
+		t.Fatalf("DecodeProxyURL(%q) failed", raw)
+	}
+	if got != target {
+		t.Fatalf("DecodeProxyURL(%q) = %q, want %q", raw, got, target)
+	}
+}
+
+func TestDecodeProxyURLKeepsForeignProxyURL(t *testing.T) {
+	target := "https://other.site/proxy?url=https://x/"
+	got, ok := DecodeProxyURL("https%3A%2F%2Fother.site%2Fproxy%3Furl%3Dhttps://x/")
+	if !ok || got != target {
+		t.Fatalf("DecodeProxyURL = %q, %v, want %q", got, ok, target)
+	}
+}
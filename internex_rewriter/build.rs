@@ -0,0 +1,20 @@
+// Build script: embed the git commit hash (when building from a checkout)
+// so `internex_version()` can report exactly which rewriter is loaded.
+
+use std::process::Command;
+
+fn main() {
+    println!("cargo:rerun-if-changed=../.git/HEAD");
+    println!("cargo:rerun-if-changed=../.git/refs");
+
+    let hash = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|o| o.status.success())
+        .and_then(|o| String::from_utf8(o.stdout).ok());
+
+    if let Some(hash) = hash {
+        println!("cargo:rustc-env=INTERNEX_GIT_HASH={}", hash.trim());
+    }
+}
@@ -0,0 +1,175 @@
+// internex_rewriter::analyze
+//
+// Dry-run reports for auditing rewriter coverage on real pages.  Each
+// `analyze_*` function runs the normal rewriter with a URL hook that
+// records every URL it routes through the proxy, then reports those and the
+// runtime wrappers the JS passes would insert; `analyze_csp` reports the
+// directives the CSP rewrite drops.  The rewritten output itself is
+// discarded.  `Config` has the same set of methods for non-default settings.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+
+use serde_derive::Serialize;
+
+use crate::url::{encode_url, with_url_hook};
+use crate::{css, csp, html, js};
+
+/// Runtime namespace the JS and HTML rewriters call into.
+const RUNTIME_PREFIX: &str = "__internex.";
+
+/// What a rewrite would change.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Report {
+    /// Absolute upstream URLs that would be routed through the proxy, in
+    /// document order.
+    pub urls: Vec<String>,
+    /// Runtime calls the rewrite would insert (`rewriteUrl`, `scope`, …)
+    /// and how many of each.
+    pub runtime_calls: BTreeMap<String, usize>,
+    /// CSP directives the rewrite would remove (lower-cased names), e.g.
+    /// `upgrade-insecure-requests`.
+    pub stripped_directives: Vec<String>,
+    /// Whether the output would differ from the input.
+    pub changed: bool,
+}
+
+/// Report what [`html::rewrite_html`] would change in `html`: the URLs in
+/// attributes, inline styles and `<style>` elements, and the runtime calls
+/// wrapped around inline scripts and event handlers.
+pub fn analyze_html(proxy_origin: &str, base_url: &str, html: &str) -> Report {
+    analyze(proxy_origin, html, || html::rewrite_html(proxy_origin, base_url, html))
+}
+
+/// Report the `url()` and `@import` URLs [`css::rewrite_css`] would proxy.
+pub fn analyze_css(proxy_origin: &str, base_url: &str, css: &str) -> Report {
+    analyze(proxy_origin, css, || css::rewrite_css(proxy_origin, base_url, css))
+}
+
+/// Report the URLs [`js::rewrite_js`] would proxy statically and the
+/// runtime calls it would wrap around dynamic sinks.
+pub fn analyze_js(proxy_origin: &str, base_url: &str, js: &str) -> Report {
+    analyze(proxy_origin, js, || js::rewrite_js(proxy_origin, base_url, js))
+}
+
+/// Report the directives [`csp::rewrite_csp`] would strip from `csp`.
+pub fn analyze_csp(proxy_origin: &str, upstream_origin: &str, csp: &str) -> Report {
+    csp_report(csp, &csp::rewrite_csp(proxy_origin, upstream_origin, csp))
+}
+
+/// Run `rewrite` with every proxied URL recorded, and report them along with
+/// the runtime calls its output gained over `input`.
+pub(crate) fn analyze(proxy_origin: &str, input: &str, rewrite: impl FnOnce() -> String) -> Report {
+    let urls = Rc::new(RefCell::new(Vec::new()));
+    let seen = Rc::clone(&urls);
+    let proxy = proxy_origin.to_string();
+    let output = with_url_hook(
+        move |url| {
+            seen.borrow_mut().push(url.to_string());
+            encode_url(&proxy, url)
+        },
+        rewrite,
+    );
+
+    let mut inserted = runtime_calls(&output);
+    for (name, n) in runtime_calls(input) {
+        if let Some(count) = inserted.get_mut(&name) {
+            *count = count.saturating_sub(n);
+        }
+    }
+    inserted.retain(|_, n| *n > 0);
+
+    let urls = urls.borrow().clone();
+    Report {
+        urls,
+        runtime_calls: inserted,
+        stripped_directives: Vec::new(),
+        changed: output != input,
+    }
+}
+
+/// Report the directives of `input` missing from its rewrite `output`.
+pub(crate) fn csp_report(input: &str, output: &str) -> Report {
+    let kept: Vec<String> = directive_names(output).collect();
+    Report {
+        stripped_directives: directive_names(input).filter(|name| !kept.contains(name)).collect(),
+        changed: output != input,
+        ..Report::default()
+    }
+}
+
+fn directive_names(csp: &str) -> impl Iterator<Item = String> + '_ {
+    csp.split(';')
+        .filter_map(|directive| directive.split_whitespace().next())
+        .map(str::to_ascii_lowercase)
+}
+
+/// Count `__internex.<name>(` calls in `text`.
+fn runtime_calls(text: &str) -> BTreeMap<String, usize> {
+    let mut calls = BTreeMap::new();
+    for (pos, _) in text.match_indices(RUNTIME_PREFIX) {
+        let rest = &text[pos + RUNTIME_PREFIX.len()..];
+        let len = rest
+            .bytes()
+            .take_while(|b| b.is_ascii_alphanumeric() || *b == b'_' || *b == b'$')
+            .count();
+        if len > 0 && rest[len..].starts_with('(') {
+            *calls.entry(rest[..len].to_string()).or_insert(0) += 1;
+        }
+    }
+    calls
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROXY: &str = "http://localhost:8080";
+    const BASE: &str = "https://example.com/page";
+
+    #[test]
+    fn reports_anchor_href() {
+        let report = analyze_html(
+            PROXY,
+            BASE,
+            r#"<html><body><a href="/about">a</a><button onclick="go()">b</button></body></html>"#,
+        );
+        assert_eq!(report.urls, vec!["https://example.com/about".to_string()]);
+        assert_eq!(report.runtime_calls.get("scope"), Some(&1));
+        assert!(report.changed);
+    }
+
+    #[test]
+    fn reports_fetch_call() {
+        let report = analyze_js(
+            PROXY,
+            BASE,
+            r#"fetch("/api/items"); history.pushState({}, "", next);"#,
+        );
+        assert_eq!(report.urls, vec!["https://example.com/api/items".to_string()]);
+        assert_eq!(report.runtime_calls.get("rewriteUrl"), Some(&1));
+    }
+
+    #[test]
+    fn reports_nothing_for_unchanged_input() {
+        let report = analyze_css(PROXY, BASE, ".a { color: red }");
+        assert_eq!(report, Report::default());
+
+        // Calls already present in the input are not counted again.
+        let report = analyze_js(PROXY, BASE, "__internex.rewriteUrl(x);");
+        assert!(report.runtime_calls.is_empty());
+    }
+
+    #[test]
+    fn reports_stripped_csp_directives() {
+        let report = analyze_csp(
+            PROXY,
+            "https://example.com",
+            "default-src 'self'; Upgrade-Insecure-Requests; block-all-mixed-content",
+        );
+        assert_eq!(report.stripped_directives, vec!["upgrade-insecure-requests", "block-all-mixed-content"]);
+        assert!(report.urls.is_empty());
+        assert!(report.changed);
+    }
+}
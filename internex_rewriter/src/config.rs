@@ -0,0 +1,527 @@
+// internex_rewriter::config
+//
+// One place for every rewriter setting.  The per-module option structs
+// (`HtmlOptions`, `JsOptions`, `CspOptions`, the JSON key allowlist) are
+// bundled with the proxy origin, so callers thread a single `Config`
+// instead of loose `&str`s plus a growing set of flags.  The free functions
+// in each module stay as the thin, default-options entry points.
+//
+// Over FFI the settings travel as the envelope's optional `config` object:
+//
+//   { "proxy_origin": "…", "base_url": "…", "content": "…",
+//     "config": { "data_url_attrs": ["data-src", "data-bg"],
+//                 "max_data_len": 4096,
+//                 "marker_secret": "…",
+//                 "url":  { "style": "root_relative", "default_scheme": "http" },
+//                 "html": { "rewrite_inline_scripts": false },
+//                 "js":   { "rewrite_url_literals": true },
+//                 "csp":  { "allow_embedding": true },
+//                 "json": { "keys": ["image"] } } }
+//
+// Absent or mistyped fields keep their defaults.
+
+use std::borrow::Cow;
+
+use serde_json::{json, Map, Value};
+
+use crate::analyze::{self, Report};
+use crate::csp::{self, CspOptions};
+use crate::html::{self, HtmlOptions, HtmlRewrite};
+use crate::js::{self, JsOptions};
+use crate::url::{self, ProxyUrlStyle, UrlSettings};
+use crate::{css, json, manifest};
+
+/// Settings shared by every rewriter entry point.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Config {
+    /// Origin of our proxy server, e.g. `"http://localhost:8080"`.
+    pub proxy_origin: String,
+    /// `data-*` attribute names whose values are URLs, for both the HTML
+    /// attribute pass and the JS `dataset` pass.  When set, overrides
+    /// `html.data_url_attrs` and `js.data_url_attrs`; `None` leaves each as
+    /// it is.
+    pub data_url_attrs: Option<Vec<String>>,
+    /// How every rewriter encodes URLs (see [`url::with_url_settings`]).
+    pub url: UrlSettings,
+    /// `data:` URLs longer than this many bytes are moved to the blob route
+    /// by the FFI `rewrite_with_blobs` (see [`url::collect_blobs`]).  `None`
+    /// keeps every `data:` URL inline.
+    pub max_data_len: Option<usize>,
+    /// Deployment-wide secret keying the "already rewritten" markers (see
+    /// [`url::with_marker_secret`]).  `None` writes no markers, so every
+    /// pass rewrites in full.
+    pub marker_secret: Option<String>,
+    pub html: HtmlOptions,
+    pub js: JsOptions,
+    pub csp: CspOptions,
+    /// Restrict JSON rewriting to values under these keys (see
+    /// [`json::rewrite_json_with_keys`]).  `None` rewrites every URL string.
+    pub json_keys: Option<Vec<String>>,
+}
+
+impl Config {
+    /// Default settings for `proxy_origin`.
+    pub fn new(proxy_origin: &str) -> Self {
+        Config {
+            proxy_origin: proxy_origin.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Build a config from an FFI envelope: its `proxy_origin` plus the
+    /// optional `config` object.  `None` without a `proxy_origin`.
+    pub fn from_envelope(envelope: &Value) -> Option<Self> {
+        let mut config = Config::new(envelope.get("proxy_origin")?.as_str()?);
+        if let Some(settings) = envelope.get("config") {
+            config.apply(settings);
+        }
+        Some(config)
+    }
+
+    /// Overlay the settings in a `config` object onto `self`.
+    pub fn apply(&mut self, settings: &Value) {
+        let section = |name: &str| settings.get(name).and_then(Value::as_object);
+
+        if let Some(attrs) = string_list(settings.get("data_url_attrs")) {
+            self.data_url_attrs = Some(attrs);
+        }
+
+        if let Some(max) = settings.get("max_data_len") {
+            self.max_data_len = max.as_u64().map(|n| n as usize);
+        }
+
+        if let Some(secret) = settings.get("marker_secret") {
+            self.marker_secret = secret.as_str().map(str::to_string);
+        }
+
+        if let Some(u) = section("url") {
+            if let Some(style) = u.get("style").and_then(Value::as_str).and_then(ProxyUrlStyle::from_name) {
+                self.url.style = style;
+            }
+            if let Some(scheme) = u.get("default_scheme").and_then(Value::as_str) {
+                self.url.default_scheme = scheme.to_string();
+            }
+        }
+
+        if let Some(h) = section("html") {
+            let flag = |field: &str| h.get(field).and_then(Value::as_bool);
+            if let Some(policy) = h.get("referrer_policy") {
+                self.html.referrer_policy = policy.as_str().map(str::to_string);
+            }
+            if let Some(on) = flag("rewrite_conditional_comments") {
+                self.html.rewrite_conditional_comments = on;
+            }
+            if let Some(names) = string_list(h.get("meta_url_names")) {
+                self.html.meta_url_names = names;
+            }
+            if let Some(on) = flag("rewrite_social_meta") {
+                self.html.rewrite_social_meta = on;
+            }
+            if let Some(on) = flag("proxy_canonical_links") {
+                self.html.proxy_canonical_links = on;
+            }
+            if let Some(on) = flag("rewrite_inline_scripts") {
+                self.html.rewrite_inline_scripts = on;
+            }
+            if let Some(on) = flag("preserve_script_lines") {
+                self.html.preserve_script_lines = on;
+            }
+            if let Some(on) = flag("rewrite_inline_styles") {
+                self.html.rewrite_inline_styles = on;
+            }
+            if let Some(on) = flag("rewrite_event_handlers") {
+                self.html.rewrite_event_handlers = on;
+            }
+            if let Some(nonce) = h.get("style_nonce") {
+                self.html.style_nonce = nonce.as_str().map(str::to_string);
+            }
+        }
+
+        if let Some(j) = section("js") {
+            let flag = |field: &str| j.get(field).and_then(Value::as_bool);
+            if let Some(on) = flag("rewrite_url_literals") {
+                self.js.rewrite_url_literals = on;
+            }
+            if let Some(on) = flag("rewrite_tagged_templates") {
+                self.js.rewrite_tagged_templates = on;
+            }
+            if let Some(on) = flag("rewrite_url_constructors") {
+                self.js.rewrite_url_constructors = on;
+            }
+            if let Some(on) = flag("rewrite_eval_sinks") {
+                self.js.rewrite_eval_sinks = on;
+            }
+            if let Some(on) = flag("rewrite_dom_properties") {
+                self.js.rewrite_dom_properties = on;
+            }
+        }
+
+        if let Some(c) = section("csp") {
+            let flag = |field: &str| c.get(field).and_then(Value::as_bool);
+            if let Some(on) = flag("preserve_sources") {
+                self.csp.preserve_sources = on;
+            }
+            if let Some(on) = flag("relax_sandbox") {
+                self.csp.relax_sandbox = on;
+            }
+            if let Some(on) = flag("allow_embedding") {
+                self.csp.allow_embedding = on;
+            }
+        }
+
+        if let Some(keys) = section("json").and_then(|j| string_list(j.get("keys"))) {
+            self.json_keys = Some(keys);
+        }
+    }
+
+    /// The `config` object for an FFI envelope; the inverse of
+    /// [`Config::apply`].  `proxy_origin` travels at the envelope's top level.
+    pub fn to_json(&self) -> Value {
+        let mut settings = Map::new();
+        if let Some(ref attrs) = self.data_url_attrs {
+            settings.insert("data_url_attrs".to_string(), json!(attrs));
+        }
+        settings.insert("max_data_len".to_string(), json!(self.max_data_len));
+        settings.insert("marker_secret".to_string(), json!(self.marker_secret));
+        settings.insert(
+            "url".to_string(),
+            json!({
+                "style": self.url.style.as_str(),
+                "default_scheme": self.url.default_scheme,
+            }),
+        );
+        settings.insert(
+            "html".to_string(),
+            json!({
+                "referrer_policy": self.html.referrer_policy,
+                "rewrite_conditional_comments": self.html.rewrite_conditional_comments,
+                "meta_url_names": self.html.meta_url_names,
+                "rewrite_social_meta": self.html.rewrite_social_meta,
+                "proxy_canonical_links": self.html.proxy_canonical_links,
+                "rewrite_inline_scripts": self.html.rewrite_inline_scripts,
+                "preserve_script_lines": self.html.preserve_script_lines,
+                "rewrite_inline_styles": self.html.rewrite_inline_styles,
+                "rewrite_event_handlers": self.html.rewrite_event_handlers,
+                "style_nonce": self.html.style_nonce,
+            }),
+        );
+        settings.insert(
+            "js".to_string(),
+            json!({
+                "rewrite_url_literals": self.js.rewrite_url_literals,
+                "rewrite_tagged_templates": self.js.rewrite_tagged_templates,
+                "rewrite_url_constructors": self.js.rewrite_url_constructors,
+                "rewrite_eval_sinks": self.js.rewrite_eval_sinks,
+                "rewrite_dom_properties": self.js.rewrite_dom_properties,
+            }),
+        );
+        settings.insert(
+            "csp".to_string(),
+            json!({
+                "preserve_sources": self.csp.preserve_sources,
+                "relax_sandbox": self.csp.relax_sandbox,
+                "allow_embedding": self.csp.allow_embedding,
+            }),
+        );
+        if let Some(ref keys) = self.json_keys {
+            settings.insert("json".to_string(), json!({ "keys": keys }));
+        }
+        Value::Object(settings)
+    }
+
+    /// `self.html`, with the shared [`Config::data_url_attrs`] if set.
+    fn html_options(&self) -> Cow<'_, HtmlOptions> {
+        match self.data_url_attrs {
+            Some(ref attrs) if *attrs != self.html.data_url_attrs => {
+                Cow::Owned(HtmlOptions { data_url_attrs: attrs.clone(), ..self.html.clone() })
+            }
+            _ => Cow::Borrowed(&self.html),
+        }
+    }
+
+    /// `self.js`, with the shared [`Config::data_url_attrs`] if set.
+    fn js_options(&self) -> Cow<'_, JsOptions> {
+        match self.data_url_attrs {
+            Some(ref attrs) if *attrs != self.js.data_url_attrs => {
+                Cow::Owned(JsOptions { data_url_attrs: attrs.clone(), ..self.js.clone() })
+            }
+            _ => Cow::Borrowed(&self.js),
+        }
+    }
+
+    /// Run `f` with [`Config::url`] applied to the URLs it encodes and
+    /// [`Config::marker_secret`] keying its markers.
+    fn scoped<R>(&self, f: impl FnOnce() -> R) -> R {
+        url::with_marker_secret(self.marker_secret.clone(), || url::with_url_settings(self.url.clone(), f))
+    }
+
+    pub fn rewrite_html(&self, base_url: &str, html: &str) -> String {
+        self.rewrite_html_with_resources(base_url, html).html
+    }
+
+    /// Inline module scripts get [`Config::js`] too.
+    pub fn rewrite_html_with_resources(&self, base_url: &str, html: &str) -> HtmlRewrite {
+        self.scoped(|| {
+            html::rewrite_html_collecting(&self.proxy_origin, base_url, html, &self.html_options(), &self.js_options())
+        })
+    }
+
+    pub fn rewrite_html_bytes(&self, base_url: &str, bytes: &[u8], charset_hint: Option<&str>) -> String {
+        self.rewrite_html(base_url, &html::decode_html_bytes(bytes, charset_hint))
+    }
+
+    pub fn rewrite_css(&self, base_url: &str, css: &str) -> String {
+        self.scoped(|| css::rewrite_css(&self.proxy_origin, base_url, css))
+    }
+
+    pub fn rewrite_js(&self, base_url: &str, js: &str) -> String {
+        self.scoped(|| js::rewrite_js_with_options(&self.proxy_origin, base_url, js, &self.js_options()))
+    }
+
+    pub fn rewrite_json(&self, base_url: &str, json: &str) -> String {
+        self.scoped(|| match self.json_keys {
+            Some(ref keys) => {
+                let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+                json::rewrite_json_with_keys(&self.proxy_origin, base_url, json, &keys)
+            }
+            None => json::rewrite_json(&self.proxy_origin, base_url, json),
+        })
+    }
+
+    pub fn rewrite_csp(&self, upstream_origin: &str, csp: &str) -> String {
+        self.scoped(|| csp::rewrite_csp_with_options(&self.proxy_origin, upstream_origin, csp, &self.csp))
+    }
+
+    pub fn rewrite_hls(&self, base_url: &str, text: &str) -> String {
+        self.scoped(|| manifest::rewrite_hls(&self.proxy_origin, base_url, text))
+    }
+
+    pub fn rewrite_dash(&self, base_url: &str, xml: &str) -> String {
+        self.scoped(|| manifest::rewrite_dash(&self.proxy_origin, base_url, xml))
+    }
+
+    pub fn rewrite_appcache_manifest(&self, base_url: &str, text: &str) -> String {
+        self.scoped(|| manifest::rewrite_appcache_manifest(&self.proxy_origin, base_url, text))
+    }
+
+    pub fn rewrite_vtt(&self, base_url: &str, text: &str) -> String {
+        self.scoped(|| manifest::rewrite_vtt(&self.proxy_origin, base_url, text))
+    }
+
+    pub fn encode_url(&self, base_url: &str, raw: &str) -> Option<String> {
+        self.scoped(|| url::encode_url_with_base(&self.proxy_origin, base_url, raw))
+    }
+
+    /// [`analyze::analyze_html`] with these settings.
+    pub fn analyze_html(&self, base_url: &str, html: &str) -> Report {
+        analyze::analyze(&self.proxy_origin, html, || self.rewrite_html(base_url, html))
+    }
+
+    /// [`analyze::analyze_css`] with these settings.
+    pub fn analyze_css(&self, base_url: &str, css: &str) -> Report {
+        analyze::analyze(&self.proxy_origin, css, || self.rewrite_css(base_url, css))
+    }
+
+    /// [`analyze::analyze_js`] with these settings.
+    pub fn analyze_js(&self, base_url: &str, js: &str) -> Report {
+        analyze::analyze(&self.proxy_origin, js, || self.rewrite_js(base_url, js))
+    }
+
+    /// [`analyze::analyze_csp`] with these settings.
+    pub fn analyze_csp(&self, upstream_origin: &str, csp: &str) -> Report {
+        analyze::csp_report(csp, &self.rewrite_csp(upstream_origin, csp))
+    }
+}
+
+/// Read an array of strings, skipping non-string items.
+fn string_list(value: Option<&Value>) -> Option<Vec<String>> {
+    Some(
+        value?
+            .as_array()?
+            .iter()
+            .filter_map(|item| item.as_str().map(str::to_string))
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROXY: &str = "http://localhost:8080";
+    const BASE: &str = "https://example.com/page";
+
+    #[test]
+    fn defaults_match_module_defaults() {
+        let config = Config::new(PROXY);
+        assert_eq!(config.html, HtmlOptions::default());
+        assert_eq!(config.js, JsOptions::default());
+        assert_eq!(config.csp, CspOptions::default());
+        assert_eq!(config.json_keys, None);
+        assert_eq!(config.url, UrlSettings::default());
+    }
+
+    #[test]
+    fn round_trips_through_envelope() {
+        let mut config = Config::new(PROXY);
+        config.html.rewrite_inline_scripts = false;
+        config.html.preserve_script_lines = true;
+        config.html.referrer_policy = Some("no-referrer".to_string());
+        config.data_url_attrs = Some(vec!["data-img".to_string()]);
+        config.url.style = ProxyUrlStyle::ProtocolRelative;
+        config.url.default_scheme = "http".to_string();
+        config.max_data_len = Some(4096);
+        config.marker_secret = Some("deployment-secret".to_string());
+        config.html.meta_url_names = vec!["x-icon".to_string()];
+        config.js.rewrite_url_literals = true;
+        config.js.rewrite_eval_sinks = false;
+        config.csp.allow_embedding = true;
+        config.json_keys = Some(vec!["image".to_string()]);
+
+        let envelope = json!({
+            "proxy_origin": PROXY,
+            "base_url": BASE,
+            "content": "",
+            "config": config.to_json(),
+        });
+        let text = envelope.to_string();
+        let parsed: Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(Config::from_envelope(&parsed), Some(config));
+    }
+
+    #[test]
+    fn missing_and_mistyped_fields_keep_defaults() {
+        let envelope = json!({
+            "proxy_origin": PROXY,
+            "config": { "html": { "rewrite_inline_scripts": "no" }, "js": 3 },
+        });
+        assert_eq!(Config::from_envelope(&envelope), Some(Config::new(PROXY)));
+        assert_eq!(Config::from_envelope(&json!({ "config": {} })), None);
+    }
+
+    #[test]
+    fn html_flags_reach_html_rewriter() {
+        let mut config = Config::new(PROXY);
+        config.apply(&json!({ "html": { "rewrite_inline_scripts": false } }));
+        let result = config.rewrite_html(BASE, "<html><body><script>go()</script></body></html>");
+        assert!(result.contains("<script>go()</script>"));
+    }
+
+    #[test]
+    fn js_flags_reach_js_rewriter() {
+        let mut config = Config::new(PROXY);
+        let js = r#"const api = "https://api.example.com/v1";"#;
+        assert_eq!(config.rewrite_js(BASE, js), js);
+        config.apply(&json!({ "js": { "rewrite_url_literals": true } }));
+        assert!(config.rewrite_js(BASE, js).contains("__internex.rewriteUrl("));
+    }
+
+    #[test]
+    fn eval_sink_flag_reaches_js_rewriter() {
+        let mut config = Config::new(PROXY);
+        let js = r#"eval("x.src");"#;
+        assert!(config.rewrite_js(BASE, js).contains("__internex.unrewriteUrl(x.src)"));
+        config.apply(&json!({ "js": { "rewrite_eval_sinks": false } }));
+        assert_eq!(config.rewrite_js(BASE, js), js);
+    }
+
+    #[test]
+    fn js_flags_reach_inline_module_scripts() {
+        let mut config = Config::new(PROXY);
+        let page = r#"<html><body><script type="module">eval("x.src");</script></body></html>"#;
+        assert!(config.rewrite_html(BASE, page).contains("__internex.unrewriteUrl(x.src)"));
+        config.apply(&json!({ "js": { "rewrite_eval_sinks": false } }));
+        assert!(config.rewrite_html(BASE, page).contains(r#"<script type="module">eval("x.src");</script>"#));
+    }
+
+    #[test]
+    fn data_url_attrs_reach_html_and_js() {
+        let mut config = Config::new(PROXY);
+        config.apply(&json!({ "data_url_attrs": ["data-hero"] }));
+        let html = config.rewrite_html(BASE, r#"<html><body><div data-hero="/h.png" data-src="/s.png"></div></body></html>"#);
+        assert!(html.contains(r#"data-hero="http://localhost:8080/proxy?url=https://example.com/h.png""#));
+        assert!(html.contains(r#"data-src="/s.png""#));
+        let js = config.rewrite_js(BASE, "el.dataset.hero = h; el.dataset.src = s;");
+        assert!(js.contains("el.dataset.hero = __internex.rewriteUrl(h);"));
+        assert!(js.contains("el.dataset.src = s;"));
+    }
+
+    #[test]
+    fn module_data_url_attrs_are_kept_without_an_override() {
+        let mut config = Config::new(PROXY);
+        config.html.data_url_attrs = vec!["data-hero".to_string()];
+        let html = config.rewrite_html(BASE, r#"<html><body><div data-hero="/h.png"></div></body></html>"#);
+        assert!(html.contains(r#"data-hero="http://localhost:8080/proxy?url=https://example.com/h.png""#));
+        assert_eq!(config.js_options().data_url_attrs, JsOptions::default().data_url_attrs);
+    }
+
+    #[test]
+    fn url_style_reaches_rewriters() {
+        let mut config = Config::new(PROXY);
+        config.apply(&json!({ "url": { "style": "root_relative" } }));
+        let html = config.rewrite_html(BASE, r#"<html><body><img src="/i.png"></body></html>"#);
+        assert!(html.contains(r#"src="/proxy?url=https://example.com/i.png""#));
+        let css = config.rewrite_css(BASE, "a { background: url(/a.png) }");
+        assert!(css.contains("url(/proxy?url=https://example.com/a.png)"));
+        assert_eq!(config.encode_url(BASE, "/x").as_deref(), Some("/proxy?url=https://example.com/x"));
+    }
+
+    #[test]
+    fn default_scheme_reaches_rewriters() {
+        let mut config = Config::new(PROXY);
+        config.apply(&json!({ "url": { "default_scheme": "http" } }));
+        // No usable base, so the protocol-relative URL takes the default.
+        let html = config.rewrite_html("", r#"<html><body><img src="//cdn.example.com/i.png"></body></html>"#);
+        assert!(html.contains("proxy?url=http://cdn.example.com/i.png"), "{}", html);
+    }
+
+    #[test]
+    fn configs_sharing_a_secret_accept_each_others_output() {
+        let settings = json!({ "marker_secret": "deployment-secret" });
+        let mut first = Config::new(PROXY);
+        first.apply(&settings);
+        let mut second = Config::new(PROXY);
+        second.apply(&settings);
+
+        let css = first.rewrite_css(BASE, "a { background: url(/a.png) }");
+        assert!(css.contains("internex:rewritten:"));
+        assert_eq!(second.rewrite_css(BASE, &css), css);
+        let html = first.rewrite_html(BASE, r#"<html><body><img src="/i.png"></body></html>"#);
+        assert_eq!(second.rewrite_html(BASE, &html), html);
+
+        // Without the secret the marker is not honoured.
+        let page = r#"<html><body><img src="https://example.com/i.png"></body></html>"#;
+        let marked = format!("{}{}", page, first.scoped(|| html::rewritten_marker(PROXY)).unwrap());
+        assert_ne!(Config::new(PROXY).rewrite_html(BASE, &marked), marked);
+    }
+
+    #[test]
+    fn analysis_uses_the_config() {
+        let mut config = Config::new(PROXY);
+        let js = r#"eval("x.src");"#;
+        assert_eq!(config.analyze_js(BASE, js).runtime_calls.get("unrewriteUrl"), Some(&1));
+        config.apply(&json!({ "js": { "rewrite_eval_sinks": false } }));
+        assert!(!config.analyze_js(BASE, js).changed);
+
+        config.apply(&json!({ "url": { "style": "root_relative" } }));
+        let report = config.analyze_css(BASE, "a { background: url(/a.png) }");
+        assert_eq!(report.urls, vec!["https://example.com/a.png".to_string()]);
+    }
+
+    #[test]
+    fn csp_flags_reach_csp_rewriter() {
+        let mut config = Config::new(PROXY);
+        config.apply(&json!({ "csp": { "allow_embedding": true } }));
+        let result = config.rewrite_csp("https://example.com", "frame-ancestors 'none'");
+        assert!(result.contains("frame-ancestors 'self'"));
+    }
+
+    #[test]
+    fn json_keys_reach_json_rewriter() {
+        let mut config = Config::new(PROXY);
+        config.apply(&json!({ "json": { "keys": ["image"] } }));
+        let result = config.rewrite_json(BASE, r#"{"image":"https://cdn/a.png","link":"https://x/"}"#);
+        assert!(result.contains("proxy?url=https://cdn/a.png"));
+        assert!(result.contains(r#""link":"https://x/""#));
+    }
+}
@@ -7,8 +7,6 @@
 // source-list directive, nonces/hashes are preserved, and directives that
 // would break mixed-content proxying are stripped.
 
-use crate::url::encode_url;
-
 /// All source-list directives that can contain URLs we need to extend.
 const SOURCE_LIST_DIRECTIVES: &[&str] = &[
     "default-src",
@@ -33,6 +31,32 @@ const STRIP_DIRECTIVES: &[&str] = &[
     "block-all-mixed-content",
 ];
 
+/// Name of the Trusted Types policy the client runtime creates to re-wrap
+/// the HTML and script URLs it rewrites on their way to DOM sinks.
+pub const TRUSTED_TYPES_POLICY: &str = "internex";
+
+/// Options for [`rewrite_csp_with_options`].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CspOptions {
+    /// Keep every source-list token as sent by upstream and only append the
+    /// proxy origin.  For deployments where resources keep their original
+    /// absolute URLs, so host-sources must not be mapped to the proxy.
+    pub preserve_sources: bool,
+
+    /// Add `allow-scripts` and `allow-same-origin` to a `sandbox` directive
+    /// so the injected runtime and rewritten inline scripts can run.  Off by
+    /// default because it weakens the sandbox upstream asked for.
+    pub relax_sandbox: bool,
+
+    /// Replace `frame-ancestors` with `'self'` so the page can render in the
+    /// proxy's viewer frame, which shares the proxy origin.
+    pub allow_embedding: bool,
+}
+
+/// Sandbox flags the client runtime needs: it runs as script and reads its
+/// own origin's storage.
+const SANDBOX_RUNTIME_FLAGS: &[&str] = &["allow-scripts", "allow-same-origin"];
+
 /// Rewrite a full Content-Security-Policy header value.
 ///
 /// * `proxy_origin` – our proxy's origin, e.g. `"http://localhost:8080"`.
@@ -40,6 +64,16 @@ const STRIP_DIRECTIVES: &[&str] = &[
 ///   re-encode any absolute URLs that appear in directive values.
 /// * `csp` – the raw CSP header value from upstream.
 pub fn rewrite_csp(proxy_origin: &str, upstream_origin: &str, csp: &str) -> String {
+    rewrite_csp_with_options(proxy_origin, upstream_origin, csp, &CspOptions::default())
+}
+
+/// Like [`rewrite_csp`], with behaviour adjusted via `opts`.
+pub fn rewrite_csp_with_options(
+    proxy_origin: &str,
+    upstream_origin: &str,
+    csp: &str,
+    opts: &CspOptions,
+) -> String {
     let mut out_directives: Vec<String> = Vec::new();
 
     for directive in csp.split(';') {
@@ -48,7 +82,7 @@ pub fn rewrite_csp(proxy_origin: &str, upstream_origin: &str, csp: &str) -> Stri
             continue;
         }
 
-        let mut parts: Vec<&str> = directive.split_whitespace().collect();
+        let parts: Vec<&str> = directive.split_whitespace().collect();
         if parts.is_empty() {
             continue;
         }
@@ -63,10 +97,21 @@ pub fn rewrite_csp(proxy_origin: &str, upstream_origin: &str, csp: &str) -> Stri
         if SOURCE_LIST_DIRECTIVES.contains(&name.as_str()) {
             // Rewrite the source list.
             let values = &parts[1..];
-            let rewritten = rewrite_source_list(proxy_origin, upstream_origin, values);
+            let rewritten = if opts.preserve_sources {
+                append_proxy_origin(proxy_origin, values)
+            } else {
+                rewrite_source_list(proxy_origin, upstream_origin, values)
+            };
             out_directives.push(format!("{} {}", name, rewritten));
+        } else if name == "trusted-types" {
+            out_directives.push(rewrite_trusted_types(&parts[1..]));
+        } else if name == "frame-ancestors" && opts.allow_embedding {
+            out_directives.push("frame-ancestors 'self'".to_string());
+        } else if name == "sandbox" && opts.relax_sandbox {
+            out_directives.push(rewrite_sandbox(&parts[1..]));
         } else {
-            // report-uri, report-to, sandbox, etc. – pass through unchanged.
+            // report-uri, report-to, require-trusted-types-for, sandbox, etc.
+            // – pass through unchanged.
             out_directives.push(parts.join(" "));
         }
     }
@@ -79,7 +124,8 @@ pub fn rewrite_csp(proxy_origin: &str, upstream_origin: &str, csp: &str) -> Stri
 /// Strategy:
 /// 1. Keep keyword sources ('self', 'unsafe-inline', 'unsafe-eval', etc.)
 /// 2. Keep nonces and hashes ('nonce-...', 'sha256-...')
-/// 3. Rewrite absolute URL sources through the proxy
+/// 3. Map host-sources to the proxy origin, since every proxied resource is
+///    served from there
 /// 4. Append the proxy origin so our own scripts/resources are allowed
 fn rewrite_source_list(
     proxy_origin: &str,
@@ -87,57 +133,142 @@ fn rewrite_source_list(
     values: &[&str],
 ) -> String {
     let mut out: Vec<String> = Vec::new();
-    let mut has_proxy_origin = false;
+    let proxy_host = proxy_origin
+        .trim_start_matches("https://")
+        .trim_start_matches("http://");
 
     for &val in values {
+        if val == proxy_origin || val == proxy_host {
+            push_unique(&mut out, proxy_origin);
+            continue;
+        }
+
         if val == "*" || val == "'none'" {
-            out.push(val.to_string());
+            push_unique(&mut out, val);
             continue;
         }
 
         // Keywords: 'self', 'unsafe-inline', 'unsafe-eval', 'wasm-unsafe-eval',
-        // 'strict-dynamic', 'report-sample'
+        // 'strict-dynamic', 'report-sample', and any keyword we don't know –
+        // all quoted tokens are kept verbatim.
         if val.starts_with('\'') && val.ends_with('\'') {
             // Nonces and hashes – rewrite nonce value if desired, but the
             // simplest safe approach is to keep them so pages that rely on
             // nonce-based CSP keep working.
-            out.push(rewrite_keyword_or_hash(proxy_origin, val));
+            push_unique(&mut out, &rewrite_keyword_or_hash(proxy_origin, val));
             continue;
         }
 
         // Scheme sources: data:, blob:, https:, etc.
         if val.ends_with(':') && !val.contains('/') {
-            out.push(val.to_string());
+            push_unique(&mut out, val);
             continue;
         }
 
-        // Assume anything else is a host-source or URL.
-        // Try to proxy-encode it so the browser accepts our proxy URLs.
-        if let Some(encoded) = encode_url(proxy_origin, val) {
-            out.push(encoded);
+        // Host-sources (`cdn.example.com`, `*.example.com`, `https://x:8443`)
+        // are served through our origin once proxied.  A `/proxy?url=` URL
+        // is not a valid source expression, so the proxy origin replaces
+        // them.  Anything unrecognised is kept as-is.
+        if is_host_source(val) {
+            push_unique(&mut out, proxy_origin);
         } else {
-            out.push(val.to_string());
+            push_unique(&mut out, val);
         }
+    }
+
+    // Always allow the proxy's own origin.
+    push_unique(&mut out, proxy_origin);
 
-        // Also keep the original value so that if we missed something the
-        // page's own resources still load.
+    // Also keep upstream origin so inline references resolve.
+    push_unique(&mut out, upstream_origin);
+
+    out.join(" ")
+}
+
+/// Keep a source list verbatim and append the proxy origin.
+fn append_proxy_origin(proxy_origin: &str, values: &[&str]) -> String {
+    let mut out: Vec<String> = values.iter().map(|v| v.to_string()).collect();
+    if !out.iter().any(|v| v == "*") {
+        push_unique(&mut out, proxy_origin);
+    }
+    out.join(" ")
+}
+
+fn push_unique(out: &mut Vec<String>, val: &str) {
+    if !out.iter().any(|v| v == val) {
         out.push(val.to_string());
     }
+}
 
-    // Always allow the proxy's own origin.
-    let proxy_host = proxy_origin
-        .trim_start_matches("https://")
-        .trim_start_matches("http://");
-    if !has_proxy_origin {
-        out.push(proxy_origin.to_string());
+/// Allow our runtime's Trusted Types policy in a `trusted-types` directive.
+///
+/// `'none'` forbids creating any policy, so it is dropped once ours is added;
+/// `*` already allows every name.
+fn rewrite_trusted_types(values: &[&str]) -> String {
+    let mut out: Vec<&str> = values.iter().copied().filter(|v| *v != "'none'").collect();
+    if !values.contains(&"*") && !values.contains(&TRUSTED_TYPES_POLICY) {
+        out.push(TRUSTED_TYPES_POLICY);
     }
+    let mut directive = String::from("trusted-types");
+    for v in out {
+        directive.push(' ');
+        directive.push_str(v);
+    }
+    directive
+}
 
-    // Also keep upstream origin so inline references resolve.
-    if !out.iter().any(|v| v == upstream_origin) {
-        out.push(upstream_origin.to_string());
+/// Ensure a `sandbox` directive carries the flags our runtime needs,
+/// keeping every flag upstream already set.
+fn rewrite_sandbox(values: &[&str]) -> String {
+    let mut directive = String::from("sandbox");
+    for v in values {
+        directive.push(' ');
+        directive.push_str(v);
     }
+    for flag in SANDBOX_RUNTIME_FLAGS {
+        if !values.iter().any(|v| v.eq_ignore_ascii_case(flag)) {
+            directive.push(' ');
+            directive.push_str(flag);
+        }
+    }
+    directive
+}
 
-    out.join(" ")
+/// Whether `val` matches the CSP host-source grammar:
+/// `[scheme "://"] host [":" port] [path]`, where host may be `*` or start
+/// with `*.` and port may be `*`.
+fn is_host_source(val: &str) -> bool {
+    let rest = match val.split_once("://") {
+        Some((scheme, rest)) => {
+            let mut bytes = scheme.bytes();
+            let valid = bytes.next().is_some_and(|b| b.is_ascii_alphabetic())
+                && bytes.all(|b| b.is_ascii_alphanumeric() || b == b'+' || b == b'-' || b == b'.');
+            if !valid {
+                return false;
+            }
+            rest
+        }
+        None => val,
+    };
+
+    let host_port = rest.split('/').next().unwrap_or("");
+    let (host, port) = match host_port.rsplit_once(':') {
+        Some((h, p)) => (h, Some(p)),
+        None => (host_port, None),
+    };
+    if let Some(port) = port {
+        let valid = port == "*" || (!port.is_empty() && port.bytes().all(|b| b.is_ascii_digit()));
+        if !valid {
+            return false;
+        }
+    }
+
+    let host = host.strip_prefix("*.").unwrap_or(host);
+    host == "*"
+        || (!host.is_empty()
+            && host.split('.').all(|label| {
+                !label.is_empty() && label.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+            }))
 }
 
 /// Rewrite a CSP keyword token (quoted).
@@ -166,6 +297,27 @@ pub fn extract_nonce(csp: &str) -> Option<String> {
     None
 }
 
+/// Directives consulted for the nonce that governs `<style>` elements, most
+/// specific first.
+const STYLE_NONCE_DIRECTIVES: &[&str] = &["style-src-elem", "style-src", "default-src"];
+
+/// The nonce a `<style>` element needs under `csp`, taken from the most
+/// specific of `style-src-elem`, `style-src` and `default-src` that is
+/// present.  `None` when that directive carries no nonce.
+pub fn extract_style_nonce(csp: &str) -> Option<String> {
+    for name in STYLE_NONCE_DIRECTIVES {
+        let directive = csp.split(';').map(str::trim).find(|d| {
+            d.split_whitespace()
+                .next()
+                .is_some_and(|n| n.eq_ignore_ascii_case(name))
+        });
+        if let Some(directive) = directive {
+            return extract_nonce(directive);
+        }
+    }
+    None
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -196,6 +348,92 @@ mod tests {
         assert!(result.contains(PROXY));
     }
 
+    #[test]
+    fn bare_host_source_maps_to_proxy_origin() {
+        let result = rewrite_csp(PROXY, UPSTREAM, "img-src cdn.example.com");
+        assert_eq!(result, "img-src http://localhost:8080 https://example.com");
+    }
+
+    #[test]
+    fn wildcard_host_source_maps_to_proxy_origin() {
+        let result = rewrite_csp(PROXY, UPSTREAM, "img-src *.example.com");
+        assert_eq!(result, "img-src http://localhost:8080 https://example.com");
+    }
+
+    #[test]
+    fn host_source_with_port_maps_to_proxy_origin() {
+        let result = rewrite_csp(PROXY, UPSTREAM, "connect-src https://x:8443");
+        assert_eq!(result, "connect-src http://localhost:8080 https://example.com");
+        assert!(!result.contains("/proxy?url="));
+    }
+
+    #[test]
+    fn adds_trusted_types_policy() {
+        let csp = "require-trusted-types-for 'script'; trusted-types app-policy 'allow-duplicates'";
+        let result = rewrite_csp(PROXY, UPSTREAM, csp);
+        assert_eq!(
+            result,
+            "require-trusted-types-for 'script'; trusted-types app-policy 'allow-duplicates' internex"
+        );
+    }
+
+    #[test]
+    fn trusted_types_none_replaced_by_policy() {
+        let result = rewrite_csp(PROXY, UPSTREAM, "trusted-types 'none'");
+        assert_eq!(result, "trusted-types internex");
+    }
+
+    #[test]
+    fn runtime_creates_the_allowed_policy() {
+        let runtime = include_str!("../../assets/internex.runtime.js");
+        assert!(runtime.contains(&format!("createPolicy(\"{}\"", TRUSTED_TYPES_POLICY)));
+    }
+
+    #[test]
+    fn host_source_not_duplicated() {
+        let csp = "script-src 'self' https://cdn.example.com 'report-sample' 'future-keyword'";
+        let result = rewrite_csp(PROXY, UPSTREAM, csp);
+        assert_eq!(
+            result,
+            "script-src 'self' http://localhost:8080 'report-sample' 'future-keyword' https://example.com"
+        );
+        assert_eq!(result.matches("http://localhost:8080").count(), 1);
+    }
+
+    #[test]
+    fn preserve_mode_keeps_host_sources() {
+        let opts = CspOptions { preserve_sources: true, ..Default::default() };
+        let csp = "script-src 'self' https://cdn.example.com *.example.org; upgrade-insecure-requests";
+        let result = rewrite_csp_with_options(PROXY, UPSTREAM, csp, &opts);
+        assert_eq!(
+            result,
+            "script-src 'self' https://cdn.example.com *.example.org http://localhost:8080"
+        );
+    }
+
+    #[test]
+    fn sandbox_unchanged_by_default() {
+        let result = rewrite_csp(PROXY, UPSTREAM, "sandbox allow-forms");
+        assert_eq!(result, "sandbox allow-forms");
+    }
+
+    #[test]
+    fn sandbox_gains_allow_scripts() {
+        let opts = CspOptions { relax_sandbox: true, ..Default::default() };
+        let csp = "sandbox allow-forms allow-same-origin";
+        let result = rewrite_csp_with_options(PROXY, UPSTREAM, csp, &opts);
+        assert_eq!(result, "sandbox allow-forms allow-same-origin allow-scripts");
+    }
+
+    #[test]
+    fn frame_ancestors_allows_proxy_viewer() {
+        let opts = CspOptions { allow_embedding: true, ..Default::default() };
+        let result = rewrite_csp_with_options(PROXY, UPSTREAM, "frame-ancestors 'none'", &opts);
+        assert_eq!(result, "frame-ancestors 'self'");
+        let result = rewrite_csp(PROXY, UPSTREAM, "frame-ancestors 'none'");
+        assert_eq!(result, "frame-ancestors 'none'");
+    }
+
     #[test]
     fn preserves_nonces() {
         let csp = "script-src 'nonce-abc123' 'self'";
@@ -208,4 +446,14 @@ mod tests {
         let csp = "script-src 'nonce-r4nd0m' 'self'; style-src *";
         assert_eq!(extract_nonce(csp), Some("r4nd0m".to_string()));
     }
+
+    #[test]
+    fn extract_style_nonce_prefers_specific_directive() {
+        let csp = "default-src 'nonce-d'; script-src 'nonce-s'; style-src 'nonce-st'";
+        assert_eq!(extract_style_nonce(csp), Some("st".to_string()));
+        let csp = "default-src 'nonce-d'; style-src-elem 'nonce-e'; style-src 'nonce-st'";
+        assert_eq!(extract_style_nonce(csp), Some("e".to_string()));
+        assert_eq!(extract_style_nonce("script-src 'nonce-s'; default-src 'nonce-d'"), Some("d".to_string()));
+        assert_eq!(extract_style_nonce("style-src 'self'; default-src 'nonce-d'"), None);
+    }
 }
@@ -3,42 +3,106 @@
 // CSS rewriter.  Parses CSS with `cssparser` and rewrites every URL reference
 // so it routes through the proxy.  Handles:
 //
-//   url(…)  and  image-set(…)
+//   url(…), image-set(…), image(…), cross-fade(…)
 //   @import url(…)  /  @import "…"
 //   @font-face { src: url(…) }
-//   @namespace url(…)
 //   background, background-image, border-image, mask-image, filter,
 //   cursor, clip-path, shape-outside, content, list-style
 //   CSSOM sinks: insertRule, replace, replaceSync, cssRules
+//
+// Feature tests in @supports / @media preludes and supports() conditions
+// are capability probes, not fetches, and are left literal, as are
+// @namespace URIs, which are identifiers.
 
 use cssparser::{
     Parser, ParserInput, Token,
 };
 
-use crate::url::encode_url_with_base;
+use crate::url::{encode_url_with_base, rewritten_token};
 
 // ---------------------------------------------------------------------------
 // Public API
 // ---------------------------------------------------------------------------
 
+/// Appended to stylesheets [`rewrite_css`] changed for `proxy_origin`, so
+/// a second pass over cached output is a no-op.  At the end because
+/// `@charset` must come first.  `None` without a marker secret (see
+/// [`crate::url::with_marker_secret`]).
+pub fn rewritten_marker(proxy_origin: &str) -> Option<String> {
+    Some(format!("\n/*internex:rewritten:{}*/", rewritten_token(proxy_origin)?))
+}
+
 /// Rewrite a complete CSS stylesheet.
+///
+/// Input already carrying [`rewritten_marker`] is returned unchanged.
 pub fn rewrite_css(proxy_origin: &str, base_url: &str, css: &str) -> String {
-    rewrite_css_string(proxy_origin, base_url, css)
+    let marker = rewritten_marker(proxy_origin);
+    if marker.as_ref().is_some_and(|m| css.trim_end().ends_with(m.trim_start())) {
+        return css.to_string();
+    }
+    let mut out = rewrite_css_string(proxy_origin, base_url, css);
+    if let Some(marker) = marker.filter(|_| out != css) {
+        out.push_str(&marker);
+    }
+    out
 }
 
 /// Rewrite an arbitrary CSS string (stylesheet, inline style, or fragment).
 /// This is also called by the HTML rewriter for `style="…"` attributes and
 /// `<style>` elements.
 pub fn rewrite_css_string(proxy_origin: &str, base_url: &str, css: &str) -> String {
+    rewrite_css_counted(proxy_origin, base_url, css).css
+}
+
+/// Output of [`rewrite_css_counted`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CssRewrite {
+    pub css: String,
+    /// URLs whose value changed; `data:` URLs and the like that pass
+    /// through untouched are not counted.
+    pub urls_rewritten: usize,
+}
+
+/// Like [`rewrite_css_string`], also reporting how many URLs were rewritten.
+pub fn rewrite_css_counted(proxy_origin: &str, base_url: &str, css: &str) -> CssRewrite {
+    if !may_contain_urls(css) {
+        return CssRewrite {
+            css: css.to_string(),
+            urls_rewritten: 0,
+        };
+    }
+
     // We walk through the CSS token stream and rebuild the output, replacing
     // url() and string tokens inside @import / @font-face / property values.
     let mut input = ParserInput::new(css);
     let mut parser = Parser::new(&mut input);
     let mut out = String::with_capacity(css.len());
+    let mut count = 0;
 
-    rewrite_token_stream(&mut parser, proxy_origin, base_url, &mut out);
+    rewrite_token_stream(&mut parser, proxy_origin, base_url, &mut out, &mut count);
 
-    out
+    CssRewrite {
+        css: out,
+        urls_rewritten: count,
+    }
+}
+
+/// Substrings that can introduce a URL into CSS.  A backslash is included
+/// because escapes can spell any of them (`u\72l(`).
+const URL_MARKERS: &[&str] = &["url(", "@import", "image-set", "image(", "cross-fade", "\\"];
+
+/// Cheap pre-scan: `false` only when `css` cannot contain anything the
+/// token rewriter would change, so parsing can be skipped entirely.  False
+/// positives simply fall through to the full rewrite.
+fn may_contain_urls(css: &str) -> bool {
+    URL_MARKERS.iter().any(|m| contains_ignore_ascii_case(css, m))
+}
+
+fn contains_ignore_ascii_case(haystack: &str, needle: &str) -> bool {
+    haystack
+        .as_bytes()
+        .windows(needle.len())
+        .any(|w| w.eq_ignore_ascii_case(needle.as_bytes()))
 }
 
 // ---------------------------------------------------------------------------
@@ -50,49 +114,95 @@ fn rewrite_token_stream(
     proxy: &str,
     base: &str,
     out: &mut String,
+    count: &mut usize,
 ) {
-    // Track whether we are inside an @import or @font-face context so we
-    // know that bare string tokens should be treated as URLs.
+    // Set between `@import` and its URL, so a bare string token there is
+    // treated as a URL.
     let mut in_import = false;
+    // Set over an `@namespace` rule, whose URL is an identifier rather than
+    // a fetchable resource and must stay as written.
+    let mut in_namespace = false;
+    // Set between `@supports` / `@media` and the rule's `{`, and over an
+    // `@import`'s media / supports conditions up to its `;`.
+    let mut in_condition = false;
 
     loop {
+        let start = parser.position();
         let token = match parser.next_including_whitespace_and_comments() {
             Ok(t) => t.clone(),
             Err(_) => break,
         };
 
         match token {
+            // ---- @namespace prelude: copied verbatim ----
+            Token::Function(_) | Token::ParenthesisBlock if in_namespace => {
+                let _ = parser.parse_nested_block(|inner| -> Result<(), cssparser::ParseError<'_, ()>> {
+                    while inner.next_including_whitespace_and_comments().is_ok() {}
+                    Ok(())
+                });
+                out.push_str(parser.slice_from(start));
+            }
+            Token::UnquotedUrl(_) | Token::QuotedString(_) if in_namespace => {
+                out.push_str(parser.slice_from(start));
+            }
+
+            // ---- Feature tests: copied verbatim ----
+            Token::ParenthesisBlock if in_condition => {
+                let _ = parser.parse_nested_block(|inner| -> Result<(), cssparser::ParseError<'_, ()>> {
+                    while inner.next_including_whitespace_and_comments().is_ok() {}
+                    Ok(())
+                });
+                out.push_str(parser.slice_from(start));
+            }
+
+            Token::Function(ref name) if name.eq_ignore_ascii_case("supports") => {
+                let _ = parser.parse_nested_block(|inner| -> Result<(), cssparser::ParseError<'_, ()>> {
+                    while inner.next_including_whitespace_and_comments().is_ok() {}
+                    Ok(())
+                });
+                out.push_str(parser.slice_from(start));
+            }
+
             // ---- url(…) ----
+            // The tokenizer has already resolved CSS escapes (`my\ file.png`),
+            // so the value is the literal URL.  Bare `#fragment` references
+            // (`fill: url(#grad)`) are left local by `encode_url_with_base`.
             Token::UnquotedUrl(ref url_val) => {
                 let url_str: &str = url_val.as_ref();
-                let rewritten = encode_url_with_base(proxy, base, url_str)
-                    .unwrap_or_else(|| url_str.to_string());
-                out.push_str(&format!("url({})", quote_css_url(&rewritten)));
+                let rewritten = proxy_url(proxy, base, url_str, count);
+                out.push_str(&format!("url({})", escape_css_unquoted_url(&rewritten)));
+                in_import = false;
             }
 
             Token::Function(ref name) if name.eq_ignore_ascii_case("url") => {
+                in_import = false;
                 out.push_str("url(");
                 // The next token(s) inside url() are the actual URL.
-                rewrite_function_args(parser, proxy, base, out, true);
+                rewrite_function_args(parser, proxy, base, out, count, true);
                 out.push(')');
             }
 
-            Token::Function(ref name) if name.eq_ignore_ascii_case("image-set") => {
-                out.push_str("image-set(");
-                rewrite_function_args(parser, proxy, base, out, true);
+            Token::Function(ref name) if is_image_function(name) => {
+                out.push_str(name.as_ref());
+                out.push('(');
+                rewrite_function_args(parser, proxy, base, out, count, true);
                 out.push(')');
             }
 
             // ---- @import ----
+            // Only the URL is rewritten; `layer(…)`, `supports(…)` and media
+            // queries after it are kept as written.  The whitespace token
+            // that follows supplies the separator.
             Token::AtKeyword(ref kw) if kw.eq_ignore_ascii_case("import") => {
-                out.push_str("@import ");
+                out.push_str("@import");
                 in_import = true;
+                in_condition = true;
             }
 
             // ---- @namespace ----
             Token::AtKeyword(ref kw) if kw.eq_ignore_ascii_case("namespace") => {
-                out.push_str("@namespace ");
-                // The url token will be handled by the url() branch above.
+                out.push_str("@namespace");
+                in_namespace = true;
             }
 
             // ---- @font-face ----
@@ -102,6 +212,15 @@ fn rewrite_token_stream(
                 // src: is caught by the url() branch.
             }
 
+            // ---- @supports / @media ----
+            Token::AtKeyword(ref kw)
+                if kw.eq_ignore_ascii_case("supports") || kw.eq_ignore_ascii_case("media") =>
+            {
+                out.push('@');
+                out.push_str(kw.as_ref());
+                in_condition = true;
+            }
+
             // ---- Other at-keywords ----
             Token::AtKeyword(ref kw) => {
                 out.push('@');
@@ -111,21 +230,22 @@ fn rewrite_token_stream(
             // ---- Quoted strings (may be URLs in @import context) ----
             Token::QuotedString(ref s) => {
                 let s_str: &str = s.as_ref();
+                let quote = source_quote(parser.slice_from(start));
                 if in_import {
-                    let rewritten = encode_url_with_base(proxy, base, s_str)
-                        .unwrap_or_else(|| s_str.to_string());
-                    out.push_str(&format!("\"{}\"", escape_css_string(&rewritten)));
+                    let rewritten = proxy_url(proxy, base, s_str, count);
+                    out.push_str(&quote_css_string(&rewritten, quote));
                     in_import = false;
                 } else {
-                    out.push_str(&format!("\"{}\"", escape_css_string(s_str)));
+                    out.push_str(&quote_css_string(s_str, quote));
                 }
             }
 
             // ---- Blocks ----
             Token::CurlyBracketBlock => {
+                in_condition = false;
                 out.push('{');
                 let _ = parser.parse_nested_block(|inner| -> Result<(), cssparser::ParseError<'_, ()>> {
-                    rewrite_token_stream(inner, proxy, base, out);
+                    rewrite_token_stream(inner, proxy, base, out, count);
                     Ok(())
                 });
                 out.push('}');
@@ -134,7 +254,7 @@ fn rewrite_token_stream(
             Token::ParenthesisBlock => {
                 out.push('(');
                 let _ = parser.parse_nested_block(|inner| -> Result<(), cssparser::ParseError<'_, ()>> {
-                    rewrite_token_stream(inner, proxy, base, out);
+                    rewrite_token_stream(inner, proxy, base, out, count);
                     Ok(())
                 });
                 out.push(')');
@@ -143,7 +263,7 @@ fn rewrite_token_stream(
             Token::SquareBracketBlock => {
                 out.push('[');
                 let _ = parser.parse_nested_block(|inner| -> Result<(), cssparser::ParseError<'_, ()>> {
-                    rewrite_token_stream(inner, proxy, base, out);
+                    rewrite_token_stream(inner, proxy, base, out, count);
                     Ok(())
                 });
                 out.push(']');
@@ -154,59 +274,23 @@ fn rewrite_token_stream(
                 out.push_str(name.as_ref());
                 out.push('(');
                 let _ = parser.parse_nested_block(|inner| -> Result<(), cssparser::ParseError<'_, ()>> {
-                    rewrite_token_stream(inner, proxy, base, out);
+                    rewrite_token_stream(inner, proxy, base, out, count);
                     Ok(())
                 });
                 out.push(')');
             }
 
-            // ---- Everything else: serialize back ----
-            Token::Ident(ref v) => out.push_str(v.as_ref()),
-            Token::Hash(ref v) | Token::IDHash(ref v) => {
-                out.push('#');
-                out.push_str(v.as_ref());
-            }
-            Token::Number { value, .. } => out.push_str(&format_number(value)),
-            Token::Percentage { unit_value, .. } => {
-                out.push_str(&format_number(unit_value * 100.0));
-                out.push('%');
-            }
-            Token::Dimension { value, ref unit, .. } => {
-                out.push_str(&format_number(value));
-                out.push_str(unit.as_ref());
-            }
-            Token::WhiteSpace(ref _s) => out.push(' '),
-            Token::Colon => out.push(':'),
+            // ---- Everything else: copied from the source ----
+            // Re-serializing would drop escapes (`.a\:b`), number spelling
+            // (`1.428571429` through f32) and the quotes of bad strings.
+            Token::WhiteSpace(_) => out.push(' '),
             Token::Semicolon => {
                 in_import = false;
+                in_namespace = false;
+                in_condition = false;
                 out.push(';');
             }
-            Token::Comma => out.push(','),
-            Token::Delim(c) => out.push(c),
-            Token::IncludeMatch => out.push_str("~="),
-            Token::DashMatch => out.push_str("|="),
-            Token::PrefixMatch => out.push_str("^="),
-            Token::SuffixMatch => out.push_str("$="),
-            Token::SubstringMatch => out.push_str("*="),
-            Token::CDO => out.push_str("<!--"),
-            Token::CDC => out.push_str("-->"),
-            Token::Comment(ref c) => {
-                out.push_str("/*");
-                out.push_str(c.as_ref());
-                out.push_str("*/");
-            }
-            Token::BadString(ref s) => {
-                out.push_str(s.as_ref());
-            }
-            Token::BadUrl(ref s) => {
-                out.push_str("url(");
-                out.push_str(s.as_ref());
-                out.push(')');
-            }
-            Token::CloseParenthesis => out.push(')'),
-            Token::CloseSquareBracket => out.push(']'),
-            Token::CloseCurlyBracket => out.push('}'),
-            _ => {}
+            _ => out.push_str(parser.slice_from(start)),
         }
     }
 }
@@ -215,15 +299,24 @@ fn rewrite_token_stream(
 // url() / image-set() argument rewriter
 // ---------------------------------------------------------------------------
 
+/// Image functions whose bare string arguments are URLs.
+const IMAGE_FUNCTIONS: &[&str] = &["image-set", "-webkit-image-set", "image", "cross-fade"];
+
+fn is_image_function(name: &str) -> bool {
+    IMAGE_FUNCTIONS.iter().any(|f| name.eq_ignore_ascii_case(f))
+}
+
 fn rewrite_function_args(
     parser: &mut Parser<'_, '_>,
     proxy: &str,
     base: &str,
     out: &mut String,
+    count: &mut usize,
     is_url_context: bool,
 ) {
     let _ = parser.parse_nested_block(|inner| -> Result<(), cssparser::ParseError<'_, ()>> {
         loop {
+            let start = inner.position();
             let tok = match inner.next_including_whitespace_and_comments() {
                 Ok(t) => t.clone(),
                 Err(_) => break,
@@ -231,31 +324,42 @@ fn rewrite_function_args(
             match tok {
                 Token::QuotedString(ref s) if is_url_context => {
                     let s_str: &str = s.as_ref();
-                    let rewritten = encode_url_with_base(proxy, base, s_str)
-                        .unwrap_or_else(|| s_str.to_string());
-                    out.push_str(&format!("\"{}\"", escape_css_string(&rewritten)));
+                    let rewritten = proxy_url(proxy, base, s_str, count);
+                    out.push_str(&quote_css_string(&rewritten, source_quote(inner.slice_from(start))));
                 }
                 Token::UnquotedUrl(ref s) => {
                     let s_str: &str = s.as_ref();
-                    let rewritten = encode_url_with_base(proxy, base, s_str)
-                        .unwrap_or_else(|| s_str.to_string());
-                    out.push_str(&quote_css_url(&rewritten));
+                    let rewritten = proxy_url(proxy, base, s_str, count);
+                    out.push_str(&format!("url({})", escape_css_unquoted_url(&rewritten)));
+                }
+                Token::QuotedString(ref s) => {
+                    out.push_str(&quote_css_string(s.as_ref(), source_quote(inner.slice_from(start))));
                 }
                 Token::Function(ref name) if name.eq_ignore_ascii_case("url") => {
                     out.push_str("url(");
-                    rewrite_function_args(inner, proxy, base, out, true);
+                    rewrite_function_args(inner, proxy, base, out, count, true);
                     out.push(')');
                 }
-                Token::WhiteSpace(_) => out.push(' '),
-                Token::Comma => out.push(','),
-                Token::Number { value, .. } => out.push_str(&format_number(value)),
-                Token::Dimension { value, ref unit, .. } => {
-                    out.push_str(&format_number(value));
-                    out.push_str(unit.as_ref());
+                Token::Function(ref name) => {
+                    // Nested image functions keep URL semantics; anything
+                    // else (`type()`, `rgb()`, …) takes plain values.
+                    out.push_str(name.as_ref());
+                    out.push('(');
+                    rewrite_function_args(inner, proxy, base, out, count, is_image_function(name));
+                    out.push(')');
+                }
+                Token::ParenthesisBlock | Token::SquareBracketBlock | Token::CurlyBracketBlock => {
+                    let (open, close) = match tok {
+                        Token::ParenthesisBlock => ('(', ')'),
+                        Token::SquareBracketBlock => ('[', ']'),
+                        _ => ('{', '}'),
+                    };
+                    out.push(open);
+                    rewrite_function_args(inner, proxy, base, out, count, is_url_context);
+                    out.push(close);
                 }
-                Token::Ident(ref v) => out.push_str(v.as_ref()),
-                Token::Delim(c) => out.push(c),
-                _ => {}
+                Token::WhiteSpace(_) => out.push(' '),
+                _ => out.push_str(inner.slice_from(start)),
             }
         }
         Ok(())
@@ -266,23 +370,69 @@ fn rewrite_function_args(
 // Helpers
 // ---------------------------------------------------------------------------
 
-fn quote_css_url(url: &str) -> String {
-    // Always double-quote for safety.
-    format!("\"{}\"", escape_css_string(url))
+/// Route `url` through the proxy, counting it if the value changed.
+fn proxy_url(proxy: &str, base: &str, url: &str, count: &mut usize) -> String {
+    match encode_url_with_base(proxy, base, url) {
+        Some(rewritten) if rewritten != url => {
+            *count += 1;
+            rewritten
+        }
+        _ => url.to_string(),
+    }
 }
 
-fn escape_css_string(s: &str) -> String {
-    s.replace('\\', "\\\\")
-        .replace('"', "\\\"")
-        .replace('\n', "\\a ")
+/// The quote character a string token was written with, from its source
+/// text, so rewriting does not churn the author's quoting style.
+fn source_quote(token_source: &str) -> char {
+    if token_source.starts_with('\'') {
+        '\''
+    } else {
+        '"'
+    }
 }
 
-fn format_number(v: f32) -> String {
-    if v == (v as i64) as f32 {
-        format!("{}", v as i64)
-    } else {
-        format!("{}", v)
+/// Serialize `s` as a CSS string token delimited by `quote`.
+fn quote_css_string(s: &str, quote: char) -> String {
+    format!("{}{}{}", quote, escape_css_string(s, quote), quote)
+}
+
+/// Escape `s` for a CSS string token delimited by `quote`, following
+/// CSSOM's "serialize a string": NUL becomes U+FFFD, other control
+/// characters are hex-escaped (with the terminating space), and `quote` /
+/// `\` are backslash-escaped.
+fn escape_css_string(s: &str, quote: char) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\0' => out.push('\u{FFFD}'),
+            '\u{1}'..='\u{1f}' | '\u{7f}' => out.push_str(&format!("\\{:x} ", c as u32)),
+            '\\' => out.push_str("\\\\"),
+            c if c == quote => {
+                out.push('\\');
+                out.push(c);
+            }
+            _ => out.push(c),
+        }
     }
+    out
+}
+
+/// Escape `url` for the body of an unquoted `url(…)` token, where
+/// whitespace, quotes, parentheses and backslashes must all be escaped.
+fn escape_css_unquoted_url(url: &str) -> String {
+    let mut out = String::with_capacity(url.len());
+    for c in url.chars() {
+        match c {
+            '\0' => out.push('\u{FFFD}'),
+            '\u{1}'..='\u{20}' | '\u{7f}' => out.push_str(&format!("\\{:x} ", c as u32)),
+            '"' | '\'' | '(' | ')' | '\\' => {
+                out.push('\\');
+                out.push(c);
+            }
+            _ => out.push(c),
+        }
+    }
+    out
 }
 
 // ---------------------------------------------------------------------------
@@ -294,6 +444,13 @@ pub fn rewrite_insert_rule(proxy_origin: &str, base_url: &str, rule: &str) -> St
     rewrite_css_string(proxy_origin, base_url, rule)
 }
 
+/// Rewrite a single declaration value as set via CSSOM, e.g.
+/// `element.style.backgroundImage = "url(x)"` or `style.setProperty(…)`.
+/// No selector or braces are expected.
+pub fn rewrite_css_declaration(proxy_origin: &str, base_url: &str, value: &str) -> String {
+    rewrite_css_string(proxy_origin, base_url, value)
+}
+
 /// Rewrite a full stylesheet string as would be passed to
 /// `CSSStyleSheet.replace()` / `replaceSync()`.
 pub fn rewrite_replace_sync(proxy_origin: &str, base_url: &str, css: &str) -> String {
@@ -303,10 +460,17 @@ pub fn rewrite_replace_sync(proxy_origin: &str, base_url: &str, css: &str) -> St
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::url::with_marker_secret;
 
     const PROXY: &str = "http://localhost:8080";
     const BASE: &str = "https://example.com/style/";
 
+    const SECRET: &str = "deployment-secret";
+
+    fn marked(expected: &str) -> String {
+        format!("{}{}", expected, rewritten_marker(PROXY).unwrap_or_default())
+    }
+
     #[test]
     fn rewrites_url_function() {
         let css = r#"body { background: url(https://example.com/bg.png); }"#;
@@ -321,10 +485,259 @@ mod tests {
         assert!(result.contains("/proxy?url="));
     }
 
+    #[test]
+    fn url_free_stylesheet_takes_fast_path() {
+        let css = "body  { color : red;margin:0 }\n.a>.b { width: 1.50em }";
+        assert!(!may_contain_urls(css));
+        assert_eq!(rewrite_css(PROXY, BASE, css), css);
+    }
+
+    #[test]
+    fn url_bearing_stylesheet_is_rewritten() {
+        let css = "body { background: URL(bg.png) }";
+        assert!(may_contain_urls(css));
+        let result = rewrite_css(PROXY, BASE, css);
+        assert!(result.contains("/proxy?url=https://example.com/style/bg.png"));
+    }
+
+    #[test]
+    fn rewrites_url_in_media_body() {
+        let css = "@media (min-width: 600px) { .x { background: url(a.png) } }";
+        let result = rewrite_css(PROXY, BASE, css);
+        assert!(result.starts_with("@media (min-width: 600px) {"));
+        assert!(result.contains(r#"url(http://localhost:8080/proxy?url=https://example.com/style/a.png)"#));
+    }
+
+    #[test]
+    fn keeps_supports_prelude_literal() {
+        let css = "@supports (background: url(x.png)) and (not (mask: url(m.svg))) { .x { background: url(a.png) } }";
+        let result = rewrite_css(PROXY, BASE, css);
+        assert!(result.starts_with("@supports (background: url(x.png)) and (not (mask: url(m.svg))) {"));
+        assert!(result.contains("/proxy?url=https://example.com/style/a.png"));
+        assert!(!result.contains("x.png\""));
+    }
+
+    #[test]
+    fn keeps_import_supports_condition_literal() {
+        let css = r#"@import url(a.css) supports(background: url(x.png));"#;
+        let result = rewrite_css(PROXY, BASE, css);
+        assert!(result.contains("/proxy?url=https://example.com/style/a.css"));
+        assert!(result.ends_with(&marked(" supports(background: url(x.png));")));
+    }
+
+    #[test]
+    fn keeps_cursor_hotspots_and_fallback() {
+        let css = ".a { cursor: url(a.png) 2 2, url(b.png), pointer; }";
+        let result = rewrite_css(PROXY, BASE, css);
+        assert_eq!(
+            result,
+            marked(r#".a { cursor: url(http://localhost:8080/proxy?url=https://example.com/style/a.png) 2 2, url(http://localhost:8080/proxy?url=https://example.com/style/b.png), pointer; }"#)
+        );
+    }
+
+    #[test]
+    fn rewrites_image_function_string() {
+        let css = r#".a { background: image("fallback.png", red); }"#;
+        let result = rewrite_css(PROXY, BASE, css);
+        assert_eq!(
+            result,
+            marked(r#".a { background: image("http://localhost:8080/proxy?url=https://example.com/style/fallback.png", red); }"#)
+        );
+    }
+
+    #[test]
+    fn rewrites_cross_fade_urls() {
+        let css = ".a { background: cross-fade(url(a.png) 50%, url(b.png)); }";
+        let result = rewrite_css(PROXY, BASE, css);
+        assert_eq!(
+            result,
+            marked(r#".a { background: cross-fade(url(http://localhost:8080/proxy?url=https://example.com/style/a.png) 50%, url(http://localhost:8080/proxy?url=https://example.com/style/b.png)); }"#)
+        );
+    }
+
+    #[test]
+    fn escapes_control_characters_in_strings() {
+        assert_eq!(escape_css_string("a\r\nb", '"'), "a\\d \\a b");
+        assert_eq!(escape_css_string("\u{c}\0\\\"", '"'), "\\c \u{FFFD}\\\\\\\"");
+    }
+
+    #[test]
+    fn reescapes_crlf_in_strings() {
+        // URL parsing strips CR/LF, so exercise the escaper via a plain
+        // string in a stylesheet that still takes the full rewrite path.
+        let css = r#".a::after { content: "x\d \a y"; background: url(a.png) }"#;
+        let result = rewrite_css(PROXY, BASE, css);
+        assert!(result.starts_with(r#".a::after { content: "x\d \a y";"#));
+    }
+
+    #[test]
+    fn rewrites_declaration_value() {
+        let result = rewrite_css_declaration(PROXY, BASE, "url(a.png) no-repeat");
+        assert_eq!(
+            result,
+            r#"url(http://localhost:8080/proxy?url=https://example.com/style/a.png) no-repeat"#
+        );
+        assert_eq!(rewrite_css_declaration(PROXY, BASE, "1px solid red"), "1px solid red");
+    }
+
     #[test]
     fn preserves_data_urls() {
         let css = r#"body { background: url(data:image/png;base64,abc); }"#;
         let result = rewrite_css(PROXY, BASE, css);
         assert!(result.contains("data:image/png;base64,abc"));
     }
+
+    #[test]
+    fn second_pass_is_a_no_op() {
+        with_marker_secret(Some(SECRET.to_string()), || {
+            let once = rewrite_css(PROXY, BASE, ".a { background: url(a.png); }");
+            assert!(once.ends_with(&rewritten_marker(PROXY).unwrap()));
+            assert_eq!(rewrite_css(PROXY, BASE, &once), once);
+        });
+    }
+
+    #[test]
+    fn upstream_marker_does_not_skip_rewriting() {
+        with_marker_secret(Some(SECRET.to_string()), || {
+            let css = ".a { background: url(https://evil.example/a.png); }\n/*internex:rewritten*/";
+            let result = rewrite_css(PROXY, BASE, css);
+            assert!(result.contains("url(http://localhost:8080/proxy?url=https://evil.example/a.png)"));
+        });
+    }
+
+    #[test]
+    fn unchanged_stylesheet_is_not_marked() {
+        let css = ".a { color: red; }";
+        assert_eq!(rewrite_css(PROXY, BASE, css), css);
+    }
+
+    #[test]
+    fn counts_rewritten_urls() {
+        let css = r#"@import "a.css";
+.a { background: url(b.png), image-set("c.png" 1x, url(d.png) 2x); }
+.b { cursor: url(e.cur), pointer; mask: url(data:image/png;base64,AA); }"#;
+        let result = rewrite_css_counted(PROXY, BASE, css);
+        assert_eq!(result.urls_rewritten, 5);
+        assert_eq!(result.css, rewrite_css_string(PROXY, BASE, css));
+        assert_eq!(rewrite_css_counted(PROXY, BASE, ".a { color: red }").urls_rewritten, 0);
+    }
+
+    #[test]
+    fn keeps_fragment_urls_local() {
+        let css = ".a { fill: url(#grad); stroke: url(\"#g2\"); }";
+        let result = rewrite_css_string(PROXY, BASE, css);
+        assert_eq!(result, r##".a { fill: url(#grad); stroke: url("#g2"); }"##);
+    }
+
+    #[test]
+    fn unescapes_unquoted_url_before_encoding() {
+        let css = r".a { background: url(my\ file.png); b: url(a\29 b.png); }";
+        let result = rewrite_css_string(PROXY, BASE, css);
+        // The space is percent-encoded by URL resolution, then the `%` once
+        // more by the query encoding; the server decodes back to `my%20file`.
+        assert!(result.contains(r#"url(http://localhost:8080/proxy?url=https://example.com/style/my%2520file.png)"#));
+        assert!(result.contains(r"style/a\)b.png)"));
+    }
+
+    #[test]
+    fn keeps_declaration_tokens_as_written() {
+        let css = r#".a\:b { color: red !important; line-height: 1.428571429; margin: +.5em 1E3px; background: url(a.png) } #\31 23 { --x: { a: b }; --y: [1,2]; }"#;
+        let result = rewrite_css_string(PROXY, BASE, css);
+        assert_eq!(
+            result,
+            r#".a\:b { color: red !important; line-height: 1.428571429; margin: +.5em 1E3px; background: url(http://localhost:8080/proxy?url=https://example.com/style/a.png) } #\31 23 { --x: { a: b }; --y: [1,2]; }"#
+        );
+    }
+
+    #[test]
+    fn keeps_multi_range_unicode_range() {
+        let css = "@font-face { src: url(f.woff2); unicode-range: U+0000-00FF, U+0131, U+0152-0153, U+1E??; }";
+        let result = rewrite_css_string(PROXY, BASE, css);
+        assert!(result.ends_with("unicode-range: U+0000-00FF, U+0131, U+0152-0153, U+1E??; }"));
+    }
+
+    #[test]
+    fn keeps_unusual_tokens() {
+        let css = "<!-- @font-face { font-family: X; src: url(x.woff2); unicode-range: U+0025-00FF, u+4??; } a[href^=\"x\"][lang|=en][class~=b][title$=c][rel*=d] { cursor: url(bad url) } -->";
+        let result = rewrite_css_string(PROXY, BASE, css);
+        assert_eq!(
+            result,
+            "<!-- @font-face { font-family: X; src: url(http://localhost:8080/proxy?url=https://example.com/style/x.woff2); unicode-range: U+0025-00FF, u+4??; } a[href^=\"x\"][lang|=en][class~=b][title$=c][rel*=d] { cursor: url(bad url) } -->"
+        );
+    }
+
+    #[test]
+    fn keeps_blocks_inside_url_functions() {
+        let css = r#".a { background: image-set(url(a.png) 1x, "b.png" type("image/webp") 2x); mask: cross-fade(url(c.png) 50%, [d]) }"#;
+        let result = rewrite_css_string(PROXY, BASE, css);
+        assert!(result.contains(r#"type("image/webp") 2x)"#));
+        assert!(result.contains(r#""http://localhost:8080/proxy?url=https://example.com/style/b.png""#));
+        assert!(result.contains("50%, [d])"));
+    }
+
+    #[test]
+    fn namespace_uris_are_not_proxied() {
+        let css = r#"@namespace svg url(http://www.w3.org/2000/svg); @namespace "http://www.w3.org/1999/xhtml"; @namespace m url("http://www.w3.org/1998/Math/MathML"); svg|a { background: url(a.png) }"#;
+        let result = rewrite_css_string(PROXY, BASE, css);
+        assert_eq!(
+            result,
+            r#"@namespace svg url(http://www.w3.org/2000/svg); @namespace "http://www.w3.org/1999/xhtml"; @namespace m url("http://www.w3.org/1998/Math/MathML"); svg|a { background: url(http://localhost:8080/proxy?url=https://example.com/style/a.png) }"#
+        );
+    }
+
+    #[test]
+    fn import_keeps_layer_and_media_conditions() {
+        let css = "@import url(x.css) layer(a) screen and (min-width:600px);\n.a { content: \"b.png\"; }";
+        let result = rewrite_css_string(PROXY, BASE, css);
+        assert_eq!(
+            result,
+            "@import url(http://localhost:8080/proxy?url=https://example.com/style/x.css) layer(a) screen and (min-width:600px); .a { content: \"b.png\"; }"
+        );
+    }
+
+    #[test]
+    fn unquoted_import_url_clears_import_context() {
+        let css = r#"@import url(a.css) "screen"; @import "b.css" "print";"#;
+        let result = rewrite_css_string(PROXY, BASE, css);
+        assert_eq!(
+            result,
+            r#"@import url(http://localhost:8080/proxy?url=https://example.com/style/a.css) "screen"; @import "http://localhost:8080/proxy?url=https://example.com/style/b.css" "print";"#
+        );
+    }
+
+    #[test]
+    fn import_url_does_not_rewrite_later_strings() {
+        let css = r#"@import url("x.css") supports(display: grid) "print";"#;
+        let result = rewrite_css_string(PROXY, BASE, css);
+        assert_eq!(
+            result,
+            r#"@import url("http://localhost:8080/proxy?url=https://example.com/style/x.css") supports(display: grid) "print";"#
+        );
+    }
+
+    #[test]
+    fn preserves_url_quoting_style() {
+        let css = r#".a { background: url(a.png), url('b.png'), url("c.png"); }"#;
+        let result = rewrite_css_string(PROXY, BASE, css);
+        assert_eq!(
+            result,
+            r#".a { background: url(http://localhost:8080/proxy?url=https://example.com/style/a.png), url('http://localhost:8080/proxy?url=https://example.com/style/b.png'), url("http://localhost:8080/proxy?url=https://example.com/style/c.png"); }"#
+        );
+        let result = rewrite_css_string(PROXY, BASE, "@import 'd.css';");
+        assert_eq!(result, "@import 'http://localhost:8080/proxy?url=https://example.com/style/d.css';");
+    }
+
+    #[test]
+    fn escapes_for_the_original_quote() {
+        assert_eq!(quote_css_string("it's \"x\"", '\''), r#"'it\'s "x"'"#);
+        assert_eq!(escape_css_unquoted_url("a b(c)'d"), r"a\20 b\(c\)\'d");
+    }
+
+    #[test]
+    fn keeps_leading_bom() {
+        let css = "\u{FEFF}@charset \"utf-8\";\n.a { background: url(a.png) }";
+        let result = rewrite_css(PROXY, BASE, css);
+        assert!(result.starts_with("\u{FEFF}@charset \"utf-8\";"));
+        assert!(result.contains("url(http://localhost:8080/proxy?url="));
+    }
 }
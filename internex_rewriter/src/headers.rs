@@ -0,0 +1,243 @@
+// internex_rewriter::headers
+//
+// Response-header rewriter.  Upstream headers that carry hosts or URLs are
+// adjusted so the browser keeps talking to the proxy:
+//
+//   Set-Cookie    Domain / Path / SameSite / Secure
+//   Location      3xx redirect targets
+//   X-Frame-Options  dropped when the page must embed in the proxy viewer
+//
+// Header values are plain strings; the Go server is responsible for
+// iterating the header map and calling the matching function.
+
+use url::Url;
+
+use crate::url::encode_url_with_base;
+
+// ---------------------------------------------------------------------------
+// Set-Cookie
+// ---------------------------------------------------------------------------
+
+/// Rewrite a `Set-Cookie` header value so the cookie is stored against the
+/// proxy instead of the upstream host.
+///
+/// * `proxy_origin`  – our proxy's origin, e.g. `"http://localhost:8080"`.
+/// * `upstream_host` – host of the response being proxied, e.g.
+///   `"www.example.com"`.
+/// * `value`         – the raw header value.  Several cookies folded into
+///   one value with commas are handled; commas inside `Expires` dates are
+///   not treated as separators.
+///
+/// The cookie name, value, and expiry are preserved.  `Domain` is pointed
+/// at the proxy host (or dropped when the proxy runs on a bare host such as
+/// `localhost`), `Path` is widened to `/` because every proxied request is
+/// served from the same route, and `Secure` / `SameSite=None` are relaxed
+/// when the proxy itself is served over plain http.  Cookies whose `Domain`
+/// does not cover `upstream_host` would have been rejected by the browser
+/// anyway and are dropped.
+pub fn rewrite_set_cookie(proxy_origin: &str, upstream_host: &str, value: &str) -> String {
+    let (proxy_host, proxy_is_secure) = match Url::parse(proxy_origin) {
+        Ok(u) => (
+            u.host_str().unwrap_or_default().to_string(),
+            u.scheme() == "https",
+        ),
+        Err(_) => (String::new(), false),
+    };
+
+    split_cookies(value)
+        .into_iter()
+        .filter_map(|cookie| rewrite_cookie(&proxy_host, proxy_is_secure, upstream_host, cookie))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn rewrite_cookie(
+    proxy_host: &str,
+    proxy_is_secure: bool,
+    upstream_host: &str,
+    cookie: &str,
+) -> Option<String> {
+    let mut parts = cookie.split(';').map(str::trim).filter(|p| !p.is_empty());
+    let name_value = parts.next()?;
+
+    let mut out: Vec<String> = vec![name_value.to_string()];
+    let mut has_path = false;
+
+    for attr in parts {
+        let (key, val) = match attr.split_once('=') {
+            Some((k, v)) => (k.trim(), v.trim()),
+            None => (attr, ""),
+        };
+
+        if key.eq_ignore_ascii_case("domain") {
+            if !domain_matches(upstream_host, val) {
+                return None;
+            }
+            if proxy_host.contains('.') && proxy_host.parse::<std::net::IpAddr>().is_err() {
+                out.push(format!("Domain={}", proxy_host));
+            }
+        } else if key.eq_ignore_ascii_case("path") {
+            has_path = true;
+            out.push("Path=/".to_string());
+        } else if key.eq_ignore_ascii_case("secure") {
+            if proxy_is_secure {
+                out.push(attr.to_string());
+            }
+        } else if key.eq_ignore_ascii_case("samesite") {
+            if val.eq_ignore_ascii_case("none") && !proxy_is_secure {
+                // SameSite=None without Secure is rejected by browsers.
+                out.push("SameSite=Lax".to_string());
+            } else {
+                out.push(attr.to_string());
+            }
+        } else {
+            // Expires, Max-Age, HttpOnly, Partitioned, … – unchanged.
+            out.push(attr.to_string());
+        }
+    }
+
+    if !has_path {
+        out.push("Path=/".to_string());
+    }
+
+    Some(out.join("; "))
+}
+
+/// Split a header value that may contain several comma-folded cookies.
+///
+/// A comma only starts a new cookie when the text after it (up to the next
+/// `;` or `,`) looks like `name=value`; the comma in
+/// `Expires=Wed, 21 Oct 2015 07:28:00 GMT` does not.
+fn split_cookies(value: &str) -> Vec<&str> {
+    let mut cookies = Vec::new();
+    let mut start = 0;
+    for (idx, _) in value.match_indices(',') {
+        let rest = &value[idx + 1..];
+        let next = rest.split([';', ',']).next().unwrap_or("");
+        if next.contains('=') {
+            cookies.push(value[start..idx].trim());
+            start = idx + 1;
+        }
+    }
+    cookies.push(value[start..].trim());
+    cookies.retain(|c| !c.is_empty());
+    cookies
+}
+
+/// RFC 6265 domain-match: `host` equals `domain` or is a subdomain of it.
+fn domain_matches(host: &str, domain: &str) -> bool {
+    let host = host.to_ascii_lowercase();
+    let domain = domain.trim_start_matches('.').to_ascii_lowercase();
+    host == domain || host.ends_with(&format!(".{}", domain))
+}
+
+// ---------------------------------------------------------------------------
+// Location
+// ---------------------------------------------------------------------------
+
+/// Rewrite a `Location` header value so a redirect stays inside the proxy.
+///
+/// * `proxy_origin` – our proxy's origin, e.g. `"http://localhost:8080"`.
+/// * `base_url`     – the URL of the request that produced the redirect;
+///   relative and protocol-relative targets are resolved against it.
+/// * `value`        – the raw header value.
+///
+/// Values that cannot be proxied are returned unchanged.
+pub fn rewrite_location(proxy_origin: &str, base_url: &str, value: &str) -> String {
+    encode_url_with_base(proxy_origin, base_url, value).unwrap_or_else(|| value.to_string())
+}
+
+// ---------------------------------------------------------------------------
+// X-Frame-Options
+// ---------------------------------------------------------------------------
+
+/// Rewrite an `X-Frame-Options` header value.  Returns `None` when the
+/// header should be dropped.
+///
+/// With `allow_embedding` the proxied page must render inside the proxy's
+/// viewer frame.  The viewer is served from the proxy origin, as is every
+/// proxied page, so `SAMEORIGIN` already permits it and is kept; `DENY` and
+/// the obsolete `ALLOW-FROM` are dropped.  Without `allow_embedding` the
+/// value is returned unchanged.  Pair with
+/// [`CspOptions::allow_embedding`](crate::csp::CspOptions::allow_embedding)
+/// for the `frame-ancestors` directive.
+pub fn rewrite_x_frame_options(allow_embedding: bool, value: &str) -> Option<String> {
+    if !allow_embedding {
+        return Some(value.to_string());
+    }
+    if value.trim().eq_ignore_ascii_case("sameorigin") {
+        Some(value.trim().to_string())
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROXY: &str = "http://localhost:8080";
+    const BASE: &str = "https://example.com/login";
+
+    #[test]
+    fn rewrites_cookie_with_domain() {
+        let value = "sid=abc123; Domain=example.com; Path=/account; Secure; SameSite=None; HttpOnly";
+        let result = rewrite_set_cookie(PROXY, "www.example.com", value);
+        assert_eq!(result, "sid=abc123; Path=/; SameSite=Lax; HttpOnly");
+    }
+
+    #[test]
+    fn rewrites_cookie_without_domain() {
+        let value = "theme=dark; Expires=Wed, 21 Oct 2026 07:28:00 GMT";
+        let result = rewrite_set_cookie(PROXY, "example.com", value);
+        assert_eq!(result, "theme=dark; Expires=Wed, 21 Oct 2026 07:28:00 GMT; Path=/");
+    }
+
+    #[test]
+    fn keeps_domain_for_named_proxy_host() {
+        let value = "a=1; Domain=.example.com; Secure";
+        let result = rewrite_set_cookie("https://proxy.test", "example.com", value);
+        assert_eq!(result, "a=1; Domain=proxy.test; Secure; Path=/");
+    }
+
+    #[test]
+    fn splits_folded_cookies() {
+        let value = "a=1; Expires=Wed, 21 Oct 2026 07:28:00 GMT, b=2";
+        let result = rewrite_set_cookie(PROXY, "example.com", value);
+        assert_eq!(result, "a=1; Expires=Wed, 21 Oct 2026 07:28:00 GMT; Path=/, b=2; Path=/");
+    }
+
+    #[test]
+    fn drops_foreign_domain() {
+        let value = "a=1; Domain=other.com";
+        assert_eq!(rewrite_set_cookie(PROXY, "example.com", value), "");
+    }
+
+    #[test]
+    fn x_frame_options_deny_removed_when_embedding() {
+        assert_eq!(rewrite_x_frame_options(true, "DENY"), None);
+        assert_eq!(rewrite_x_frame_options(true, "SAMEORIGIN"), Some("SAMEORIGIN".to_string()));
+        assert_eq!(rewrite_x_frame_options(false, "DENY"), Some("DENY".to_string()));
+    }
+
+    #[test]
+    fn location_relative() {
+        let result = rewrite_location(PROXY, BASE, "/next");
+        assert_eq!(result, "http://localhost:8080/proxy?url=https://example.com/next");
+    }
+
+    #[test]
+    fn location_protocol_relative() {
+        let result = rewrite_location(PROXY, BASE, "//cdn.example.com/welcome");
+        assert_eq!(result, "http://localhost:8080/proxy?url=https://cdn.example.com/welcome");
+    }
+
+    #[test]
+    fn location_absolute() {
+        let result = rewrite_location(PROXY, BASE, "https://accounts.example.org/?a=1&b=2");
+        assert_eq!(
+            result,
+            "http://localhost:8080/proxy?url=https://accounts.example.org/?a%3D1%26b%3D2"
+        );
+    }
+}
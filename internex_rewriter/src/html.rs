@@ -5,98 +5,408 @@
 // meta refresh, SVG link, <template> content, and DOM-manipulation sink so
 // that all traffic flows through the proxy.
 
+use encoding_rs::{Encoding, UTF_16BE, UTF_16LE, UTF_8, WINDOWS_1252};
 use kuchikiki::traits::*;
-use kuchikiki::{parse_html, NodeRef, NodeData};
-use html5ever::serialize::{serialize, SerializeOpts};
-use markup5ever::{ns, namespace_url};
+use kuchikiki::{parse_fragment, parse_html, NodeRef, NodeData};
+use html5ever::serialize::{serialize, SerializeOpts, TraversalScope};
+use html5ever::QualName;
+use markup5ever::{local_name, ns, namespace_url};
+use serde_derive::Serialize;
 use serde_json;
+use url::Url;
 
-use crate::url::encode_url_with_base;
-use crate::css::rewrite_css_string;
+use crate::url::{count_proxied, encode_url_with_base, rewritten_token};
+use crate::css::{rewrite_css_counted, rewrite_css_string};
+use crate::js::{rewrite_js_with_options, JsOptions};
 
 // ---------------------------------------------------------------------------
 // Public entry point
 // ---------------------------------------------------------------------------
 
+/// `data-*` attributes treated as URL-bearing by default.  Lazy-loading
+/// libraries keep the real resource URL in these until the element scrolls
+/// into view.
+pub const DEFAULT_DATA_URL_ATTRS: &[&str] = &[
+    "data-src", "data-srcset", "data-original", "data-bg", "data-background",
+];
+
+/// `<meta name>`s whose `content` is a resource URL the browser loads
+/// (Windows tile images and browserconfig), proxied by default.
+pub const DEFAULT_META_URL_NAMES: &[&str] = &[
+    "msapplication-TileImage", "msapplication-config",
+    "msapplication-square70x70logo", "msapplication-square150x150logo",
+    "msapplication-wide310x150logo", "msapplication-square310x310logo",
+];
+
+/// Comment appended to every document rewritten for `proxy_origin`, so a
+/// second pass over cached output is a no-op.  The marker carries a token
+/// only holders of the marker secret can produce, so upstream pages cannot
+/// forge it; `None` without a secret (see
+/// [`crate::url::with_marker_secret`]).
+pub fn rewritten_marker(proxy_origin: &str) -> Option<String> {
+    Some(format!("<!--{}-->", rewritten_comment(proxy_origin)?))
+}
+
+fn rewritten_comment(proxy_origin: &str) -> Option<String> {
+    Some(format!("internex:rewritten:{}", rewritten_token(proxy_origin)?))
+}
+
+/// Options controlling optional HTML rewriting passes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HtmlOptions {
+    /// When set, `<meta name="referrer">` is rewritten to this policy and
+    /// every element that fetches a resource gets a matching
+    /// `referrerpolicy` attribute, so the proxied URL structure does not
+    /// leak through the `Referer` header.  `None` preserves the upstream
+    /// policy.
+    pub referrer_policy: Option<String>,
+
+    /// Parse and rewrite the markup inside IE-style conditional comments
+    /// (`<!--[if IE]><link href="…"><![endif]-->`).  Off by default since
+    /// most comments are inert.
+    pub rewrite_conditional_comments: bool,
+
+    /// `data-*` attribute names whose values are URLs.  Names containing
+    /// `srcset` are parsed as srcset lists.
+    pub data_url_attrs: Vec<String>,
+
+    /// `<meta name>`s (matched case-insensitively) whose `content` is a URL.
+    pub meta_url_names: Vec<String>,
+
+    /// Proxy the URLs in Open Graph / Twitter card `<meta content>` so link
+    /// previews of a crawled proxy page load through the proxy.  Off by
+    /// default since some crawlers want the canonical origin.
+    pub rewrite_social_meta: bool,
+
+    /// Proxy `<link rel="canonical">`.  Off by default: the canonical URL
+    /// tells crawlers where the real page lives.  Other `rel` values,
+    /// including `alternate` / hreflang links, are always proxied so
+    /// navigation stays inside the proxy.
+    pub proxy_canonical_links: bool,
+
+    /// Wrap inline `<script>` bodies in the runtime scope.  Disable when a
+    /// client-side runtime handles inline scripts and the wrapper would
+    /// break their CSP hashes or debugging.
+    pub rewrite_inline_scripts: bool,
+
+    /// Put the inline `<script>` wrapper's prefix on the body's first line
+    /// instead of its own, so line numbers in stack traces and source maps
+    /// match the original.  Event handler wrappers are always single-line.
+    pub preserve_script_lines: bool,
+
+    /// Rewrite `style` attributes and `<style>` element contents.
+    pub rewrite_inline_styles: bool,
+
+    /// Wrap `on*` event handler attributes in the runtime scope.
+    pub rewrite_event_handlers: bool,
+
+    /// The page's `style-src` nonce (see [`crate::csp::extract_style_nonce`]).
+    /// Stamped onto `<style>` elements whose content was rewritten and that
+    /// carry no nonce, since a `'sha256-…'` source no longer matches the
+    /// rewritten text.  `style=` attributes cannot carry a nonce.
+    pub style_nonce: Option<String>,
+}
+
+impl Default for HtmlOptions {
+    fn default() -> Self {
+        HtmlOptions {
+            referrer_policy: None,
+            rewrite_conditional_comments: false,
+            data_url_attrs: DEFAULT_DATA_URL_ATTRS.iter().map(|a| a.to_string()).collect(),
+            meta_url_names: DEFAULT_META_URL_NAMES.iter().map(|n| n.to_string()).collect(),
+            rewrite_social_meta: false,
+            proxy_canonical_links: false,
+            rewrite_inline_scripts: true,
+            preserve_script_lines: false,
+            rewrite_inline_styles: true,
+            rewrite_event_handlers: true,
+            style_nonce: None,
+        }
+    }
+}
+
 /// Rewrite a full HTML document so every URL routes through the proxy.
 ///
 /// * `proxy_origin` – e.g. `"http://localhost:8080"`
 /// * `base_url`     – the original page URL (for resolving relative paths)
 /// * `html`         – raw HTML source
 pub fn rewrite_html(proxy_origin: &str, base_url: &str, html: &str) -> String {
+    rewrite_html_with_options(proxy_origin, base_url, html, &HtmlOptions::default())
+}
+
+/// Rewrite an HTML document given as raw bytes in an unknown encoding.
+///
+/// The charset is taken from a byte-order mark, then `charset_hint` (e.g.
+/// from the `Content-Type` header), then a `<meta charset>` in the first
+/// 1024 bytes; without any of those, valid UTF-8 is assumed and anything
+/// else is read as Windows-1252.  The output is always UTF-8, with the
+/// document's meta charset updated to match.
+pub fn rewrite_html_bytes(
+    proxy_origin: &str,
+    base_url: &str,
+    bytes: &[u8],
+    charset_hint: Option<&str>,
+) -> String {
+    rewrite_html_bytes_with_options(proxy_origin, base_url, bytes, charset_hint, &HtmlOptions::default())
+}
+
+/// Like [`rewrite_html_bytes`], with optional passes enabled via `opts`.
+pub fn rewrite_html_bytes_with_options(
+    proxy_origin: &str,
+    base_url: &str,
+    bytes: &[u8],
+    charset_hint: Option<&str>,
+    opts: &HtmlOptions,
+) -> String {
+    rewrite_html_with_options(proxy_origin, base_url, &decode_html_bytes(bytes, charset_hint), opts)
+}
+
+/// Decode HTML bytes as described on [`rewrite_html_bytes`].
+pub(crate) fn decode_html_bytes(bytes: &[u8], charset_hint: Option<&str>) -> String {
+    let encoding = detect_charset(bytes, charset_hint);
+    encoding.decode(bytes).0.into_owned()
+}
+
+/// Like [`rewrite_html`], with optional passes enabled via `opts`.
+///
+/// Input already carrying [`rewritten_marker`] is returned unchanged.
+pub fn rewrite_html_with_options(
+    proxy_origin: &str,
+    base_url: &str,
+    html: &str,
+    opts: &HtmlOptions,
+) -> String {
+    rewrite_html_collecting(proxy_origin, base_url, html, opts, &JsOptions::default()).html
+}
+
+/// Output of [`rewrite_html_with_resources`].
+#[derive(Debug, Clone)]
+pub struct HtmlRewrite {
+    pub html: String,
+    /// Original (pre-proxy) absolute URLs of the sub-resources the page loads.
+    pub resources: Vec<Resource>,
+    /// URLs routed through the proxy, across attributes, inline styles and
+    /// `<style>` elements.
+    pub urls_rewritten: usize,
+}
+
+/// Like [`rewrite_html_with_options`], but also reports the sub-resources
+/// the page loads, so the server can warm its cache without re-parsing the
+/// output, and how many URLs were rewritten.
+pub fn rewrite_html_with_resources(
+    proxy_origin: &str,
+    base_url: &str,
+    html: &str,
+    opts: &HtmlOptions,
+) -> HtmlRewrite {
+    rewrite_html_collecting(proxy_origin, base_url, html, opts, &JsOptions::default())
+}
+
+/// The rewrite behind the `rewrite_html*` functions, with `js_opts` applied
+/// to inline module scripts, which go through the JS rewriter.
+pub(crate) fn rewrite_html_collecting(
+    proxy_origin: &str,
+    base_url: &str,
+    html: &str,
+    opts: &HtmlOptions,
+    js_opts: &JsOptions,
+) -> HtmlRewrite {
+    if rewritten_marker(proxy_origin).is_some_and(|marker| html.trim_end().ends_with(&marker)) {
+        return HtmlRewrite {
+            html: html.to_string(),
+            resources: Vec::new(),
+            urls_rewritten: 0,
+        };
+    }
+
     let doc = parse_html().one(html);
 
     // Determine <base href> if present – it overrides the page URL for
     // relative resolution.
-    let effective_base = find_base_href(&doc).unwrap_or_else(|| base_url.to_string());
+    let effective_base = find_base_href(&doc, base_url).unwrap_or_else(|| base_url.to_string());
 
-    walk(&doc, proxy_origin, &effective_base);
+    let mut found = Vec::new();
+    let mut count = 0;
+    walk(&doc, proxy_origin, &effective_base, opts, js_opts, &mut found, &mut count);
     inject_client_script(&doc, proxy_origin, &effective_base);
+    if let Some(comment) = rewritten_comment(proxy_origin) {
+        doc.append(NodeRef::new_comment(comment));
+    }
 
-    let mut buf = Vec::new();
-    serialize(
-        &mut buf,
-        &doc,
-        SerializeOpts {
-            scripting_enabled: true,
-            traversal_scope: html5ever::serialize::TraversalScope::IncludeNode,
-            create_missing_parent: false,
-        },
-    )
-    .expect("serialization failed");
-
-    String::from_utf8(buf).unwrap_or_else(|_| html.to_string())
+    // On a serialization failure fall back to the untouched input rather
+    // than panicking across the FFI boundary.
+    let out = serialize_node(&doc, TraversalScope::IncludeNode).unwrap_or_else(|| html.to_string());
+    HtmlRewrite {
+        html: out,
+        resources: found,
+        urls_rewritten: count,
+    }
 }
 
 // ---------------------------------------------------------------------------
 // DOM walker
 // ---------------------------------------------------------------------------
 
-fn walk(node: &NodeRef, proxy: &str, base: &str) {
+/// Marker attribute that opts an element and its subtree out of rewriting.
+/// It is removed from the output.
+const SKIP_ATTR: &str = "data-internex-skip";
+
+fn walk(
+    node: &NodeRef,
+    proxy: &str,
+    base: &str,
+    opts: &HtmlOptions,
+    js_opts: &JsOptions,
+    found: &mut Vec<Resource>,
+    count: &mut usize,
+) {
     if let NodeData::Element(ref el) = *node.data() {
+        if el.attributes.borrow_mut().remove_ordered(SKIP_ATTR) {
+            restore_template_contents_deep(node);
+            return;
+        }
+
         let tag = el.name.local.to_string().to_ascii_lowercase();
         let mut attrs = el.attributes.borrow_mut();
 
+        // ---- Sub-resources, recorded before their URLs are proxied ----
+        collect_resources(&tag, &attrs, base, found);
+        let proxied_before = count_proxied_attrs(&attrs, proxy);
+
         // ---- URL attributes ----
-        rewrite_url_attrs(&tag, &mut attrs, proxy, base);
+        rewrite_url_attrs(&tag, &mut attrs, proxy, base, opts);
+
+        // ---- URL lists (ping, archive) ----
+        rewrite_url_list_attr(&mut attrs, "ping", proxy, base, |c| c.is_ascii_whitespace());
+        rewrite_url_list_attr(&mut attrs, "archive", proxy, base, |c| {
+            c.is_ascii_whitespace() || c == ','
+        });
 
         // ---- srcset / imagesrcset ----
         rewrite_srcset_attr(&mut attrs, "srcset", proxy, base);
         rewrite_srcset_attr(&mut attrs, "imagesrcset", proxy, base);
 
-        // ---- <meta http-equiv="refresh"> ----
+        // ---- Lazy-loading data-* attributes ----
+        rewrite_data_url_attrs(&mut attrs, &opts.data_url_attrs, proxy, base);
+
+        // ---- <param name="movie" value="…"> ----
+        if tag == "param" {
+            rewrite_param_value(&mut attrs, proxy, base);
+        }
+
+        // ---- <link rel="preload" / "modulepreload"> ----
+        if tag == "link" {
+            strip_preload_integrity(&mut attrs);
+        }
+
+        // ---- <script type="module"> ----
+        let is_module = tag == "script" && is_module_script(&attrs);
+        if is_module && attrs.contains("src") {
+            attrs.remove_ordered("integrity");
+        }
+
+        // ---- <meta http-equiv="refresh"> / charset ----
         if tag == "meta" {
             rewrite_meta_refresh(&mut attrs, proxy, base);
+            rewrite_meta_charset(&mut attrs);
+            rewrite_meta_url_content(&mut attrs, proxy, base, &opts.meta_url_names);
+            if opts.rewrite_social_meta {
+                rewrite_social_meta(&mut attrs, proxy, base);
+            }
+        }
+
+        // ---- Referrer policy ----
+        if let Some(ref policy) = opts.referrer_policy {
+            apply_referrer_policy(&tag, &mut attrs, policy);
         }
 
         // ---- Inline styles ----
-        if let Some(style) = attrs.get("style").map(|s| s.to_string()) {
-            let rewritten = rewrite_css_string(proxy, base, &style);
-            attrs.set("style", rewritten);
+        if opts.rewrite_inline_styles {
+            if let Some(style) = attrs.get("style").map(|s| s.to_string()) {
+                let rewritten = rewrite_css_string(proxy, base, &style);
+                attrs.set("style", rewritten);
+            }
         }
 
         // ---- Inline event handlers ----
-        rewrite_event_handlers(&mut attrs, proxy, base);
+        if opts.rewrite_event_handlers {
+            rewrite_event_handlers(&mut attrs, proxy, base);
+        }
 
         // ---- SVG attributes ----
         rewrite_svg_attrs(&tag, &mut attrs, proxy, base);
 
+        // ---- MathML attributes ----
+        if el.name.ns == ns!(mathml) {
+            rewrite_mathml_attrs(&mut attrs, proxy, base);
+        }
+
+        *count += count_proxied_attrs(&attrs, proxy).saturating_sub(proxied_before);
+
         // ---- <style> element: rewrite the text content ----
+        // The tag check ignores the namespace, so SVG <style> is covered too.
         drop(attrs); // release borrow
-        if tag == "style" {
-            rewrite_inline_style_element(node, proxy, base);
+        if tag == "style" && opts.rewrite_inline_styles {
+            let rewritten = rewrite_inline_style_element(node, proxy, base);
+            if rewritten > 0 {
+                if let Some(ref nonce) = opts.style_nonce {
+                    let mut attrs = el.attributes.borrow_mut();
+                    if !attrs.contains("nonce") {
+                        attrs.insert("nonce", nonce.clone());
+                    }
+                }
+            }
+            *count += rewritten;
         }
 
         // ---- <script>: wrap dangerous sinks ----
-        if tag == "script" {
-            rewrite_inline_script(node, proxy, base);
+        if tag == "script" && opts.rewrite_inline_scripts {
+            if is_module {
+                rewrite_inline_module(node, proxy, base, js_opts);
+            } else {
+                rewrite_inline_script(node, proxy, base, opts.preserve_script_lines);
+            }
+        }
+
+        // ---- <template> content ----
+        restore_template_contents(node);
+    }
+
+    if opts.rewrite_conditional_comments {
+        if let NodeData::Comment(ref text) = *node.data() {
+            let rewritten =
+                rewrite_conditional_comment(&text.borrow(), proxy, base, opts, js_opts, found, count);
+            if let Some(rewritten) = rewritten {
+                *text.borrow_mut() = rewritten;
+            }
         }
     }
 
-    // Recurse into children (handles <template> content automatically
-    // because kuchikiki exposes template contents as children).
+    // Recurse into children.
     for child in node.children() {
-        walk(&child, proxy, base);
+        walk(&child, proxy, base, opts, js_opts, found, count);
+    }
+}
+
+/// html5ever parks `<template>` content in a separate fragment that is
+/// neither reachable via `children()` nor emitted by the serializer.  Move it
+/// back under the element so it is walked and survives serialization.
+fn restore_template_contents(node: &NodeRef) {
+    if let NodeData::Element(ref el) = *node.data() {
+        if let Some(ref contents) = el.template_contents {
+            let moved: Vec<NodeRef> = contents.children().collect();
+            for child in moved {
+                node.append(child);
+            }
+        }
+    }
+}
+
+/// [`restore_template_contents`] for a whole subtree that is not walked.
+fn restore_template_contents_deep(node: &NodeRef) {
+    restore_template_contents(node);
+    for child in node.children() {
+        restore_template_contents_deep(&child);
     }
 }
 
@@ -107,7 +417,7 @@ fn walk(node: &NodeRef, proxy: &str, base: &str) {
 /// Standard element attributes that contain a single URL.
 const URL_ATTRS: &[&str] = &[
     "href", "src", "action", "formaction", "poster", "data", "manifest",
-    "background", "ping", "cite", "longdesc", "usemap", "archive",
+    "background", "cite", "longdesc", "usemap",
     "codebase", "classid",
 ];
 
@@ -116,8 +426,14 @@ fn rewrite_url_attrs(
     attrs: &mut kuchikiki::Attributes,
     proxy: &str,
     base: &str,
+    opts: &HtmlOptions,
 ) {
+    let keep_href = tag == "link" && !opts.proxy_canonical_links && is_canonical_link(attrs);
+
     for &attr in URL_ATTRS {
+        if keep_href && attr == "href" {
+            continue;
+        }
         if let Some(val) = attrs.get(attr).map(|s| s.to_string()) {
             if let Some(encoded) = encode_url_with_base(proxy, base, &val) {
                 attrs.set(attr, encoded);
@@ -131,6 +447,72 @@ fn rewrite_url_attrs(
     // <object> and <embed> also may have "type" – no rewriting needed there.
 }
 
+fn is_canonical_link(attrs: &kuchikiki::Attributes) -> bool {
+    attrs
+        .get("rel")
+        .is_some_and(|rel| rel.split_ascii_whitespace().any(|r| r.eq_ignore_ascii_case("canonical")))
+}
+
+fn rewrite_data_url_attrs(
+    attrs: &mut kuchikiki::Attributes,
+    names: &[String],
+    proxy: &str,
+    base: &str,
+) {
+    for name in names {
+        if name.contains("srcset") {
+            rewrite_srcset_attr(attrs, name, proxy, base);
+        } else if let Some(val) = attrs.get(name.as_str()).map(|s| s.to_string()) {
+            if let Some(encoded) = encode_url_with_base(proxy, base, &val) {
+                attrs.set(name, encoded);
+            }
+        }
+    }
+}
+
+// ---------------------------------------------------------------------------
+// URL lists  (<a ping="url1 url2">, <object archive="a.jar,b.jar">)
+// ---------------------------------------------------------------------------
+
+fn rewrite_url_list_attr(
+    attrs: &mut kuchikiki::Attributes,
+    attr: &str,
+    proxy: &str,
+    base: &str,
+    is_separator: impl Fn(char) -> bool,
+) {
+    if let Some(val) = attrs.get(attr).map(|s| s.to_string()) {
+        let rewritten = rewrite_url_list(proxy, base, &val, is_separator);
+        attrs.set(attr, rewritten);
+    }
+}
+
+/// Encode every URL in a separated list, keeping the separators verbatim.
+fn rewrite_url_list(
+    proxy: &str,
+    base: &str,
+    list: &str,
+    is_separator: impl Fn(char) -> bool,
+) -> String {
+    let mut out = String::with_capacity(list.len());
+    let mut rest = list;
+    while !rest.is_empty() {
+        let url_start = rest.find(|c| !is_separator(c)).unwrap_or(rest.len());
+        out.push_str(&rest[..url_start]);
+        rest = &rest[url_start..];
+
+        let url_end = rest.find(&is_separator).unwrap_or(rest.len());
+        let url = &rest[..url_end];
+        if !url.is_empty() {
+            let encoded = encode_url_with_base(proxy, base, url)
+                .unwrap_or_else(|| url.to_string());
+            out.push_str(&encoded);
+        }
+        rest = &rest[url_end..];
+    }
+    out
+}
+
 // ---------------------------------------------------------------------------
 // srcset / imagesrcset
 // ---------------------------------------------------------------------------
@@ -149,26 +531,143 @@ fn rewrite_srcset_attr(
 
 /// Parse and rewrite a `srcset` value.  Format:
 ///   url1 1x, url2 2x, url3 300w
+/// Each candidate is emitted as `<url>[ <descriptor>]` with single spaces;
+/// empty candidates are dropped.
 fn rewrite_srcset(proxy: &str, base: &str, srcset: &str) -> String {
     srcset
         .split(',')
-        .map(|entry| {
-            let parts: Vec<&str> = entry.trim().splitn(2, char::is_whitespace).collect();
-            match parts.as_slice() {
-                [url, descriptor] => {
-                    let encoded = encode_url_with_base(proxy, base, url)
-                        .unwrap_or_else(|| url.to_string());
-                    format!("{} {}", encoded, descriptor)
-                }
-                [url] => encode_url_with_base(proxy, base, url)
-                    .unwrap_or_else(|| url.to_string()),
-                _ => entry.to_string(),
+        .filter_map(|entry| {
+            let mut parts = entry.split_ascii_whitespace();
+            let url = parts.next()?;
+            let mut candidate = encode_url_with_base(proxy, base, url)
+                .unwrap_or_else(|| url.to_string());
+            for descriptor in parts {
+                candidate.push(' ');
+                candidate.push_str(descriptor);
             }
+            Some(candidate)
         })
         .collect::<Vec<_>>()
         .join(", ")
 }
 
+// ---------------------------------------------------------------------------
+// <object><param name="movie" value="…"></object>
+// ---------------------------------------------------------------------------
+
+/// `<param>` names whose `value` is the embedded resource URL.
+const URL_PARAM_NAMES: &[&str] = &["movie", "src", "url", "source", "file"];
+
+fn rewrite_param_value(attrs: &mut kuchikiki::Attributes, proxy: &str, base: &str) {
+    let is_url_param = attrs
+        .get("name")
+        .map(|n| URL_PARAM_NAMES.iter().any(|p| n.trim().eq_ignore_ascii_case(p)))
+        .unwrap_or(false);
+    if !is_url_param {
+        return;
+    }
+    if let Some(val) = attrs.get("value").map(|s| s.to_string()) {
+        if let Some(encoded) = encode_url_with_base(proxy, base, &val) {
+            attrs.set("value", encoded);
+        }
+    }
+}
+
+// ---------------------------------------------------------------------------
+// <link rel="preload" | "modulepreload">
+// ---------------------------------------------------------------------------
+
+/// Preloaded resources are fetched through the proxy and may be rewritten
+/// on the way, so an upstream `integrity` hash would no longer match and the
+/// browser would discard the preload (and, for modules, fail the graph).
+fn strip_preload_integrity(attrs: &mut kuchikiki::Attributes) {
+    let is_preload = attrs
+        .get("rel")
+        .map(|rel| {
+            rel.split_ascii_whitespace().any(|r| {
+                r.eq_ignore_ascii_case("preload") || r.eq_ignore_ascii_case("modulepreload")
+            })
+        })
+        .unwrap_or(false);
+    if is_preload {
+        attrs.remove_ordered("integrity");
+    }
+}
+
+/// `<script type="module">`.  Its `integrity` is stripped for the same reason
+/// as a preload's; `crossorigin` is kept, since it only controls credentials
+/// and error reporting and the proxied fetch is same-origin anyway.
+fn is_module_script(attrs: &kuchikiki::Attributes) -> bool {
+    attrs
+        .get("type")
+        .is_some_and(|t| t.trim().eq_ignore_ascii_case("module"))
+}
+
+// ---------------------------------------------------------------------------
+// Charset  (<meta charset>, <meta http-equiv="content-type">)
+// ---------------------------------------------------------------------------
+
+/// How many leading bytes are searched for a `<meta charset>`, as in the
+/// HTML encoding-sniffing algorithm.
+const CHARSET_PRESCAN_LEN: usize = 1024;
+
+fn detect_charset(bytes: &[u8], hint: Option<&str>) -> &'static Encoding {
+    if let Some((encoding, _)) = Encoding::for_bom(bytes) {
+        return encoding;
+    }
+    if let Some(encoding) = hint.and_then(|h| Encoding::for_label(h.trim().as_bytes())) {
+        return encoding;
+    }
+    if let Some(encoding) = prescan_meta_charset(&bytes[..bytes.len().min(CHARSET_PRESCAN_LEN)]) {
+        // A document that can declare its charset in ASCII is not UTF-16.
+        if encoding == UTF_16BE || encoding == UTF_16LE {
+            return UTF_8;
+        }
+        return encoding;
+    }
+    if std::str::from_utf8(bytes).is_ok() {
+        UTF_8
+    } else {
+        WINDOWS_1252
+    }
+}
+
+/// Find `charset=` inside a `<meta …>` tag, covering both
+/// `<meta charset="…">` and `<meta http-equiv content="…; charset=…">`.
+fn prescan_meta_charset(bytes: &[u8]) -> Option<&'static Encoding> {
+    let text = String::from_utf8_lossy(bytes).to_ascii_lowercase();
+    let mut rest = text.as_str();
+    while let Some(start) = rest.find("<meta") {
+        let tag = &rest[start..];
+        let tag = &tag[..tag.find('>').unwrap_or(tag.len())];
+        if let Some(pos) = tag.find("charset=") {
+            let value = tag[pos + 8..].trim_start_matches(['"', '\'', ' ']);
+            let end = value
+                .find(|c: char| matches!(c, '"' | '\'' | ';' | '/') || c.is_ascii_whitespace())
+                .unwrap_or(value.len());
+            if let Some(encoding) = Encoding::for_label(&value.as_bytes()[..end]) {
+                return Some(encoding);
+            }
+        }
+        rest = &rest[start + 5..];
+    }
+    None
+}
+
+/// The rewritten document is always emitted as UTF-8, so any declared
+/// charset must say so.
+fn rewrite_meta_charset(attrs: &mut kuchikiki::Attributes) {
+    if attrs.get("charset").is_some() {
+        attrs.set("charset", "utf-8".to_string());
+    }
+    let is_content_type = attrs
+        .get("http-equiv")
+        .is_some_and(|v| v.eq_ignore_ascii_case("content-type"));
+    if is_content_type {
+        attrs.set("content", "text/html; charset=utf-8".to_string());
+    }
+}
+
 // ---------------------------------------------------------------------------
 // <meta http-equiv="refresh" content="0;url=…">
 // ---------------------------------------------------------------------------
@@ -197,6 +696,75 @@ fn rewrite_meta_refresh(
     }
 }
 
+fn rewrite_meta_url_content(attrs: &mut kuchikiki::Attributes, proxy: &str, base: &str, names: &[String]) {
+    let is_url_name = attrs
+        .get("name")
+        .is_some_and(|name| names.iter().any(|n| n.eq_ignore_ascii_case(name.trim())));
+    if !is_url_name {
+        return;
+    }
+    if let Some(content) = attrs.get("content").map(|s| s.to_string()) {
+        if let Some(encoded) = encode_url_with_base(proxy, base, &content) {
+            attrs.set("content", encoded);
+        }
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Social previews  (<meta property="og:image" content="…">)
+// ---------------------------------------------------------------------------
+
+/// Open Graph / Twitter card properties whose `content` is a URL.
+const SOCIAL_META_URL_PROPERTIES: &[&str] = &[
+    "og:image", "og:image:url", "og:image:secure_url", "og:url",
+    "og:video", "og:video:url", "og:video:secure_url", "og:audio",
+    "twitter:image", "twitter:image:src", "twitter:player",
+];
+
+fn rewrite_social_meta(attrs: &mut kuchikiki::Attributes, proxy: &str, base: &str) {
+    // Open Graph uses `property`, Twitter cards use `name`.
+    let key = match attrs.get("property").or_else(|| attrs.get("name")) {
+        Some(k) => k.to_ascii_lowercase(),
+        None => return,
+    };
+    if !SOCIAL_META_URL_PROPERTIES.contains(&key.as_str()) {
+        return;
+    }
+    if let Some(content) = attrs.get("content").map(|s| s.to_string()) {
+        if let Some(encoded) = encode_url_with_base(proxy, base, &content) {
+            attrs.set("content", encoded);
+        }
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Referrer policy  (<meta name="referrer">, referrerpolicy="…")
+// ---------------------------------------------------------------------------
+
+/// Elements that accept a `referrerpolicy` attribute.
+const REFERRER_POLICY_TAGS: &[&str] = &["a", "area", "img", "iframe", "link", "script"];
+
+fn apply_referrer_policy(tag: &str, attrs: &mut kuchikiki::Attributes, policy: &str) {
+    if tag == "meta" {
+        let is_referrer = attrs
+            .get("name")
+            .map(|v| v.eq_ignore_ascii_case("referrer"))
+            .unwrap_or(false);
+        if is_referrer {
+            attrs.set("content", policy.to_string());
+        }
+    } else if REFERRER_POLICY_TAGS.contains(&tag) {
+        attrs.set("referrerpolicy", policy.to_string());
+    }
+}
+
+/// Number of proxied URLs across an element's attribute values.  The walker
+/// diffs this before and after the attribute passes rather than threading a
+/// counter through each of them.
+fn count_proxied_attrs(attrs: &kuchikiki::Attributes, proxy: &str) -> usize {
+    attrs.map.values().map(|a| count_proxied(proxy, &a.value)).sum()
+}
+
 // ---------------------------------------------------------------------------
 // Inline event handlers  (onclick, onerror, onload, …)
 // ---------------------------------------------------------------------------
@@ -220,7 +788,7 @@ const EVENT_ATTRS: &[&str] = &[
 
 fn rewrite_event_handlers(
     attrs: &mut kuchikiki::Attributes,
-    proxy: &str,
+    _proxy: &str,
     _base: &str,
 ) {
     for &attr in EVENT_ATTRS {
@@ -263,7 +831,7 @@ fn rewrite_svg_attrs(
     }
 
     for &attr in SVG_URL_ATTRS {
-        if let Some(val) = attrs.get(attr).map(|s| s.to_string()) {
+        if let Some(val) = attrs.get_qualified(attr).map(|s| s.to_string()) {
             // url(#local) references should be left alone.
             if val.starts_with("url(#") || val.starts_with('#') {
                 continue;
@@ -289,11 +857,30 @@ fn rewrite_svg_attrs(
     }
 }
 
+// ---------------------------------------------------------------------------
+// MathML
+// ---------------------------------------------------------------------------
+
+/// MathML link attributes not already covered by [`URL_ATTRS`]: `href` and
+/// `src` (`<mglyph>`) are, but MathML 2 links use the XLink namespace.
+const MATHML_URL_ATTRS: &[&str] = &["xlink:href"];
+
+fn rewrite_mathml_attrs(attrs: &mut kuchikiki::Attributes, proxy: &str, base: &str) {
+    for &attr in MATHML_URL_ATTRS {
+        if let Some(val) = attrs.get_qualified(attr).map(|s| s.to_string()) {
+            if let Some(encoded) = encode_url_with_base(proxy, base, &val) {
+                attrs.set(attr, encoded);
+            }
+        }
+    }
+}
+
 // ---------------------------------------------------------------------------
 // <style> element body
 // ---------------------------------------------------------------------------
 
-fn rewrite_inline_style_element(node: &NodeRef, proxy: &str, base: &str) {
+/// Returns the number of URLs rewritten.
+fn rewrite_inline_style_element(node: &NodeRef, proxy: &str, base: &str) -> usize {
     let mut text_content = String::new();
     for child in node.children() {
         if let NodeData::Text(ref t) = *child.data() {
@@ -301,21 +888,22 @@ fn rewrite_inline_style_element(node: &NodeRef, proxy: &str, base: &str) {
         }
     }
     if text_content.is_empty() {
-        return;
+        return 0;
     }
-    let rewritten = rewrite_css_string(proxy, base, &text_content);
+    let rewritten = rewrite_css_counted(proxy, base, &text_content);
     // Replace all text children with the rewritten content.
     for child in node.children() {
         child.detach();
     }
-    node.append(NodeRef::new_text(&rewritten));
+    node.append(NodeRef::new_text(&rewritten.css));
+    rewritten.urls_rewritten
 }
 
 // ---------------------------------------------------------------------------
 // <script> inline: wrap dangerous sinks
 // ---------------------------------------------------------------------------
 
-fn rewrite_inline_script(node: &NodeRef, proxy: &str, _base: &str) {
+fn rewrite_inline_script(node: &NodeRef, _proxy: &str, _base: &str, preserve_lines: bool) {
     let mut text_content = String::new();
     for child in node.children() {
         if let NodeData::Text(ref t) = *child.data() {
@@ -334,8 +922,14 @@ fn rewrite_inline_script(node: &NodeRef, proxy: &str, _base: &str) {
     // `innerHTML`, etc. happens in js.rs when the server rewrites
     // standalone JS resources.  For inline scripts we inject a scope
     // wrapper and rely on the client runtime.
+    //
+    // With `preserve_lines` the prefix shares the body's first line, so
+    // stack traces and source maps keep their line numbers.  The newline
+    // before the suffix stays: it closes a trailing `//` comment.
+    let open = if preserve_lines { "" } else { "\n" };
     let wrapped = format!(
-        "(function(__internex_proxy){{\n{}\n}})(window.__internex);",
+        "(function(__internex_proxy){{{}{}\n}})(window.__internex);",
+        open,
         text_content,
     );
 
@@ -345,16 +939,229 @@ fn rewrite_inline_script(node: &NodeRef, proxy: &str, _base: &str) {
     node.append(NodeRef::new_text(&wrapped));
 }
 
+/// Inline module scripts cannot be wrapped in a function (`import` is only
+/// valid at module top level), so run them through the JS rewriter instead,
+/// which also rewrites their import specifiers.
+fn rewrite_inline_module(node: &NodeRef, proxy: &str, base: &str, js_opts: &JsOptions) {
+    let mut text_content = String::new();
+    for child in node.children() {
+        if let NodeData::Text(ref t) = *child.data() {
+            text_content.push_str(&t.borrow());
+        }
+    }
+    if text_content.is_empty() {
+        return;
+    }
+    let rewritten = rewrite_js_with_options(proxy, base, &text_content, js_opts);
+    for child in node.children() {
+        child.detach();
+    }
+    node.append(NodeRef::new_text(&rewritten));
+}
+
+// ---------------------------------------------------------------------------
+// Discovered sub-resources
+// ---------------------------------------------------------------------------
+
+/// What a discovered sub-resource is loaded as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResourceKind {
+    Script,
+    Style,
+    Image,
+    Font,
+    Media,
+    Frame,
+    Other,
+}
+
+/// A sub-resource URL found while rewriting, before it was proxied.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Resource {
+    pub url: String,
+    pub kind: ResourceKind,
+}
+
+/// Record the sub-resources an element loads.  Navigation targets
+/// (`<a href>`, `<form action>`, canonical links) are not resources and are
+/// skipped.
+fn collect_resources(
+    tag: &str,
+    attrs: &kuchikiki::Attributes,
+    base: &str,
+    found: &mut Vec<Resource>,
+) {
+    let mut add = |raw: &str, kind: ResourceKind| {
+        if let Some(url) = resolve_http(base, raw) {
+            if !found.iter().any(|r| r.url == url) {
+                found.push(Resource { url, kind });
+            }
+        }
+    };
+
+    match tag {
+        "script" => {
+            if let Some(src) = attrs.get("src") {
+                add(src, ResourceKind::Script);
+            }
+        }
+        "link" => {
+            let rel = attrs.get("rel").unwrap_or("").to_ascii_lowercase();
+            let kind = if rel.split_ascii_whitespace().any(|r| r == "stylesheet") {
+                Some(ResourceKind::Style)
+            } else if rel.split_ascii_whitespace().any(|r| r == "modulepreload") {
+                Some(ResourceKind::Script)
+            } else if rel.split_ascii_whitespace().any(|r| r == "preload") {
+                Some(preload_kind(attrs.get("as").unwrap_or("")))
+            } else if rel.contains("icon") {
+                Some(ResourceKind::Image)
+            } else {
+                None
+            };
+            if let (Some(kind), Some(href)) = (kind, attrs.get("href")) {
+                add(href, kind);
+            }
+        }
+        "input" => {
+            let is_image = attrs.get("type").is_some_and(|t| t.eq_ignore_ascii_case("image"));
+            if let (true, Some(src)) = (is_image, attrs.get("src")) {
+                add(src, ResourceKind::Image);
+            }
+        }
+        "img" | "source" => {
+            let kind = match (tag, attrs.get("srcset")) {
+                // <source> inside <video> / <audio> carries `src`, inside
+                // <picture> it carries `srcset`.
+                ("source", None) => ResourceKind::Media,
+                _ => ResourceKind::Image,
+            };
+            if let Some(src) = attrs.get("src") {
+                add(src, kind);
+            }
+            for url in attrs.get("srcset").map(srcset_urls).unwrap_or_default() {
+                add(url, ResourceKind::Image);
+            }
+        }
+        "video" | "audio" | "track" => {
+            if let Some(src) = attrs.get("src") {
+                add(src, ResourceKind::Media);
+            }
+            if let Some(poster) = attrs.get("poster") {
+                add(poster, ResourceKind::Image);
+            }
+        }
+        "iframe" | "frame" => {
+            if let Some(src) = attrs.get("src") {
+                add(src, ResourceKind::Frame);
+            }
+        }
+        "embed" | "object" => {
+            if let Some(src) = attrs.get("src").or_else(|| attrs.get("data")) {
+                add(src, ResourceKind::Other);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Map a `<link rel="preload" as="…">` destination to a resource kind.
+fn preload_kind(as_attr: &str) -> ResourceKind {
+    match as_attr.to_ascii_lowercase().as_str() {
+        "script" | "worker" => ResourceKind::Script,
+        "style" => ResourceKind::Style,
+        "image" => ResourceKind::Image,
+        "font" => ResourceKind::Font,
+        "audio" | "video" | "track" => ResourceKind::Media,
+        "document" => ResourceKind::Frame,
+        _ => ResourceKind::Other,
+    }
+}
+
+/// The URL part of each `srcset` candidate.
+fn srcset_urls(srcset: &str) -> Vec<&str> {
+    srcset
+        .split(',')
+        .filter_map(|candidate| candidate.split_ascii_whitespace().next())
+        .collect()
+}
+
+/// Resolve `raw` against `base`, keeping only http(s) results.
+fn resolve_http(base: &str, raw: &str) -> Option<String> {
+    let url = Url::parse(base).ok()?.join(raw.trim()).ok()?;
+    match url.scheme() {
+        "http" | "https" => Some(url.to_string()),
+        _ => None,
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Conditional comments  (<!--[if IE]> … <![endif]-->)
+// ---------------------------------------------------------------------------
+
+/// Rewrite the markup inside a conditional comment.  Returns `None` when
+/// `text` is not a conditional comment.
+fn rewrite_conditional_comment(
+    text: &str,
+    proxy: &str,
+    base: &str,
+    opts: &HtmlOptions,
+    js_opts: &JsOptions,
+    found: &mut Vec<Resource>,
+    count: &mut usize,
+) -> Option<String> {
+    if !text.trim_start().starts_with("[if ") {
+        return None;
+    }
+    let open_end = text.find("]>")? + 2;
+    let close_start = text.rfind("<![endif]")?;
+    if close_start < open_end {
+        return None;
+    }
+
+    let ctx = QualName::new(None, ns!(html), local_name!("body"));
+    let frag = parse_fragment(ctx, Vec::new()).one(&text[open_end..close_start]);
+    walk(&frag, proxy, base, opts, js_opts, found, count);
+
+    // The fragment parser wraps the nodes in an <html> element.
+    let root = frag.first_child()?;
+    let inner = serialize_node(&root, TraversalScope::ChildrenOnly(None))?;
+    Some(format!("{}{}{}", &text[..open_end], inner, &text[close_start..]))
+}
+
+fn serialize_node(node: &NodeRef, traversal_scope: TraversalScope) -> Option<String> {
+    let mut buf = Vec::new();
+    serialize(
+        &mut buf,
+        node,
+        SerializeOpts {
+            scripting_enabled: true,
+            traversal_scope,
+            create_missing_parent: false,
+        },
+    )
+    .ok()?;
+    String::from_utf8(buf).ok()
+}
+
 // ---------------------------------------------------------------------------
 // <base href> detection
 // ---------------------------------------------------------------------------
 
-fn find_base_href(doc: &NodeRef) -> Option<String> {
+/// The document base URL: the first `<base>` that has an `href` (a
+/// `<base target>` on its own does not count), resolved against the
+/// document URL so `<base href="/sub/">` works too.
+fn find_base_href(doc: &NodeRef, document_url: &str) -> Option<String> {
     for node in doc.inclusive_descendants() {
         if let NodeData::Element(ref el) = *node.data() {
             if el.name.local.to_string() == "base" {
                 let attrs = el.attributes.borrow();
-                return attrs.get("href").map(|s| s.to_string());
+                if let Some(href) = attrs.get("href") {
+                    return match Url::parse(document_url) {
+                        Ok(doc_url) => doc_url.join(href.trim()).ok().map(|u| u.to_string()),
+                        Err(_) => Some(href.to_string()),
+                    };
+                }
             }
         }
     }
@@ -398,27 +1205,68 @@ fn inject_client_script(doc: &NodeRef, proxy_origin: &str, base_url: &str) {
 // ---------------------------------------------------------------------------
 
 trait AttrsExt {
-    fn get(&self, name: &str) -> Option<&str>;
+    fn get_qualified(&self, name: &str) -> Option<&str>;
     fn set(&mut self, name: &str, value: String);
+    fn remove_ordered(&mut self, name: &str) -> bool;
+}
+
+/// Map a possibly prefixed attribute name (`xlink:href`, `xml:base`) to the
+/// namespaced key html5ever stores it under on foreign (SVG / MathML)
+/// elements.  Unprefixed names live in the null namespace.
+fn qualified_key(name: &str) -> (Option<markup5ever::Prefix>, kuchikiki::ExpandedName) {
+    let (prefix, ns, local) = match name.split_once(':') {
+        Some(("xlink", local)) => (Some(markup5ever::Prefix::from("xlink")), ns!(xlink), local),
+        Some(("xml", local)) => (Some(markup5ever::Prefix::from("xml")), ns!(xml), local),
+        _ => (None, ns!(), name),
+    };
+    (prefix, kuchikiki::ExpandedName::new(ns, markup5ever::LocalName::from(local)))
+}
+
+fn null_ns_key(name: &str) -> kuchikiki::ExpandedName {
+    kuchikiki::ExpandedName::new(ns!(), markup5ever::LocalName::from(name))
 }
 
 impl AttrsExt for kuchikiki::Attributes {
-    fn get(&self, name: &str) -> Option<&str> {
-        self.map.get(&kuchikiki::ExpandedName::new(ns!(), markup5ever::LocalName::from(name)))
+    /// Namespace-aware lookup: `xlink:href` finds the XLink-namespaced
+    /// attribute on SVG elements as well as a literal `xlink:href` attribute
+    /// on HTML elements (where the parser does not adjust it).
+    fn get_qualified(&self, name: &str) -> Option<&str> {
+        let (_, key) = qualified_key(name);
+        self.map
+            .get(&key)
+            .or_else(|| self.map.get(&null_ns_key(name)))
             .map(|a| a.value.as_str())
     }
 
+    /// Set an attribute, inserting it if it is not already present.
+    /// Prefixed names follow the same lookup as [`AttrsExt::get_qualified`].
     fn set(&mut self, name: &str, value: String) {
-        let key = kuchikiki::ExpandedName::new(ns!(), markup5ever::LocalName::from(name));
-        if let Some(attr) = self.map.get_mut(&key) {
-            attr.value = value.into();
+        let (prefix, key) = qualified_key(name);
+        let literal = null_ns_key(name);
+        let key = if !self.map.contains_key(&key) && self.map.contains_key(&literal) {
+            literal
+        } else {
+            key
+        };
+        match self.map.get_mut(&key) {
+            Some(attr) => attr.value = value,
+            None => {
+                self.map.insert(key, kuchikiki::Attribute { prefix, value });
+            }
         }
     }
+
+    /// Remove an unprefixed attribute, keeping the others in source order.
+    /// `Attributes::remove` swaps the last attribute into the gap.
+    fn remove_ordered(&mut self, name: &str) -> bool {
+        self.map.shift_remove(&null_ns_key(name)).is_some()
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::url::{with_marker_secret, with_url_settings, ProxyUrlStyle, UrlSettings};
 
     const PROXY: &str = "http://localhost:8080";
     const BASE: &str = "https://example.com/page";
@@ -430,6 +1278,35 @@ mod tests {
         assert!(result.contains("/proxy?url="));
     }
 
+    #[test]
+    fn rewrites_mathml_mglyph_src() {
+        let html = r#"<html><head></head><body><math><mglyph src="g.png"></mglyph></math></body></html>"#;
+        let result = rewrite_html(PROXY, BASE, html);
+        assert!(result.contains(r#"<mglyph src="http://localhost:8080/proxy?url=https://example.com/g.png">"#));
+    }
+
+    #[test]
+    fn rewrites_mathml_links() {
+        let html = r#"<html><head></head><body><math><mi href="/x">x</mi><mo xlink:href="/y">+</mo></math></body></html>"#;
+        let result = rewrite_html(PROXY, BASE, html);
+        assert!(result.contains(r#"<mi href="http://localhost:8080/proxy?url=https://example.com/x">"#));
+        assert!(result.contains(r#"<mo xlink:href="http://localhost:8080/proxy?url=https://example.com/y">"#));
+    }
+
+    #[test]
+    fn leaves_canonical_link_alone() {
+        let html = r#"<html><head><link rel="canonical" href="https://example.com/page"></head><body></body></html>"#;
+        let result = rewrite_html(PROXY, BASE, html);
+        assert!(result.contains(r#"<link rel="canonical" href="https://example.com/page">"#));
+    }
+
+    #[test]
+    fn proxies_alternate_link() {
+        let html = r#"<html><head><link rel="alternate" hreflang="de" href="/de/page"></head><body></body></html>"#;
+        let result = rewrite_html(PROXY, BASE, html);
+        assert!(result.contains(r#"href="http://localhost:8080/proxy?url=https://example.com/de/page""#));
+    }
+
     #[test]
     fn rewrites_img_src() {
         let html = r#"<html><head></head><body><img src="https://example.com/img.png"></body></html>"#;
@@ -437,6 +1314,236 @@ mod tests {
         assert!(result.contains("/proxy?url="));
     }
 
+    #[test]
+    fn reports_discovered_resources() {
+        let html = r#"<html><head><script src="/app.js"></script><link rel="canonical" href="/"></head><body><a href="/next">n</a><img src="img/logo.png"></body></html>"#;
+        let result = rewrite_html_with_resources(PROXY, BASE, html, &HtmlOptions::default());
+        assert!(result.html.contains("/proxy?url=https://example.com/app.js"));
+        assert_eq!(
+            result.resources,
+            vec![
+                Resource { url: "https://example.com/app.js".into(), kind: ResourceKind::Script },
+                Resource { url: "https://example.com/img/logo.png".into(), kind: ResourceKind::Image },
+            ]
+        );
+    }
+
+    #[test]
+    fn rewrites_each_ping_url() {
+        let html = r#"<html><head></head><body><a href="/" ping="https://a/x https://b/y">x</a></body></html>"#;
+        let result = rewrite_html(PROXY, BASE, html);
+        assert!(result.contains(
+            r#"ping="http://localhost:8080/proxy?url=https://a/x http://localhost:8080/proxy?url=https://b/y""#
+        ));
+    }
+
+    #[test]
+    fn rewrites_each_archive_url() {
+        let html = r#"<html><head></head><body><object archive="lib/a.jar  lib/b.jar"></object></body></html>"#;
+        let result = rewrite_html(PROXY, BASE, html);
+        assert!(result.contains(
+            r#"archive="http://localhost:8080/proxy?url=https://example.com/lib/a.jar  http://localhost:8080/proxy?url=https://example.com/lib/b.jar""#
+        ));
+    }
+
+    #[test]
+    fn rewrites_comma_separated_archive() {
+        let html = r#"<html><head></head><body><object archive="a.jar,b.jar"></object></body></html>"#;
+        let result = rewrite_html(PROXY, BASE, html);
+        assert!(result.contains(".com/a.jar,http://localhost:8080/proxy?url="));
+    }
+
+    #[test]
+    fn rewrites_template_content() {
+        let html = r#"<html><head></head><body><template><img src="/a.png"></template></body></html>"#;
+        let result = rewrite_html(PROXY, BASE, html);
+        assert!(result.contains(
+            r#"<template><img src="http://localhost:8080/proxy?url=https://example.com/a.png"></template>"#
+        ));
+    }
+
+    #[test]
+    fn rewrites_srcset_inside_templates() {
+        let html = r#"<html><head></head><body><template><img srcset="a.jpg 1x, b.jpg 2x"><template><link rel="preload" as="image" imagesrcset="c.jpg 1x"></template></template></body></html>"#;
+        let result = rewrite_html(PROXY, BASE, html);
+        assert!(result.contains(
+            r#"<img srcset="http://localhost:8080/proxy?url=https://example.com/a.jpg 1x, http://localhost:8080/proxy?url=https://example.com/b.jpg 2x">"#
+        ));
+        assert!(result.contains(r#"imagesrcset="http://localhost:8080/proxy?url=https://example.com/c.jpg 1x""#));
+    }
+
+    #[test]
+    fn rewrites_svg_style_element() {
+        let html = r#"<html><head></head><body><svg><style>image{fill:url(ext.svg#p)}</style></svg></body></html>"#;
+        let result = rewrite_html(PROXY, BASE, html);
+        assert!(result.contains(
+            r#"<style>image{fill:url(http://localhost:8080/proxy?url=https://example.com/ext.svg%23p)}</style>"#
+        ));
+    }
+
+    #[test]
+    fn rewrites_foreign_object_html() {
+        let html = r#"<html><head></head><body><svg><foreignObject><div><img src="/x.png"></div></foreignObject></svg></body></html>"#;
+        let result = rewrite_html(PROXY, BASE, html);
+        assert!(result.contains(r#"<img src="http://localhost:8080/proxy?url=https://example.com/x.png">"#));
+    }
+
+    #[test]
+    fn normalizes_srcset_whitespace() {
+        let result = rewrite_srcset(PROXY, BASE, "  a.png   2x ,\n b.png  ,c.png\t1.5x  ");
+        assert_eq!(
+            result,
+            "http://localhost:8080/proxy?url=https://example.com/a.png 2x, \
+http://localhost:8080/proxy?url=https://example.com/b.png, \
+http://localhost:8080/proxy?url=https://example.com/c.png 1.5x"
+        );
+    }
+
+    #[test]
+    fn rewrites_preload_imagesrcset() {
+        let html = r#"<html><head><link rel=preload as=image imagesrcset="a.jpg 1x"></head><body></body></html>"#;
+        let result = rewrite_html(PROXY, BASE, html);
+        assert!(result.contains(
+            r#"imagesrcset="http://localhost:8080/proxy?url=https://example.com/a.jpg 1x""#
+        ));
+    }
+
+    #[test]
+    fn strips_modulepreload_integrity() {
+        let html = r#"<html><head><link rel="modulepreload" href="/app.js" integrity="sha384-abc"></head><body></body></html>"#;
+        let result = rewrite_html(PROXY, BASE, html);
+        assert!(result.contains(r#"href="http://localhost:8080/proxy?url=https://example.com/app.js""#));
+        assert!(!result.contains("integrity"));
+    }
+
+    #[test]
+    fn skips_marked_element() {
+        let html = r#"<html><head></head><body><a data-internex-skip href="https://x/"><img src="https://x/i.png"></a></body></html>"#;
+        let result = rewrite_html(PROXY, BASE, html);
+        assert!(result.contains(r#"<a href="https://x/"><img src="https://x/i.png"></a>"#));
+        assert!(!result.contains("data-internex-skip"));
+    }
+
+    #[test]
+    fn rewrites_conditional_comment_when_enabled() {
+        let html = r#"<html><head><!--[if IE]><link rel="stylesheet" href="/ie.css"><![endif]--></head><body></body></html>"#;
+        let opts = HtmlOptions {
+            rewrite_conditional_comments: true,
+            ..HtmlOptions::default()
+        };
+        let result = rewrite_html_with_options(PROXY, BASE, html, &opts);
+        assert!(result.contains(
+            r#"<!--[if IE]><link rel="stylesheet" href="http://localhost:8080/proxy?url=https://example.com/ie.css"><![endif]-->"#
+        ));
+    }
+
+    #[test]
+    fn leaves_conditional_comment_by_default() {
+        let html = r#"<html><head><!--[if IE]><link href="/ie.css"><![endif]--></head><body></body></html>"#;
+        let result = rewrite_html(PROXY, BASE, html);
+        assert!(result.contains(r#"<!--[if IE]><link href="/ie.css"><![endif]-->"#));
+    }
+
+    #[test]
+    fn rewrites_data_src() {
+        let html = r#"<html><head></head><body><img class="lazy" data-src="/big.jpg"></body></html>"#;
+        let result = rewrite_html(PROXY, BASE, html);
+        assert!(result.contains(r#"data-src="http://localhost:8080/proxy?url=https://example.com/big.jpg""#));
+    }
+
+    #[test]
+    fn rewrites_data_srcset() {
+        let html = r#"<html><head></head><body><img data-srcset="/s.jpg 1x, /l.jpg 2x"></body></html>"#;
+        let result = rewrite_html(PROXY, BASE, html);
+        assert!(result.contains(
+            r#"data-srcset="http://localhost:8080/proxy?url=https://example.com/s.jpg 1x, http://localhost:8080/proxy?url=https://example.com/l.jpg 2x""#
+        ));
+    }
+
+    #[test]
+    fn data_url_attrs_are_configurable() {
+        let html = r#"<html><head></head><body><div data-src="/a.jpg" data-lazy="/b.jpg"></div></body></html>"#;
+        let opts = HtmlOptions {
+            data_url_attrs: vec!["data-lazy".to_string()],
+            ..HtmlOptions::default()
+        };
+        let result = rewrite_html_with_options(PROXY, BASE, html, &opts);
+        assert!(result.contains(r#"data-src="/a.jpg""#));
+        assert!(result.contains(r#"data-lazy="http://localhost:8080/proxy?url="#));
+    }
+
+    #[test]
+    fn rewrites_param_movie_value() {
+        let html = r#"<html><head></head><body><object><param name="movie" value="https://x/y.swf"><param name="quality" value="high"></object></body></html>"#;
+        let result = rewrite_html(PROXY, BASE, html);
+        assert!(result.contains(r#"value="http://localhost:8080/proxy?url=https://x/y.swf""#));
+        assert!(result.contains(r#"value="high""#));
+    }
+
+    #[test]
+    fn rewrites_namespaced_xlink_href() {
+        let html = r#"<html><head></head><body><svg><use xlink:href="x.svg#i"/></svg></body></html>"#;
+        let result = rewrite_html(PROXY, BASE, html);
+        assert!(result.contains(
+            r#"<use xlink:href="http://localhost:8080/proxy?url=https://example.com/x.svg%23i">"#
+        ));
+    }
+
+    #[test]
+    fn decodes_windows_1252_bytes() {
+        let bytes = b"<html><head><meta charset=\"windows-1252\"></head><body><p>caf\xe9 \x93hi\x94</p><img src=\"/a.png\"></body></html>";
+        let result = rewrite_html_bytes(PROXY, BASE, bytes, None);
+        assert!(result.contains("<meta charset=\"utf-8\">"));
+        assert!(result.contains("<p>café \u{201c}hi\u{201d}</p>"));
+        assert!(result.contains("/proxy?url=https://example.com/a.png"));
+    }
+
+    #[test]
+    fn charset_hint_and_fallback() {
+        assert_eq!(detect_charset(b"caf\xe9", Some("iso-8859-1")), WINDOWS_1252);
+        assert_eq!(detect_charset("café".as_bytes(), None), UTF_8);
+        assert_eq!(detect_charset(b"caf\xe9", None), WINDOWS_1252);
+        assert_eq!(detect_charset(b"\xef\xbb\xbfhi", Some("windows-1252")), UTF_8);
+    }
+
+    #[test]
+    fn rewrites_tile_image_meta() {
+        let html = r##"<html><head><meta name="msapplication-TileImage" content="/tile.png"><meta name="msapplication-config" content="https://example.com/browserconfig.xml"><meta name="theme-color" content="#fff"></head><body></body></html>"##;
+        let result = rewrite_html(PROXY, BASE, html);
+        assert!(result.contains(r#"content="http://localhost:8080/proxy?url=https://example.com/tile.png""#));
+        assert!(result.contains(r#"content="http://localhost:8080/proxy?url=https://example.com/browserconfig.xml""#));
+        assert!(result.contains(r##"content="#fff""##));
+
+        let opts = HtmlOptions {
+            meta_url_names: vec!["x-icon".to_string()],
+            ..HtmlOptions::default()
+        };
+        let html = r#"<html><head><meta name="X-Icon" content="/i.png"><meta name="msapplication-TileImage" content="/tile.png"></head><body></body></html>"#;
+        let result = rewrite_html_with_options(PROXY, BASE, html, &opts);
+        assert!(result.contains(r#"content="http://localhost:8080/proxy?url=https://example.com/i.png""#));
+        assert!(result.contains(r#"content="/tile.png""#));
+    }
+
+    #[test]
+    fn social_meta_left_alone_by_default() {
+        let html = r#"<html><head><meta property="og:image" content="https://example.com/og.png"></head><body></body></html>"#;
+        let result = rewrite_html(PROXY, BASE, html);
+        assert!(result.contains(r#"content="https://example.com/og.png""#));
+    }
+
+    #[test]
+    fn rewrites_social_meta_when_enabled() {
+        let html = r#"<html><head><meta property="og:image" content="/og.png"><meta name="twitter:image" content="https://cdn.example.com/tw.png"><meta property="og:title" content="/not-a-url"></head><body></body></html>"#;
+        let opts = HtmlOptions {
+            rewrite_social_meta: true,
+            ..HtmlOptions::default()
+        };
+        let result = rewrite_html_with_options(PROXY, BASE, html, &opts);
+        assert!(result.contains(r#"content="http://localhost:8080/proxy?url=https://example.com/og.png""#));
+        assert!(result.contains(r#"content="http://localhost:8080/proxy?url=https://cdn.example.com/tw.png""#));
+        assert!(result.contains(r#"content="/not-a-url""#));
+    }
+
     #[test]
     fn rewrites_meta_refresh() {
         let html = r#"<html><head><meta http-equiv="refresh" content="5;url=https://example.com/new"></head><body></body></html>"#;
@@ -444,10 +1551,248 @@ mod tests {
         assert!(result.contains("/proxy?url="));
     }
 
+    #[test]
+    fn normalizes_meta_referrer() {
+        let html = r#"<html><head><meta name="referrer" content="unsafe-url"></head><body><img src="/a.png"></body></html>"#;
+        let opts = HtmlOptions {
+            referrer_policy: Some("no-referrer".to_string()),
+            ..HtmlOptions::default()
+        };
+        let result = rewrite_html_with_options(PROXY, BASE, html, &opts);
+        assert!(result.contains(r#"content="no-referrer""#));
+        assert!(result.contains(r#"referrerpolicy="no-referrer""#));
+    }
+
+    #[test]
+    fn preserves_referrer_policy_by_default() {
+        let html = r#"<html><head><meta name="referrer" content="unsafe-url"></head><body><img src="/a.png"></body></html>"#;
+        let result = rewrite_html(PROXY, BASE, html);
+        assert!(result.contains(r#"content="unsafe-url""#));
+        assert!(!result.contains("referrerpolicy"));
+    }
+
+    #[test]
+    fn pathological_input_never_panics() {
+        let inputs = [
+            "",
+            "\u{0}",
+            "<<<>>>&&&",
+            "<html><head><title>\u{FFFD}</title><html><body><body></html></html>",
+            "<table><tr><td><svg><foreignObject><math><mi><template><style>",
+        ];
+        for html in inputs {
+            let result = rewrite_html(PROXY, BASE, html);
+            assert!(result == html || result.contains("internex.runtime.js"));
+        }
+    }
+
+    #[test]
+    fn base_href_is_proxied_and_used_for_resolution() {
+        let html = r#"<html><head><base href="https://cdn/"></head><body><img src="a.png"></body></html>"#;
+        let result = rewrite_html(PROXY, BASE, html);
+        assert!(result.contains(r#"<base href="http://localhost:8080/proxy?url=https://cdn/">"#));
+        assert!(result.contains(r#"src="http://localhost:8080/proxy?url=https://cdn/a.png""#));
+        assert!(result.contains(r#"window.__internex_base = "https://cdn/";"#));
+        // The runtime is loaded from an absolute URL ahead of <base>.
+        let runtime = result.find(r#"<script src="http://localhost:8080/internex.runtime.js">"#).unwrap();
+        assert!(runtime < result.find("<base").unwrap());
+    }
+
+    #[test]
+    fn base_href_keeps_original_for_server_side_resolution() {
+        let html = r#"<html><head><base href="https://cdn.example.org/app/"></head><body><form action="submit"></form><a href="../up">u</a></body></html>"#;
+        let result = rewrite_html(PROXY, BASE, html);
+        assert!(result.contains(r#"<base href="http://localhost:8080/proxy?url=https://cdn.example.org/app/">"#));
+        assert!(result.contains(r#"action="http://localhost:8080/proxy?url=https://cdn.example.org/app/submit""#));
+        assert!(result.contains(r#"href="http://localhost:8080/proxy?url=https://cdn.example.org/up""#));
+    }
+
+    #[test]
+    fn base_target_without_href_is_skipped() {
+        let html = r#"<html><head><base target="_blank"><base href="https://cdn/"></head><body><a href="x">x</a></body></html>"#;
+        let result = rewrite_html(PROXY, BASE, html);
+        assert!(result.contains(r#"<base target="_blank">"#));
+        assert!(result.contains(r#"href="http://localhost:8080/proxy?url=https://cdn/x""#));
+    }
+
+    #[test]
+    fn relative_base_href_resolves_against_document() {
+        let html = r#"<html><head><base href="/sub/"></head><body><img src="a.png"></body></html>"#;
+        let result = rewrite_html(PROXY, BASE, html);
+        assert!(result.contains(r#"<base href="http://localhost:8080/proxy?url=https://example.com/sub/">"#));
+        assert!(result.contains(r#"src="http://localhost:8080/proxy?url=https://example.com/sub/a.png""#));
+    }
+
+    #[test]
+    fn output_is_deterministic_and_keeps_attribute_order() {
+        let html = r#"<html><head><script type="module" src="/m.js" integrity="sha384-x" crossorigin="anonymous" defer></script></head><body><img data-src="/a.png" alt="a" src="/b.png" class="c" loading="lazy"></body></html>"#;
+        let first = rewrite_html(PROXY, BASE, html);
+        let second = rewrite_html(PROXY, BASE, html);
+        assert_eq!(first, second);
+        assert!(first.contains(r#"<script type="module" src="http://localhost:8080/proxy?url=https://example.com/m.js" crossorigin="anonymous" defer="">"#));
+        assert!(first.contains(r#"<img data-src="http://localhost:8080/proxy?url=https://example.com/a.png" alt="a" src="http://localhost:8080/proxy?url=https://example.com/b.png" class="c" loading="lazy">"#));
+    }
+
+    #[test]
+    fn preserves_inline_script_line_numbers_when_asked() {
+        let body = "var a = 1;\nthrow new Error(a); // line 2";
+        let html = format!("<html><head></head><body><script>{}</script></body></html>", body);
+        let opts = HtmlOptions {
+            preserve_script_lines: true,
+            ..HtmlOptions::default()
+        };
+        let result = rewrite_html_with_options(PROXY, BASE, &html, &opts);
+        let start = result.find("<script>(function").unwrap() + "<script>".len();
+        let script = &result[start..start + result[start..].find("</script>").unwrap()];
+        assert_eq!(script, "(function(__internex_proxy){var a = 1;\nthrow new Error(a); // line 2\n})(window.__internex);");
+        let line_of_throw = script.lines().position(|l| l.contains("throw")).unwrap();
+        assert_eq!(line_of_throw, body.lines().position(|l| l.contains("throw")).unwrap());
+
+        let default = rewrite_html(PROXY, BASE, &html);
+        assert!(default.contains("(function(__internex_proxy){\nvar a = 1;"));
+    }
+
+    #[test]
+    fn rewrites_custom_property_urls_in_style_attribute() {
+        let html = r#"<html><body><div style="--bg: url(https://x/y.png); background: var(--bg)"></div></body></html>"#;
+        let result = rewrite_html(PROXY, BASE, html);
+        assert!(result.contains(r#"style="--bg: url(http://localhost:8080/proxy?url=https://x/y.png); background: var(--bg)""#));
+    }
+
+    #[test]
+    fn rewrites_custom_element_url_attributes() {
+        let html = r#"<html><body><amp-img src="/a.jpg" srcset="/a-1x.jpg 1x, /a-2x.jpg 2x" layout="responsive"></amp-img><amp-iframe src="https://embed.example.com/e"></amp-iframe><my-widget href="/w"></my-widget></body></html>"#;
+        let result = rewrite_html(PROXY, BASE, html);
+        assert!(result.contains(r#"<amp-img src="http://localhost:8080/proxy?url=https://example.com/a.jpg" srcset="http://localhost:8080/proxy?url=https://example.com/a-1x.jpg 1x, http://localhost:8080/proxy?url=https://example.com/a-2x.jpg 2x" layout="responsive">"#));
+        assert!(result.contains(r#"<amp-iframe src="http://localhost:8080/proxy?url=https://embed.example.com/e">"#));
+        assert!(result.contains(r#"<my-widget href="http://localhost:8080/proxy?url=https://example.com/w">"#));
+    }
+
     #[test]
     fn injects_runtime_script() {
         let html = "<html><head></head><body></body></html>";
         let result = rewrite_html(PROXY, BASE, html);
         assert!(result.contains("internex.runtime.js"));
     }
+
+    #[test]
+    fn second_pass_is_a_no_op() {
+        with_marker_secret(Some("deployment-secret".to_string()), || {
+            let once = rewrite_html(PROXY, BASE, r#"<html><body><img src="/a.png"></body></html>"#);
+            assert!(once.trim_end().ends_with(&rewritten_marker(PROXY).unwrap()));
+            assert_eq!(rewrite_html(PROXY, BASE, &once), once);
+        });
+    }
+
+    #[test]
+    fn upstream_marker_does_not_skip_rewriting() {
+        with_marker_secret(Some("deployment-secret".to_string()), || {
+            let page = r#"<html><body><script src="https://evil.example/x.js"></script></body></html>"#;
+            let result = rewrite_html(PROXY, BASE, &format!("{}<!--internex:rewritten-->", page));
+            assert!(result.contains("src=\"http://localhost:8080/proxy?url=https://evil.example/x.js\""));
+            assert!(result.contains("internex.runtime.js"));
+
+            // Another proxy's marker does not count either.
+            let other = rewrite_html("http://other-proxy:9090", BASE, page);
+            let result = rewrite_html(PROXY, BASE, &other);
+            assert!(result.contains("src=\"http://localhost:8080/proxy?url=http://other-proxy:9090/"));
+        });
+    }
+
+    const INLINE_PAGE: &str = r#"<html><head><style>a { background: url(/s.png) }</style><script>go()</script></head><body><div style="background: url(/d.png)" onclick="go()"><img src="/i.png"></div></body></html>"#;
+
+    #[test]
+    fn skips_inline_scripts_when_disabled() {
+        let opts = HtmlOptions {
+            rewrite_inline_scripts: false,
+            ..HtmlOptions::default()
+        };
+        let result = rewrite_html_with_options(PROXY, BASE, INLINE_PAGE, &opts);
+        assert!(result.contains("<script>go()</script>"));
+        assert!(result.contains("__internex.scope(this"));
+        assert!(result.contains("proxy?url=https://example.com/s.png"));
+        assert!(result.contains("proxy?url=https://example.com/i.png"));
+    }
+
+    #[test]
+    fn skips_inline_styles_when_disabled() {
+        let opts = HtmlOptions {
+            rewrite_inline_styles: false,
+            ..HtmlOptions::default()
+        };
+        let result = rewrite_html_with_options(PROXY, BASE, INLINE_PAGE, &opts);
+        assert!(result.contains("<style>a { background: url(/s.png) }</style>"));
+        assert!(result.contains(r#"style="background: url(/d.png)""#));
+        assert!(result.contains("(window.__internex)"));
+        assert!(result.contains("proxy?url=https://example.com/i.png"));
+    }
+
+    #[test]
+    fn skips_event_handlers_when_disabled() {
+        let opts = HtmlOptions {
+            rewrite_event_handlers: false,
+            ..HtmlOptions::default()
+        };
+        let result = rewrite_html_with_options(PROXY, BASE, INLINE_PAGE, &opts);
+        assert!(result.contains(r#"onclick="go()""#));
+        assert!(result.contains("proxy?url=https://example.com/d.png"));
+        assert!(result.contains("proxy?url=https://example.com/i.png"));
+    }
+
+    #[test]
+    fn counts_rewritten_urls() {
+        let html = r#"<html><head><style>a { background: url(/a.png) } b { background: url(/b.png) }</style></head><body><img src="/i.png" srcset="/s.png 1x, /l.png 2x"><div style="background: url(/d.png)"></div><a href="data:text/plain,x">x</a></body></html>"#;
+        let result = rewrite_html_with_resources(PROXY, BASE, html, &HtmlOptions::default());
+        assert_eq!(result.urls_rewritten, 6);
+    }
+
+    #[test]
+    fn counts_rewritten_urls_in_every_style() {
+        let html = r#"<html><body><img src="/i.png" srcset="/s.png 1x, /l.png 2x"><a href="/proxy?url=x">x</a></body></html>"#;
+        // Root-relative `/proxy?url=` is only taken as ours, and kept, in
+        // the root-relative style.
+        for (style, rewritten) in [(ProxyUrlStyle::ProtocolRelative, 4), (ProxyUrlStyle::RootRelative, 3)] {
+            let settings = UrlSettings { style, ..UrlSettings::default() };
+            let result = with_url_settings(settings, || rewrite_html_with_resources(PROXY, BASE, html, &HtmlOptions::default()));
+            assert_eq!(result.urls_rewritten, rewritten, "{:?}", style);
+        }
+    }
+
+    #[test]
+    fn stamps_style_nonce_on_rewritten_style() {
+        let html = r#"<html><head><style>a { background: url(/a.png) }</style><style>b { color: red }</style><style nonce="own">c { background: url(/c.png) }</style></head><body></body></html>"#;
+        let opts = HtmlOptions {
+            style_nonce: crate::csp::extract_style_nonce("style-src 'nonce-xyz' 'sha256-abc'"),
+            ..HtmlOptions::default()
+        };
+        let result = rewrite_html_with_options(PROXY, BASE, html, &opts);
+        assert!(result.contains(r#"<style nonce="xyz">a { background: url("#));
+        assert!(result.contains("<style>b { color: red }</style>"));
+        assert!(result.contains(r#"<style nonce="own">c"#));
+    }
+
+    #[test]
+    fn rewrites_inline_module_script() {
+        let html = r#"<html><head><script type="module">import { x } from "./lib.js";
+x();</script><script type="module" src="/app.js" integrity="sha384-abc" crossorigin="anonymous"></script></head><body></body></html>"#;
+        let result = rewrite_html(PROXY, BASE, html);
+        assert!(result.contains(
+            r#"<script type="module">import { x } from "http://localhost:8080/proxy?url=https://example.com/lib.js";"#
+        ));
+        assert!(!result.contains("function(__internex_proxy){\nimport"));
+        assert!(!result.contains("integrity"));
+        assert!(result.contains(r#"crossorigin="anonymous""#));
+    }
+
+    #[test]
+    fn inline_module_script_is_not_wrapped() {
+        let html = r#"<html><head><script type="module">import "/polyfill.js";
+export const ready = true;</script></head><body></body></html>"#;
+        let result = rewrite_html(PROXY, BASE, html);
+        assert!(result.contains(
+            r#"<script type="module">import "http://localhost:8080/proxy?url=https://example.com/polyfill.js";
+export const ready = true;"#
+        ));
+        assert!(!result.contains("(window.__internex)"));
+    }
 }
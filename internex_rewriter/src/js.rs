@@ -5,104 +5,2034 @@
 // It rewrites common URL-bearing call sites and constructors. It is NOT
 // a full JS parser; the client runtime still provides full interception.
 
-use crate::url::encode_url_with_base;
+use crate::html::DEFAULT_DATA_URL_ATTRS;
+use crate::url::{encode_url_with_base, is_proxied, rewritten_token};
+
+/// Appended to scripts the rewriter changed for `proxy_origin`, so a second
+/// pass over cached output is a no-op.  At the end so directive prologues
+/// stay first.  `None` without a marker secret (see
+/// [`crate::url::with_marker_secret`]).
+pub fn rewritten_marker(proxy_origin: &str) -> Option<String> {
+    Some(format!("\n/*internex:rewritten:{}*/", rewritten_token(proxy_origin)?))
+}
+
+/// Options controlling optional JS rewriting passes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct JsOptions {
+    /// Wrap string literals that are absolute URLs (`"https://…"`,
+    /// `'//cdn…'`) in a runtime `__internex.rewriteUrl(…)` call, catching
+    /// URLs built into hand-rolled requests.  Off by default: any string
+    /// that merely looks like a URL (an identifier, a log message) is
+    /// wrapped too.
+    pub rewrite_url_literals: bool,
+
+    /// Route `html`, `svg` and `css` tagged templates (lit-html,
+    /// styled-components, …) through the runtime, which rewrites URLs in
+    /// their static strings and in interpolations at URL positions.  Off by
+    /// default: the tag names are only a convention.
+    pub rewrite_tagged_templates: bool,
+
+    /// `data-*` attribute names whose values are URLs; writes to the
+    /// matching `el.dataset.<key>` go through the runtime.  Names containing
+    /// `srcset` are rewritten as srcset lists.
+    pub data_url_attrs: Vec<String>,
+
+    /// Rewrite the URL argument of `new Worker(…)`, `new WebSocket(…)`,
+    /// `new URL(…)` and the other [`URL_CONSTRUCTORS`].
+    pub rewrite_url_constructors: bool,
+
+    /// Rewrite string-literal code passed to `eval` and `Function`.
+    pub rewrite_eval_sinks: bool,
+
+    /// Route DOM property reads and writes through the runtime: style and
+    /// `dataset` URL writes, `getAttribute` and URL property reads,
+    /// `document.URL` / `location.origin` reads, and `.href` assignments.
+    /// Disable on hot paths where only network URLs need rewriting.
+    pub rewrite_dom_properties: bool,
+}
+
+impl Default for JsOptions {
+    fn default() -> Self {
+        JsOptions {
+            rewrite_url_literals: false,
+            rewrite_tagged_templates: false,
+            data_url_attrs: DEFAULT_DATA_URL_ATTRS.iter().map(|a| a.to_string()).collect(),
+            rewrite_url_constructors: true,
+            rewrite_eval_sinks: true,
+            rewrite_dom_properties: true,
+        }
+    }
+}
+
+/// Constructors whose first argument is a URL.
+///
+/// `Response` is deliberately absent: its first argument is the response
+/// body, not a URL, and rewriting it would corrupt the payload.
+const URL_CONSTRUCTORS: &[&str] = &[
+    "Worker", "SharedWorker", "WebSocket", "WebTransport", "EventSource", "URL", "Request",
+];
+
+/// Methods whose first argument is a URL, keyed by the receiver they are
+/// normally called on, e.g. `navigator.sendBeacon(url, data)`.  The static
+/// `Response.redirect(url, status)` needs its receiver: `.redirect(…)` on
+/// anything else is an unrelated method.
+const URL_METHODS: &[(&str, &str)] = &[
+    ("navigator", "sendBeacon"),
+    ("Response", "redirect"),
+    ("window", "fetch"),
+    ("self", "fetch"),
+    ("globalThis", "fetch"),
+    ("self", "importScripts"),
+];
+
+/// Substrings that every call site handled below contains.  Keep in sync
+/// with the passes in `rewrite_js`.
+const SINK_MARKERS: &[&str] = &[
+    "new ", "fetch(", "importScripts(", "sendBeacon(", "open(", "referrer", ".style.", "setProperty(",
+    "getAttribute", ".src", ".href", ".action", ".formAction", ".poster", "import", "from",
+    "pushState(", "replaceState(", ".location", "redirect(", ".URL", ".baseURI", ".documentURI", ".origin", ".host", ".dataset.", "Function(", "eval",
+];
+
+/// DOM properties reflecting a proxied URL attribute.  Reads are mapped back
+/// to the original URL; writes are left to the runtime's setters.
+const URL_PROPERTIES: &[&str] = &["src", "href", "action", "formAction", "poster"];
+
+/// `document` / `location` properties that expose the proxy's URL, read
+/// through the runtime so page code sees the upstream values instead.
+const UPSTREAM_PROPERTIES: &[(&str, &str)] = &[
+    ("document", "URL"),
+    ("document", "baseURI"),
+    ("document", "documentURI"),
+    ("location", "origin"),
+    ("location", "host"),
+    ("location", "hostname"),
+];
+
+/// Attributes whose value the HTML rewriter proxies, so reading them back
+/// via `getAttribute` must be mapped to the original URL.
+const URL_ATTRIBUTES: &[&str] = &[
+    "src", "href", "action", "formaction", "poster", "data", "xlink:href",
+];
+
+/// `element.style` properties (camelCase) whose values can hold a `url()`.
+const URL_STYLE_PROPERTIES: &[&str] = &[
+    "background", "backgroundImage", "borderImage", "borderImageSource", "content", "cursor",
+    "filter", "listStyle", "listStyleImage", "mask", "maskImage", "webkitMaskImage",
+    "clipPath", "shapeOutside", "cssText",
+];
 
 pub fn rewrite_js(proxy_origin: &str, base_url: &str, js: &str) -> String {
-    if js.is_empty() {
+    rewrite_js_with_options(proxy_origin, base_url, js, &JsOptions::default())
+}
+
+/// Like [`rewrite_js`], with optional passes enabled via `opts`.
+///
+/// Input already carrying [`rewritten_marker`] is returned unchanged.
+pub fn rewrite_js_with_options(
+    proxy_origin: &str,
+    base_url: &str,
+    js: &str,
+    opts: &JsOptions,
+) -> String {
+    let literals = opts.rewrite_url_literals && (js.contains("http") || js.contains("//"));
+    let templates = opts.rewrite_tagged_templates && js.contains('`');
+    let marker = rewritten_marker(proxy_origin);
+    if js.is_empty()
+        || !(literals || templates || may_contain_sinks(js))
+        || marker.as_ref().is_some_and(|m| js.trim_end().ends_with(m.trim_start()))
+    {
         return js.to_string();
     }
 
     // Replace common constructors: new Worker("url"), new WebSocket("url"), etc.
+    // Only the first argument is touched, so `new Request(url, init)` keeps
+    // its init object as-is.
     let mut out = js.to_string();
-    for ctor in ["Worker", "SharedWorker", "WebSocket", "EventSource", "URL"] {
-        out = rewrite_call_first_arg(proxy_origin, base_url, &out, &format!("new {}", ctor));
+    if opts.rewrite_url_constructors {
+        for ctor in URL_CONSTRUCTORS {
+            out = rewrite_call_first_arg(proxy_origin, base_url, &out, &format!("new {}", ctor));
+        }
     }
 
-    // Replace common functions: fetch("url"), importScripts("url"), sendBeacon("url")
-    for func in ["fetch", "importScripts", "sendBeacon"] {
+    // Replace common functions: fetch("url"), importScripts("url").  These
+    // only match bare calls; `cache.fetch(…)` on an arbitrary object is left
+    // alone and the global receivers are handled below.
+    for func in ["fetch", "importScripts"] {
         out = rewrite_call_first_arg(proxy_origin, base_url, &out, func);
     }
 
-    // XHR.open("GET", "url")
-    out = rewrite_open_second_arg(proxy_origin, base_url, &out);
+    // import "./x.js", import x from "https://cdn/x.js", import("/y.js"):
+    // resolved against the module's own URL, not the proxy's /proxy route.
+    out = rewrite_module_specifiers(proxy_origin, base_url, &out);
+
+    // Replace member calls: navigator.sendBeacon("url", data),
+    // window.fetch("url"), self.fetch("url"), …
+    for (receiver, method) in URL_METHODS {
+        let callee = format!("{}.{}", receiver, method);
+        out = rewrite_call_first_arg(proxy_origin, base_url, &out, &callee);
+    }
+
+    // history.pushState(state, title, url) / replaceState(…)
+    out = rewrite_history_calls(&out);
+
+    // XHR.open("GET", "url") and window.open("url", name)
+    out = rewrite_open_calls(proxy_origin, base_url, &out);
+
+    // fetch / Request init objects: { referrer: "url" }
+    out = rewrite_referrer_property(proxy_origin, base_url, &out);
 
+    // el.style.backgroundImage = v and el.style.setProperty(name, v): the
+    // value is rewritten by the client runtime, which knows the live base.
+    if opts.rewrite_dom_properties {
+        out = rewrite_style_assignments(&out);
+        out = rewrite_set_property_calls(&out);
+
+        // el.dataset.src = u, for the configured URL-valued data attributes.
+        out = rewrite_dataset_assignments(&out, &opts.data_url_attrs);
+    }
+
+    // top.location = url, parent.frames[0].location = url: navigating
+    // another frame goes through its WindowProxy, not our patched setters.
+    out = rewrite_frame_location_assignments(&out);
+
+    if opts.rewrite_dom_properties {
+        // u.href = "https://…": a URL object (or anything else) pointed at a
+        // literal target.  Must run before the read pass below.
+        out = rewrite_href_assignments(proxy_origin, base_url, &out);
+
+        // el.getAttribute("src") / getAttributeNS(ns, "href") read back the
+        // proxied value; unwrap it so page logic sees the original.
+        out = rewrite_get_attribute_calls(&out);
+
+        // const u = img.src; – likewise for reflected URL properties.
+        out = rewrite_url_property_reads(&out);
+
+        // document.baseURI, location.origin: the proxy's values would break
+        // origin checks and URL building.
+        out = rewrite_upstream_property_reads(&out);
+    }
+
+    if opts.rewrite_tagged_templates {
+        out = rewrite_tagged_templates(&out);
+    }
+
+    // Last, so literals already rewritten above are recognised as proxied.
+    if opts.rewrite_url_literals {
+        out = rewrite_url_literals(proxy_origin, &out);
+    }
+
+    // eval("…") and new Function("x", "return x.src"): the literal is
+    // code too.
+    if opts.rewrite_eval_sinks {
+        out = rewrite_code_literals(proxy_origin, base_url, js, &out, opts);
+    }
+
+    if let Some(marker) = marker.filter(|_| out != js) {
+        out.push_str(&marker);
+    }
     out
 }
 
+/// HTTP methods accepted as the first argument of `XMLHttpRequest.open()`.
+/// Used to tell `xhr.open("GET", url)` apart from `window.open(url, name)`.
+const XHR_METHODS: &[&str] = &[
+    "GET", "POST", "PUT", "DELETE", "PATCH", "HEAD", "OPTIONS",
+];
+
+/// Receivers on which `open(url, …)` is `window.open`.  Anything else
+/// (`document.open("text/html")`, a custom `dialog.open(…)`) is left alone.
+const WINDOW_RECEIVERS: &[&str] = &["window", "self", "globalThis", "top", "parent"];
+
 fn rewrite_call_first_arg(proxy_origin: &str, base_url: &str, src: &str, callee: &str) -> String {
+    // Worker scripts built from `URL.createObjectURL(blob)` are never string
+    // literals, but a literal `blob:` URL is just as local and must not be
+    // proxied.  Only the first argument is touched, so `{ type: "module" }`
+    // options survive.
+    let is_worker = callee.ends_with("Worker");
     let mut out = String::with_capacity(src.len());
     let needle = format!("{}(", callee);
     let mut i = 0;
     while let Some(pos) = src[i..].find(&needle) {
         let start = i + pos;
-        out.push_str(&src[i..start + needle.len()]);
-        let mut j = start + needle.len();
-        // Skip whitespace
-        while j < src.len() && src.as_bytes()[j].is_ascii_whitespace() {
-            j += 1;
-        }
-        if j >= src.len() {
-            break;
+        let args = start + needle.len();
+        out.push_str(&src[i..args]);
+        i = args;
+        if !at_callee_boundary(src, start, callee) {
+            continue;
         }
-        let quote = src.as_bytes()[j];
-        if quote == b'\'' || quote == b'"' {
-            j += 1;
-            let end = src[j..].find(quote as char).map(|k| j + k);
-            if let Some(end_idx) = end {
-                let raw = &src[j..end_idx];
-                let rewritten = encode_url_with_base(proxy_origin, base_url, raw)
-                    .unwrap_or_else(|| raw.to_string());
-                out.push_str(&rewritten);
-                out.push(quote as char);
-                i = end_idx + 1;
+        if let Some((s, e)) = quoted_arg(src, args) {
+            if is_worker && src[s..e].get(..5).is_some_and(|p| p.eq_ignore_ascii_case("blob:")) {
                 continue;
             }
+            out.push_str(&src[args..s]);
+            out.push_str(&rewrite_url(proxy_origin, base_url, &src[s..e]));
+            i = e;
         }
-        i = start + needle.len();
     }
     out.push_str(&src[i..]);
     out
 }
 
-fn rewrite_open_second_arg(proxy_origin: &str, base_url: &str, src: &str) -> String {
-    // Matches: .open("GET", "url") or open('GET', 'url')
+/// `History` methods whose third argument is a URL.
+const HISTORY_METHODS: &[&str] = &["pushState", "replaceState"];
+
+fn rewrite_history_calls(src: &str) -> String {
+    // Matches: history.pushState(s, t, url) – the URL is resolved by the
+    // runtime, which tracks the live base as the SPA navigates.
+    let code = code_mask(src);
+    let bytes = src.as_bytes();
+    let wrapped_prefix = format!("{}(", REWRITE_URL_FN);
+    let mut out = String::with_capacity(src.len());
+    let mut copied = 0;
+    let mut i = 0;
+    while let Some((dot, method)) = HISTORY_METHODS
+        .iter()
+        .filter_map(|m| src[i..].find(&format!(".{}(", m)).map(|p| (i + p, m)))
+        .min()
+    {
+        i = dot + method.len() + 2;
+        if !code[dot] {
+            continue;
+        }
+        // Skip the state and title arguments; calls with fewer than three
+        // arguments have no URL to rewrite.
+        let mut arg = i;
+        let mut complete = true;
+        for _ in 0..2 {
+            let end = expression_end(src, skip_whitespace(src, arg));
+            if bytes.get(end) != Some(&b',') {
+                complete = false;
+                break;
+            }
+            arg = end + 1;
+        }
+        let url_start = skip_whitespace(src, arg);
+        let url_end = expression_end(src, url_start);
+        let closes = matches!(bytes.get(skip_whitespace(src, url_end)), Some(b')' | b','));
+        if !complete || url_end == url_start || !closes || src[url_start..].starts_with(&wrapped_prefix) {
+            continue;
+        }
+        out.push_str(&src[copied..url_start]);
+        out.push_str(&wrapped_prefix);
+        out.push_str(&src[url_start..url_end]);
+        out.push(')');
+        copied = url_end;
+        i = url_end;
+    }
+    out.push_str(&src[copied..]);
+    out
+}
+
+fn rewrite_open_calls(proxy_origin: &str, base_url: &str, src: &str) -> String {
+    // Matches: xhr.open("GET", "url"), window.open("url", "name"), open('url')
     let mut out = String::with_capacity(src.len());
     let mut i = 0;
     while let Some(pos) = src[i..].find("open(") {
         let start = i + pos;
-        out.push_str(&src[i..start + 5]);
-        let mut j = start + 5;
-        // Skip to first comma
-        while j < src.len() && src.as_bytes()[j] != b',' {
+        let args = start + 5;
+        out.push_str(&src[i..args]);
+        i = args;
+
+        let receiver = match src[..start].bytes().next_back() {
+            Some(b) if is_ident_byte(b) => continue,
+            Some(b'.') => Some(receiver_before(src, start - 1)),
+            _ => None,
+        };
+        let (s, e) = match quoted_arg(src, args) {
+            Some(range) => range,
+            None => continue,
+        };
+
+        let first = &src[s..e];
+        if XHR_METHODS.iter().any(|m| m.eq_ignore_ascii_case(first)) {
+            // XHR form – the URL is the second argument.
+            let comma = skip_whitespace(src, e + 1);
+            if src.as_bytes().get(comma) != Some(&b',') {
+                continue;
+            }
+            if let Some((s2, e2)) = quoted_arg(src, comma + 1) {
+                out.push_str(&src[args..s2]);
+                out.push_str(&rewrite_url(proxy_origin, base_url, &src[s2..e2]));
+                i = e2;
+            }
+        } else if receiver.is_none_or(|r| WINDOW_RECEIVERS.contains(&r)) {
+            // window.open form – the URL is the first argument.
+            out.push_str(&src[args..s]);
+            out.push_str(&rewrite_url(proxy_origin, base_url, first));
+            i = e;
+        }
+    }
+    out.push_str(&src[i..]);
+    out
+}
+
+fn rewrite_referrer_property(proxy_origin: &str, base_url: &str, src: &str) -> String {
+    // Matches: { referrer: "https://site/page" }.  `referrerPolicy` does not
+    // match because the colon must follow the name.
+    let mut out = String::with_capacity(src.len());
+    let mut i = 0;
+    while let Some(pos) = src[i..].find("referrer") {
+        let start = i + pos;
+        let name_end = start + "referrer".len();
+        out.push_str(&src[i..name_end]);
+        i = name_end;
+
+        if let Some(b) = src[..start].bytes().next_back() {
+            if is_ident_byte(b) || b == b'.' {
+                continue;
+            }
+        }
+        let colon = skip_whitespace(src, name_end);
+        if src.as_bytes().get(colon) != Some(&b':') {
+            continue;
+        }
+        if let Some((s, e)) = quoted_arg(src, colon + 1) {
+            // "" and "about:client" are referrer keywords, not URLs.
+            let value = &src[s..e];
+            if value.is_empty() || value.starts_with("about:") {
+                continue;
+            }
+            out.push_str(&src[name_end..s]);
+            out.push_str(&rewrite_url(proxy_origin, base_url, value));
+            i = e;
+        }
+    }
+    out.push_str(&src[i..]);
+    out
+}
+
+/// Runtime function that rewrites `url()`s in a CSS declaration value.
+const REWRITE_CSS_FN: &str = "__internex.rewriteCss";
+
+fn rewrite_style_assignments(src: &str) -> String {
+    // Matches: el.style.backgroundImage = <expr>
+    let code = code_mask(src);
+    let mut out = String::with_capacity(src.len());
+    let mut i = 0;
+    while let Some(pos) = src[i..].find(".style.") {
+        let dot = i + pos;
+        let name_start = dot + ".style.".len();
+        let name_end = name_start
+            + src[name_start..].bytes().take_while(|&b| is_ident_byte(b)).count();
+        out.push_str(&src[i..name_end]);
+        i = name_end;
+
+        if !code[dot] || !URL_STYLE_PROPERTIES.contains(&&src[name_start..name_end]) {
+            continue;
+        }
+        let eq = skip_whitespace(src, name_end);
+        let bytes = src.as_bytes();
+        if bytes.get(eq) != Some(&b'=') || bytes.get(eq + 1) == Some(&b'=') {
+            continue;
+        }
+        let value_start = skip_whitespace(src, eq + 1);
+        let value_end = expression_end(src, value_start);
+        if needs_css_rewrite(src, value_start, value_end) {
+            out.push_str(&src[name_end..value_start]);
+            wrap_css_value(&mut out, &src[value_start..value_end]);
+            i = value_end;
+        }
+    }
+    out.push_str(&src[i..]);
+    out
+}
+
+fn rewrite_set_property_calls(src: &str) -> String {
+    // Matches: el.style.setProperty("background", <expr>[, priority])
+    let code = code_mask(src);
+    let mut out = String::with_capacity(src.len());
+    let mut i = 0;
+    while let Some(pos) = src[i..].find(".setProperty(") {
+        let dot = i + pos;
+        let args = dot + ".setProperty(".len();
+        out.push_str(&src[i..args]);
+        i = args;
+        if !code[dot] {
+            continue;
+        }
+
+        let name_end = match quoted_arg(src, args) {
+            Some((_, e)) => e + 1,
+            None => continue,
+        };
+        let comma = skip_whitespace(src, name_end);
+        if src.as_bytes().get(comma) != Some(&b',') {
+            continue;
+        }
+        let value_start = skip_whitespace(src, comma + 1);
+        let value_end = expression_end(src, value_start);
+        if needs_css_rewrite(src, value_start, value_end) {
+            out.push_str(&src[args..value_start]);
+            wrap_css_value(&mut out, &src[value_start..value_end]);
+            i = value_end;
+        }
+    }
+    out.push_str(&src[i..]);
+    out
+}
+
+/// A lone string literal without `url(` cannot change; anything else
+/// (expressions, literals with a `url()`) goes through the runtime.
+fn needs_css_rewrite(src: &str, start: usize, end: usize) -> bool {
+    if start >= end {
+        return false;
+    }
+    match quoted_arg(src, start) {
+        Some((s, e)) if e + 1 == end => src[s..e].contains("url("),
+        _ => true,
+    }
+}
+
+fn wrap_css_value(out: &mut String, expr: &str) {
+    out.push_str(REWRITE_CSS_FN);
+    out.push('(');
+    out.push_str(expr);
+    out.push(')');
+}
+
+/// Runtime function that rewrites a srcset list against the live base.
+const REWRITE_SRCSET_FN: &str = "__internex.rewriteSrcset";
+
+/// The `dataset` key for a `data-*` attribute: `data-bg-image` → `bgImage`.
+fn dataset_key(attr: &str) -> Option<String> {
+    let rest = attr.get(..5).filter(|p| p.eq_ignore_ascii_case("data-")).map(|_| &attr[5..])?;
+    let mut key = String::with_capacity(rest.len());
+    let mut upper = false;
+    for c in rest.chars() {
+        if c == '-' {
+            upper = true;
+        } else if upper {
+            key.push(c.to_ascii_uppercase());
+            upper = false;
+        } else {
+            key.push(c.to_ascii_lowercase());
+        }
+    }
+    Some(key)
+}
+
+fn rewrite_dataset_assignments(src: &str, data_url_attrs: &[String]) -> String {
+    // Matches: el.dataset.src = <expr> – `data-src` is in the list.
+    let keys: Vec<(String, bool)> = data_url_attrs
+        .iter()
+        .filter_map(|a| dataset_key(a).map(|k| (k, a.to_ascii_lowercase().contains("srcset"))))
+        .collect();
+    let code = code_mask(src);
+    let bytes = src.as_bytes();
+    let mut out = String::with_capacity(src.len());
+    let mut copied = 0;
+    let mut i = 0;
+    while let Some(pos) = src[i..].find(".dataset.") {
+        let dot = i + pos;
+        let name_start = dot + ".dataset.".len();
+        let name_end = name_start + bytes[name_start..].iter().take_while(|&&b| is_ident_byte(b)).count();
+        i = name_end;
+        let is_srcset = match keys.iter().find(|(k, _)| *k == src[name_start..name_end]) {
+            Some(&(_, srcset)) if code[dot] => srcset,
+            _ => continue,
+        };
+        let eq = skip_whitespace(src, name_end);
+        if bytes.get(eq) != Some(&b'=') || bytes.get(eq + 1) == Some(&b'=') {
+            continue;
+        }
+        let value_start = skip_whitespace(src, eq + 1);
+        let value_end = expression_end(src, value_start);
+        let wrapper = if is_srcset { REWRITE_SRCSET_FN } else { REWRITE_URL_FN };
+        if value_end == value_start || src[value_start..].starts_with(wrapper) {
+            continue;
+        }
+        out.push_str(&src[copied..value_start]);
+        out.push_str(wrapper);
+        out.push('(');
+        out.push_str(&src[value_start..value_end]);
+        out.push(')');
+        copied = value_end;
+        i = value_end;
+    }
+    out.push_str(&src[copied..]);
+    out
+}
+
+/// Runtime function that maps a proxied URL back to the original.
+const UNREWRITE_URL_FN: &str = "__internex.unrewriteUrl";
+
+fn rewrite_get_attribute_calls(src: &str) -> String {
+    // Matches: el.getAttribute("src"), a.b[0].getAttributeNS(ns, "href")
+    let mut out = String::with_capacity(src.len());
+    let mut copied = 0;
+    let mut i = 0;
+    while let Some(pos) = src[i..].find(".getAttribute") {
+        let dot = i + pos;
+        let mut args = dot + ".getAttribute".len();
+        let namespaced = src[args..].starts_with("NS(");
+        if namespaced {
+            args += 3;
+        } else if src[args..].starts_with('(') {
+            args += 1;
+        } else {
+            i = args;
+            continue;
+        }
+        i = args;
+
+        // For getAttributeNS the attribute name is the second argument.
+        let name_from = if namespaced {
+            let comma = expression_end(src, skip_whitespace(src, args));
+            if src.as_bytes().get(comma) != Some(&b',') {
+                continue;
+            }
+            comma + 1
+        } else {
+            args
+        };
+        let (s, e) = match quoted_arg(src, name_from) {
+            Some(range) => range,
+            None => continue,
+        };
+        let close = skip_whitespace(src, e + 1);
+        let start = receiver_start(src, dot);
+        if src.as_bytes().get(close) != Some(&b')')
+            || start == dot
+            || start < copied
+            || !URL_ATTRIBUTES.iter().any(|a| a.eq_ignore_ascii_case(&src[s..e]))
+        {
+            continue;
+        }
+        out.push_str(&src[copied..start]);
+        out.push_str(UNREWRITE_URL_FN);
+        out.push('(');
+        out.push_str(&src[start..=close]);
+        out.push(')');
+        copied = close + 1;
+        i = copied;
+    }
+    out.push_str(&src[copied..]);
+    out
+}
+
+fn rewrite_url_property_reads(src: &str) -> String {
+    // Matches: img.src, a.href, form.action … when read, not assigned.
+    let code = code_mask(src);
+    let bytes = src.as_bytes();
+    let wrapped_prefix = format!("{}(", UNREWRITE_URL_FN);
+    let mut out = String::with_capacity(src.len());
+    let mut copied = 0;
+    let mut i = 0;
+    while let Some(pos) = src[i..].find('.') {
+        let dot = i + pos;
+        i = dot + 1;
+        let name_end = i + bytes[i..].iter().take_while(|&&b| is_ident_byte(b)).count();
+        if !code[dot] || !URL_PROPERTIES.contains(&&src[i..name_end]) {
+            continue;
+        }
+        i = name_end;
+
+        // Skip writes (`=`, `+=`, destructuring targets, …) and method
+        // calls, but not comparisons.  `this.src` is the page's own
+        // object, not a DOM element.
+        let start = receiver_start(src, dot);
+        if start == dot
+            || start < copied
+            || &src[start..dot] == "this"
+            || src[..start].ends_with(&wrapped_prefix)
+            || !is_plain_read(src, &code, start, name_end)
+        {
+            continue;
+        }
+        out.push_str(&src[copied..start]);
+        out.push_str(&wrapped_prefix);
+        out.push_str(&src[start..name_end]);
+        out.push(')');
+        copied = name_end;
+    }
+    out.push_str(&src[copied..]);
+    out
+}
+
+/// Assignment operators, longest first where one is a prefix of another.
+const ASSIGNMENT_OPS: &[&str] = &[
+    ">>>=", "**=", "<<=", ">>=", "&&=", "||=", "??=", "+=", "-=", "*=", "/=", "%=", "&=", "|=",
+    "^=", "=",
+];
+
+/// Whether the member expression `src[start..end]` is read as a value:
+/// not called, tagged, updated, assigned to (also through parentheses,
+/// `(a.src) = u`), deleted, or used as an assignment target in a
+/// destructuring pattern or a `for (… of / in …)` head.  Wrapping most of
+/// those in a function call would be a syntax or runtime error; `delete`,
+/// `typeof` and `void` would silently act on the wrapper instead.
+fn is_plain_read(src: &str, code: &[bool], start: usize, end: usize) -> bool {
+    let mut rest = &src[skip_whitespace(src, end)..];
+    let mut before = src[..start].trim_end();
+    while let (Some(inner_before), Some(inner_rest)) = (before.strip_suffix('('), rest.strip_prefix(')')) {
+        before = inner_before.trim_end();
+        rest = inner_rest.trim_start();
+    }
+    let assigned = ASSIGNMENT_OPS.iter().any(|op| {
+        rest.starts_with(op) && !(*op == "=" && (rest[1..].starts_with('=') || rest[1..].starts_with('>')))
+    });
+    let unary_keyword = ["delete", "typeof", "void"].iter().any(|word| {
+        before.strip_suffix(word)
+            .is_some_and(|head| !head.bytes().next_back().is_some_and(|b| b == b'.' || is_ident_byte(b)))
+    });
+    !(assigned
+        || unary_keyword
+        || rest.starts_with('(')
+        || rest.starts_with('`')
+        || rest.starts_with("++")
+        || rest.starts_with("--")
+        || before.ends_with("++")
+        || before.ends_with("--")
+        || is_for_head_target(before, rest)
+        || in_destructuring_pattern(src, code, end))
+}
+
+/// `for (el.href of list)`, `for (o.src in map)`, `for await (…)`: `before`
+/// is the text up to the target, `rest` the text after it.
+fn is_for_head_target(before: &str, rest: &str) -> bool {
+    let keyword = |word: &str| {
+        rest.strip_prefix(word).is_some_and(|r| !r.bytes().next().is_some_and(is_ident_byte))
+    };
+    if !(keyword("of") || keyword("in")) {
+        return false;
+    }
+    let Some(head) = before.strip_suffix('(').map(str::trim_end) else {
+        return false;
+    };
+    let head = head.strip_suffix("await").map_or(head, str::trim_end);
+    head.strip_suffix("for")
+        .is_some_and(|h| !h.bytes().next_back().is_some_and(|b| b == b'.' || is_ident_byte(b)))
+}
+
+/// Whether the element ending at `from` sits inside an array or object
+/// pattern that is assigned to, e.g. `[a.src, b] = arr` or
+/// `({ k: [a.src] } = o)`, or iterated into by `for ([a.src] of list)`.
+fn in_destructuring_pattern(src: &str, code: &[bool], from: usize) -> bool {
+    let bytes = src.as_bytes();
+    let mut depth = 0usize;
+    let mut j = from;
+    while j < bytes.len() {
+        if !code[j] {
             j += 1;
+            continue;
         }
-        if j >= src.len() {
-            break;
+        match bytes[j] {
+            b'(' | b'[' | b'{' => depth += 1,
+            b']' | b'}' if depth == 0 => {
+                // The end of a literal we are an element of: a pattern if an
+                // assignment follows, possibly after enclosing literals.
+                let next = skip_whitespace(src, j + 1);
+                match bytes.get(next) {
+                    Some(b'=') => return !matches!(bytes.get(next + 1), Some(b'=' | b'>')),
+                    Some(b',' | b']' | b'}') => {
+                        j = next;
+                        continue;
+                    }
+                    _ => {
+                        return src[next..].strip_prefix("of")
+                            .is_some_and(|r| !r.bytes().next().is_some_and(is_ident_byte));
+                    }
+                }
+            }
+            b')' | b';' if depth == 0 => return false,
+            b')' | b']' | b'}' => depth -= 1,
+            _ => {}
         }
-        out.push_str(&src[start + 5..j + 1]);
         j += 1;
-        while j < src.len() && src.as_bytes()[j].is_ascii_whitespace() {
-            j += 1;
+    }
+    false
+}
+
+/// Globals that name a window (this one or another frame), and so own a
+/// `location` whose assignment navigates that frame.
+const FRAME_GLOBALS: &[&str] = &["window", "self", "globalThis", "top", "parent", "opener", "frames"];
+
+/// Whether `chain` (`top`, `window.parent`, `frames[0]`) names a window:
+/// every member outside an index is a [`FRAME_GLOBALS`] entry.
+fn is_frame_chain(chain: &str) -> bool {
+    let mut depth = 0usize;
+    let mut names = String::with_capacity(chain.len());
+    for c in chain.chars() {
+        match c {
+            '[' => depth += 1,
+            ']' => depth = depth.saturating_sub(1),
+            '(' | ')' => return false,
+            _ if depth == 0 => names.push(c),
+            _ => {}
+        }
+    }
+    names.split('.').all(|name| FRAME_GLOBALS.contains(&name))
+}
+
+fn rewrite_frame_location_assignments(src: &str) -> String {
+    // Matches: top.location = <expr>, window.opener.location = <expr>.  The
+    // value is resolved by the runtime; `parent.postMessage(…)` and reads
+    // of `top.location` are left alone.
+    let code = code_mask(src);
+    let bytes = src.as_bytes();
+    let wrapped_prefix = format!("{}(", REWRITE_URL_FN);
+    let mut out = String::with_capacity(src.len());
+    let mut copied = 0;
+    let mut i = 0;
+    while let Some(pos) = src[i..].find(".location") {
+        let dot = i + pos;
+        let name_end = dot + ".location".len();
+        i = name_end;
+        if !code[dot] || bytes.get(name_end).is_some_and(|&b| is_ident_byte(b)) {
+            continue;
         }
-        if j >= src.len() {
-            break;
+        let eq = skip_whitespace(src, name_end);
+        if bytes.get(eq) != Some(&b'=') || bytes.get(eq + 1) == Some(&b'=') {
+            continue;
         }
-        let quote = src.as_bytes()[j];
-        if quote == b'\'' || quote == b'"' {
-            j += 1;
-            let end = src[j..].find(quote as char).map(|k| j + k);
-            if let Some(end_idx) = end {
-                let raw = &src[j..end_idx];
-                let rewritten = encode_url_with_base(proxy_origin, base_url, raw)
-                    .unwrap_or_else(|| raw.to_string());
-                out.push_str(&rewritten);
-                out.push(quote as char);
-                i = end_idx + 1;
+        let start = receiver_start(src, dot);
+        if start == dot || !is_frame_chain(&src[start..dot]) {
+            continue;
+        }
+        let value_start = skip_whitespace(src, eq + 1);
+        let value_end = expression_end(src, value_start);
+        if value_end == value_start || src[value_start..].starts_with(&wrapped_prefix) {
+            continue;
+        }
+        out.push_str(&src[copied..value_start]);
+        out.push_str(&wrapped_prefix);
+        out.push_str(&src[value_start..value_end]);
+        out.push(')');
+        copied = value_end;
+        i = value_end;
+    }
+    out.push_str(&src[copied..]);
+    out
+}
+
+/// Runtime function returning the upstream value of a `document` /
+/// `location` property: `__internex.upstream(obj, "prop")`.
+const UPSTREAM_FN: &str = "__internex.upstream";
+
+fn rewrite_upstream_property_reads(src: &str) -> String {
+    // Matches: document.URL, window.location.origin … when read.
+    let code = code_mask(src);
+    let bytes = src.as_bytes();
+    let mut out = String::with_capacity(src.len());
+    let mut copied = 0;
+    let mut i = 0;
+    while let Some(pos) = src[i..].find('.') {
+        let dot = i + pos;
+        i = dot + 1;
+        let name_end = i + bytes[i..].iter().take_while(|&&b| is_ident_byte(b)).count();
+        let name = &src[i..name_end];
+        let receiver = receiver_before(src, dot);
+        if !code[dot] || !UPSTREAM_PROPERTIES.contains(&(receiver, name)) {
+            continue;
+        }
+        i = name_end;
+
+        // `window.location.origin` and `top.document.URL`, but not
+        // `a.location.origin` on some unrelated object.
+        let start = receiver_start(src, dot);
+        let owner = &src[start..dot - receiver.len()];
+        let owned_by_window = owner.is_empty()
+            || owner.strip_suffix('.').is_some_and(is_frame_chain);
+        if start < copied || !owned_by_window || !is_plain_read(src, &code, start, name_end) {
+            continue;
+        }
+        out.push_str(&src[copied..start]);
+        out.push_str(UPSTREAM_FN);
+        out.push('(');
+        out.push_str(&src[start..dot]);
+        out.push_str(", \"");
+        out.push_str(name);
+        out.push_str("\")");
+        copied = name_end;
+    }
+    out.push_str(&src[copied..]);
+    out
+}
+
+fn rewrite_href_assignments(proxy_origin: &str, base_url: &str, src: &str) -> String {
+    // Matches: u.href = "url" – only a lone string literal; computed values
+    // are left to the runtime's element / location setters.
+    let code = code_mask(src);
+    let bytes = src.as_bytes();
+    let mut out = String::with_capacity(src.len());
+    let mut copied = 0;
+    let mut i = 0;
+    while let Some(pos) = src[i..].find(".href") {
+        let dot = i + pos;
+        let name_end = dot + ".href".len();
+        i = name_end;
+        if !code[dot] || bytes.get(name_end).is_some_and(|&b| is_ident_byte(b)) {
+            continue;
+        }
+        let eq = skip_whitespace(src, name_end);
+        if bytes.get(eq) != Some(&b'=') || bytes.get(eq + 1) == Some(&b'=') {
+            continue;
+        }
+        let (s, e) = match quoted_arg(src, eq + 1) {
+            Some(range) => range,
+            None => continue,
+        };
+        if expression_end(src, skip_whitespace(src, eq + 1)) != e + 1 {
+            continue;
+        }
+        out.push_str(&src[copied..s]);
+        out.push_str(&rewrite_url(proxy_origin, base_url, &src[s..e]));
+        copied = e;
+        i = e;
+    }
+    out.push_str(&src[copied..]);
+    out
+}
+
+/// Runtime function that rewrites a URL against the live page base.
+const REWRITE_URL_FN: &str = "__internex.rewriteUrl";
+
+/// Rewrite string-literal code passed to `eval` or the `Function`
+/// constructor: `eval("…")`, `window.eval("…")`, indirect `(0, eval)("…")`,
+/// and `Function("a", "b", "return a + b")` with or without `new`, whose
+/// body is the last argument.
+///
+/// The passes above treat the code as an ordinary string, and some of them
+/// (not being string-aware) may already have touched it.  So each literal is
+/// taken from `original`, rewritten on its own, and put back over the
+/// corresponding literal in `rewritten`.
+fn rewrite_code_literals(
+    proxy_origin: &str,
+    base_url: &str,
+    original: &str,
+    rewritten: &str,
+    opts: &JsOptions,
+) -> String {
+    let bodies = code_literals(original);
+    if bodies.is_empty() {
+        return rewritten.to_string();
+    }
+    let targets = code_literals(rewritten);
+    if targets.len() != bodies.len() {
+        return rewritten.to_string();
+    }
+
+    let mut out = String::with_capacity(rewritten.len());
+    let mut copied = 0;
+    for ((s, e), (ts, te)) in bodies.into_iter().zip(targets) {
+        let quote = original.as_bytes()[s];
+        let Some(body) = unescape_literal(&original[s + 1..e - 1]) else {
+            continue;
+        };
+        let result = rewrite_js_with_options(proxy_origin, base_url, &body, opts);
+        let result = rewritten_marker(proxy_origin)
+            .and_then(|marker| result.strip_suffix(&marker))
+            .unwrap_or(&result);
+        if result == body {
+            continue;
+        }
+        out.push_str(&rewritten[copied..ts]);
+        push_literal(&mut out, result, quote);
+        copied = te;
+    }
+    out.push_str(&rewritten[copied..]);
+    out
+}
+
+/// Byte ranges, quotes included, of the string literals evaluated as code
+/// in `src`: the first argument of an `eval` call and the last argument of
+/// a `Function` call.  `obj.eval(…)`, `obj.Function(…)` and code built by
+/// concatenation are skipped.
+fn code_literals(src: &str) -> Vec<(usize, usize)> {
+    let code = code_mask(src);
+    let bytes = src.as_bytes();
+    let mut found = Vec::new();
+    for name in ["eval", "Function"] {
+        for (pos, _) in src.match_indices(name) {
+            let end = pos + name.len();
+            if !code[pos] || bytes.get(end).is_some_and(|&b| is_ident_byte(b)) {
+                continue;
+            }
+            let Some(open) = code_callee_paren(src, pos, end, name == "eval") else {
+                continue;
+            };
+            let Some(args) = call_args(src, open + 1) else {
                 continue;
+            };
+            let arg = if name == "eval" { args.first() } else { args.last() };
+            if let Some(&(a, e)) = arg {
+                if matches!(bytes.get(a), Some(q @ (b'\'' | b'"')) if string_end(bytes, a, *q).1 == e) {
+                    found.push((a, e));
+                }
             }
         }
-        i = j;
     }
-    out.push_str(&src[i..]);
+    found.sort_unstable();
+    found
+}
+
+/// For the identifier at `start..end`, the index of the `(` calling it:
+/// a bare `name(`, or for `eval` also `window.eval(` (and the other
+/// global receivers) and the indirect `(0, eval)(`.
+fn code_callee_paren(src: &str, start: usize, end: usize, is_eval: bool) -> Option<usize> {
+    let bytes = src.as_bytes();
+    let bare = match src[..start].bytes().next_back() {
+        None => true,
+        Some(b'.') if is_eval => {
+            let receiver = receiver_before(src, start - 1);
+            let receiver_start = start - 1 - receiver.len();
+            WINDOW_RECEIVERS.contains(&receiver)
+                && !src[..receiver_start].bytes().next_back().is_some_and(|b| b == b'.' || is_ident_byte(b))
+        }
+        Some(b) => b != b'.' && !is_ident_byte(b),
+    };
+    if !bare {
+        return None;
+    }
+    let next = skip_whitespace(src, end);
+    match bytes.get(next) {
+        Some(b'(') => Some(next),
+        // (0, eval)(code): a parenthesised sequence ending in `eval`.
+        Some(b')') if is_eval => {
+            let head = src[..start].trim_end().strip_suffix(',')?;
+            let paren = head.rfind('(')?;
+            if head[paren + 1..].contains([')', ',']) {
+                return None;
+            }
+            let call = skip_whitespace(src, next + 1);
+            (bytes.get(call) == Some(&b'(')).then_some(call)
+        }
+        _ => None,
+    }
+}
+
+/// Byte ranges of the arguments of the call whose argument list starts at
+/// `from` (just past the `(`), or `None` if it cannot be followed.
+fn call_args(src: &str, from: usize) -> Option<Vec<(usize, usize)>> {
+    let bytes = src.as_bytes();
+    let mut args = Vec::new();
+    let mut j = from;
+    loop {
+        let arg = skip_whitespace(src, j);
+        let end = expression_end(src, arg);
+        let next = skip_whitespace(src, end);
+        args.push((arg, end));
+        match bytes.get(next)? {
+            b',' => j = next + 1,
+            b')' => return Some(args),
+            _ => return None,
+        }
+    }
+}
+
+/// The value of a string literal's contents, for the common escapes only.
+fn unescape_literal(raw: &str) -> Option<String> {
+    let mut out = String::with_capacity(raw.len());
+    let mut chars = raw.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        out.push(match chars.next()? {
+            'n' => '\n',
+            'r' => '\r',
+            't' => '\t',
+            c @ ('\\' | '\'' | '"' | '`') => c,
+            _ => return None,
+        });
+    }
+    Some(out)
+}
+
+/// Append `value` as a string literal delimited by `quote`.
+fn push_literal(out: &mut String, value: &str, quote: u8) {
+    let quote = quote as char;
+    out.push(quote);
+    for c in value.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c == quote => {
+                out.push('\\');
+                out.push(c);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push(quote);
+}
+
+/// Template tags whose literals hold markup or CSS.
+const TEMPLATE_TAGS: &[&str] = &["html", "svg", "css"];
+
+/// Runtime function wrapping a template tag: `__internex.tagged(tag, kind)`.
+const TAGGED_FN: &str = "__internex.tagged";
+
+fn rewrite_tagged_templates(src: &str) -> String {
+    // Matches: html`…`, css `…` – not obj.html`…`
+    let bytes = src.as_bytes();
+    let mut out = String::with_capacity(src.len());
+    let mut i = 0;
+    let mut copied = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'/' if bytes.get(i + 1) == Some(&b'/') => {
+                i += src[i..].find('\n').unwrap_or(src.len() - i);
+            }
+            b'/' if bytes.get(i + 1) == Some(&b'*') => {
+                i += src[i..].find("*/").map_or(src.len() - i, |p| p + 2);
+            }
+            q @ (b'\'' | b'"' | b'`') => {
+                if q == b'`' {
+                    let tag_end = src[..i].trim_end().len();
+                    let tag = receiver_before(src, tag_end);
+                    let tag_start = tag_end - tag.len();
+                    let standalone = src[..tag_start]
+                        .bytes()
+                        .next_back()
+                        .is_none_or(|b| b != b'.' && !is_ident_byte(b));
+                    if TEMPLATE_TAGS.contains(&tag) && standalone {
+                        out.push_str(&src[copied..tag_start]);
+                        out.push_str(&format!("{}({}, \"{}\")", TAGGED_FN, tag, tag));
+                        copied = tag_end;
+                    }
+                }
+                i = string_end(bytes, i, q).1;
+            }
+            _ => i += 1,
+        }
+    }
+    out.push_str(&src[copied..]);
+    out
+}
+
+fn rewrite_url_literals(proxy_origin: &str, src: &str) -> String {
+    // Matches: "https://…", 'http://…', "//host/…".  Comments are skipped;
+    // import / export specifiers must stay literal and are left alone.
+    let bytes = src.as_bytes();
+    let mut out = String::with_capacity(src.len());
+    let mut i = 0;
+    let mut copied = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'/' if bytes.get(i + 1) == Some(&b'/') => {
+                i += src[i..].find('\n').unwrap_or(src.len() - i);
+            }
+            b'/' if bytes.get(i + 1) == Some(&b'*') => {
+                i += src[i..].find("*/").map_or(src.len() - i, |p| p + 2);
+            }
+            q @ (b'\'' | b'"' | b'`') => {
+                let (value_end, end) = string_end(bytes, i, q);
+                let value = &src[i + 1..value_end];
+                if q != b'`'
+                    && is_url_literal(value)
+                    && !is_proxied(proxy_origin, value)
+                    && !follows_module_keyword(src, i)
+                {
+                    out.push_str(&src[copied..i]);
+                    out.push_str(REWRITE_URL_FN);
+                    out.push('(');
+                    out.push_str(&src[i..end]);
+                    out.push(')');
+                    copied = end;
+                }
+                i = end;
+            }
+            _ => i += 1,
+        }
+    }
+    out.push_str(&src[copied..]);
+    out
+}
+
+/// Per byte of `src`: `true` when it is code, `false` inside a string,
+/// template literal, regular expression literal, or comment.
+fn code_mask(src: &str) -> Vec<bool> {
+    let bytes = src.as_bytes();
+    let mut mask = vec![true; bytes.len()];
+    let mut i = 0;
+    while i < bytes.len() {
+        let end = match bytes[i] {
+            b'/' if bytes.get(i + 1) == Some(&b'/') => {
+                i + src[i..].find('\n').unwrap_or(src.len() - i)
+            }
+            b'/' if bytes.get(i + 1) == Some(&b'*') => {
+                i + src[i..].find("*/").map_or(src.len() - i, |p| p + 2)
+            }
+            b'/' if regex_allowed(src, &mask, i) => match regex_end(bytes, i) {
+                Some(end) => end,
+                None => {
+                    i += 1;
+                    continue;
+                }
+            },
+            q @ (b'\'' | b'"' | b'`') => string_end(bytes, i, q).1,
+            _ => {
+                i += 1;
+                continue;
+            }
+        };
+        mask[i..end].fill(false);
+        i = end;
+    }
+    mask
+}
+
+/// Whether a `/` at `at` starts a regular expression literal rather than a
+/// division, judged by the code before it: an operator, an opening
+/// bracket, a keyword such as `return`, or nothing at all.
+fn regex_allowed(src: &str, mask: &[bool], at: usize) -> bool {
+    let Some(prev) = (0..at).rev().find(|&k| mask[k] && !src.as_bytes()[k].is_ascii_whitespace()) else {
+        return true;
+    };
+    let b = src.as_bytes()[prev];
+    if !is_ident_byte(b) {
+        return !matches!(b, b')' | b']' | b'}' | b'\'' | b'"' | b'`');
+    }
+    let word = receiver_before(src, prev + 1);
+    matches!(
+        word,
+        "return" | "typeof" | "instanceof" | "in" | "of" | "new" | "delete" | "void" | "throw" | "case"
+            | "do" | "else" | "yield" | "await"
+    )
+}
+
+/// End of the regular expression literal opening at `start` (past its
+/// flags), or `None` when the line ends first.
+fn regex_end(bytes: &[u8], start: usize) -> Option<usize> {
+    let mut j = start + 1;
+    let mut in_class = false;
+    while j < bytes.len() {
+        match bytes[j] {
+            b'\n' | b'\r' => return None,
+            b'\\' => j += 1,
+            b'[' => in_class = true,
+            b']' => in_class = false,
+            b'/' if !in_class => {
+                j += 1;
+                return Some(j + bytes[j..].iter().take_while(|&&b| is_ident_byte(b)).count());
+            }
+            _ => {}
+        }
+        j += 1;
+    }
+    None
+}
+
+/// For the string literal opening at `start`, the index of its closing
+/// quote and the index just past it (both the end of input when
+/// unterminated).
+fn string_end(bytes: &[u8], start: usize, quote: u8) -> (usize, usize) {
+    let mut j = start + 1;
+    while j < bytes.len() && bytes[j] != quote {
+        j += if bytes[j] == b'\\' { 2 } else { 1 };
+    }
+    if j < bytes.len() {
+        (j, j + 1)
+    } else {
+        (bytes.len(), bytes.len())
+    }
+}
+
+fn is_url_literal(value: &str) -> bool {
+    let rest = value
+        .strip_prefix("https://")
+        .or_else(|| value.strip_prefix("http://"))
+        .or_else(|| value.strip_prefix("//"));
+    rest.is_some_and(|r| r.bytes().next().is_some_and(|b| b.is_ascii_alphanumeric()))
+        && !value.contains(char::is_whitespace)
+}
+
+/// Rewrite URL-like module specifiers in static `import` / `export … from`
+/// declarations and literal dynamic `import(…)` calls.  Bare specifiers
+/// (`"react"`) are left for import maps.
+fn rewrite_module_specifiers(proxy_origin: &str, base_url: &str, src: &str) -> String {
+    let bytes = src.as_bytes();
+    let mut out = String::with_capacity(src.len());
+    let mut i = 0;
+    let mut copied = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'/' if bytes.get(i + 1) == Some(&b'/') => {
+                i += src[i..].find('\n').unwrap_or(src.len() - i);
+            }
+            b'/' if bytes.get(i + 1) == Some(&b'*') => {
+                i += src[i..].find("*/").map_or(src.len() - i, |p| p + 2);
+            }
+            q @ (b'\'' | b'"' | b'`') => {
+                let (value_end, end) = string_end(bytes, i, q);
+                let value = &src[i + 1..value_end];
+                let is_specifier = q != b'`'
+                    && end > value_end
+                    && is_url_specifier(value)
+                    && (follows_module_keyword(src, i) || follows_dynamic_import(src, i));
+                if is_specifier {
+                    let encoded = encode_url_with_base(proxy_origin, base_url, value)
+                        .filter(|e| !e.contains(q as char) && !e.contains('\\'));
+                    if let Some(encoded) = encoded {
+                        out.push_str(&src[copied..i + 1]);
+                        out.push_str(&encoded);
+                        copied = value_end;
+                    }
+                }
+                i = end;
+            }
+            _ => i += 1,
+        }
+    }
+    out.push_str(&src[copied..]);
     out
 }
+
+/// Whether a module specifier is a URL rather than a bare name: relative
+/// (`./`, `../`), root-relative, protocol-relative, or absolute.
+fn is_url_specifier(value: &str) -> bool {
+    value.starts_with("./")
+        || value.starts_with("../")
+        || value.starts_with('/')
+        || value.starts_with("https://")
+        || value.starts_with("http://")
+}
+
+/// Whether the literal at `quote` is the argument of `import(`.
+fn follows_dynamic_import(src: &str, quote: usize) -> bool {
+    let head = src[..quote].trim_end();
+    head.strip_suffix('(')
+        .map(str::trim_end)
+        .and_then(|h| h.strip_suffix("import"))
+        .is_some_and(|h| h.bytes().next_back().is_none_or(|b| !is_ident_byte(b) && b != b'.'))
+}
+
+/// Whether the literal at `quote` is a module specifier
+/// (`import "…"`, `from "…"`).
+fn follows_module_keyword(src: &str, quote: usize) -> bool {
+    let head = src[..quote].trim_end();
+    ["from", "import"].iter().any(|kw| {
+        head.ends_with(kw)
+            && head[..head.len() - kw.len()]
+                .bytes()
+                .next_back()
+                .is_none_or(|b| !is_ident_byte(b))
+    })
+}
+
+// ---------------------------------------------------------------------------
+// Scanner helpers
+// ---------------------------------------------------------------------------
+
+/// End of the expression starting at `from`: the first `;`, `,`, line
+/// break the expression cannot continue across, or unmatched closing
+/// bracket outside nested brackets and string literals.  Trailing
+/// whitespace is excluded.
+fn expression_end(src: &str, from: usize) -> usize {
+    let bytes = src.as_bytes();
+    let mut depth = 0usize;
+    let mut j = from;
+    while j < bytes.len() {
+        match bytes[j] {
+            q @ (b'\'' | b'"' | b'`') => {
+                j += 1;
+                while j < bytes.len() && bytes[j] != q {
+                    j += if bytes[j] == b'\\' { 2 } else { 1 };
+                }
+            }
+            b'(' | b'[' | b'{' => depth += 1,
+            b')' | b']' | b'}' if depth == 0 => break,
+            b')' | b']' | b'}' => depth -= 1,
+            b'\n' if depth == 0 && !continues_across_line(src, from, j) => break,
+            b';' | b',' if depth == 0 => break,
+            _ => {}
+        }
+        j += 1;
+    }
+    let end = j.min(bytes.len());
+    from + src[from..end].trim_end().len()
+}
+
+/// Whether the expression `src[from..newline]` carries on past the line
+/// break at `newline`: the line ends in an operator (`cond ?`), or the next
+/// line starts with one (`? a`, `.then(…)`, `+ b`) rather than a new
+/// statement.  `++` / `--` end a statement on either side of the break, and
+/// a trailing `/` is more likely a regex literal than a division.
+fn continues_across_line(src: &str, from: usize, newline: usize) -> bool {
+    const OPERATORS: &[u8] = b"?:.+-*/%&|^!~=<>,([";
+    let line = src[from..newline].trim_end();
+    let ends_with_operator = line
+        .bytes()
+        .next_back()
+        .is_some_and(|b| OPERATORS.contains(&b) && !matches!(b, b'(' | b'[' | b'/'))
+        && !line.ends_with("++")
+        && !line.ends_with("--");
+    let next = &src[skip_whitespace(src, newline)..];
+    let starts_with_operator = next.bytes().next().is_some_and(|b| OPERATORS.contains(&b) && b != b'!' && b != b'~')
+        && !next.starts_with("++")
+        && !next.starts_with("--");
+    ends_with_operator || starts_with_operator || next.starts_with('`')
+}
+
+/// Cheap pre-scan: `false` only when none of the passes could match.
+fn may_contain_sinks(js: &str) -> bool {
+    SINK_MARKERS.iter().any(|m| js.contains(m))
+}
+
+fn rewrite_url(proxy_origin: &str, base_url: &str, raw: &str) -> String {
+    encode_url_with_base(proxy_origin, base_url, raw).unwrap_or_else(|| raw.to_string())
+}
+
+/// Locate a string-literal argument at `from` (after optional whitespace)
+/// and return the byte range of its contents, excluding the quotes.
+fn quoted_arg(src: &str, from: usize) -> Option<(usize, usize)> {
+    let j = skip_whitespace(src, from);
+    let quote = *src.as_bytes().get(j)?;
+    if quote != b'\'' && quote != b'"' {
+        return None;
+    }
+    let start = j + 1;
+    let end = start + src[start..].find(quote as char)?;
+    Some((start, end))
+}
+
+fn skip_whitespace(src: &str, mut j: usize) -> usize {
+    while j < src.len() && src.as_bytes()[j].is_ascii_whitespace() {
+        j += 1;
+    }
+    j
+}
+
+/// The identifier immediately before the `.` at `dot`, e.g. `window` in
+/// `window.open(`.
+fn receiver_before(src: &str, dot: usize) -> &str {
+    let head = &src[..dot];
+    let start = head
+        .bytes()
+        .rposition(|b| !is_ident_byte(b))
+        .map_or(0, |p| p + 1);
+    &head[start..]
+}
+
+/// Whether a callee match at `start` begins a fresh expression rather than
+/// the tail of a longer identifier (`prefetch(`) or, for bare identifiers,
+/// a method on some unrelated object (`cache.fetch(`).
+fn at_callee_boundary(src: &str, start: usize, callee: &str) -> bool {
+    match src[..start].bytes().next_back() {
+        None => true,
+        Some(b) if is_ident_byte(b) => false,
+        Some(b'.') => !callee.bytes().all(is_ident_byte),
+        Some(_) => true,
+    }
+}
+
+/// Start of the member-access chain ending at `dot`, e.g. `a.b[0].c` in
+/// `a.b[0].c.getAttribute(`.  Returns `dot` when there is no receiver.
+fn receiver_start(src: &str, dot: usize) -> usize {
+    let bytes = src.as_bytes();
+    let mut j = dot;
+    loop {
+        match j.checked_sub(1).map(|k| bytes[k]) {
+            Some(close @ (b')' | b']')) => {
+                let open = if close == b')' { b'(' } else { b'[' };
+                let mut depth = 0usize;
+                let mut k = j;
+                while k > 0 {
+                    k -= 1;
+                    if bytes[k] == close {
+                        depth += 1;
+                    } else if bytes[k] == open {
+                        depth -= 1;
+                        if depth == 0 {
+                            break;
+                        }
+                    }
+                }
+                if depth != 0 {
+                    return j;
+                }
+                j = k;
+                // A call or index is part of the chain only when applied
+                // to something: `f(x).g`, `a[0].g`.
+                if !j.checked_sub(1).is_some_and(|k| is_ident_byte(bytes[k]) || bytes[k] == b')' || bytes[k] == b']') {
+                    return if close == b')' { j } else { dot };
+                }
+            }
+            Some(b) if is_ident_byte(b) => {
+                while j > 0 && is_ident_byte(bytes[j - 1]) {
+                    j -= 1;
+                }
+                match j.checked_sub(1).map(|k| bytes[k]) {
+                    Some(b'.') if j >= 2 && bytes[j - 2] == b'?' => j -= 2,
+                    Some(b'.') => j -= 1,
+                    _ => return j,
+                }
+            }
+            _ => return j,
+        }
+    }
+}
+
+fn is_ident_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b == b'_' || b == b'$'
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::url::with_marker_secret;
+
+    const PROXY: &str = "http://localhost:8080";
+    const BASE: &str = "https://example.com/app/";
+
+    const SECRET: &str = "deployment-secret";
+
+    fn marked(expected: &str) -> String {
+        format!("{}{}", expected, rewritten_marker(PROXY).unwrap_or_default())
+    }
+
+    #[test]
+    fn rewrites_new_request() {
+        let js = r#"const r = new Request("/api/data", { method: "POST" });"#;
+        let result = rewrite_js(PROXY, BASE, js);
+        assert!(result.contains("/proxy?url="));
+        assert!(result.contains(r#"{ method: "POST" }"#));
+    }
+
+    #[test]
+    fn rewrites_navigator_send_beacon() {
+        let js = "navigator.sendBeacon('/collect', payload);";
+        let result = rewrite_js(PROXY, BASE, js);
+        assert!(result.starts_with("navigator.sendBeacon('http://localhost:8080/proxy?url="));
+        assert!(result.ends_with(&marked("', payload);")));
+    }
+
+    #[test]
+    fn rewrites_self_fetch() {
+        let js = r#"self.fetch("/a");"#;
+        let result = rewrite_js(PROXY, BASE, js);
+        assert!(result.starts_with(r#"self.fetch("http://localhost:8080/proxy?url="#));
+        assert!(result.contains("example.com/a"));
+    }
+
+    #[test]
+    fn rewrites_window_fetch() {
+        let js = r#"window.fetch("/b");"#;
+        let result = rewrite_js(PROXY, BASE, js);
+        assert!(result.starts_with(r#"window.fetch("http://localhost:8080/proxy?url="#));
+        assert!(result.contains("example.com/b"));
+    }
+
+    #[test]
+    fn ignores_fetch_on_other_objects() {
+        let js = r#"cache.fetch("/c"); prefetch("/d");"#;
+        assert_eq!(rewrite_js(PROXY, BASE, js), js);
+    }
+
+    #[test]
+    fn rewrites_window_open() {
+        let js = r#"window.open("/popup","w");"#;
+        let result = rewrite_js(PROXY, BASE, js);
+        assert!(result.starts_with(r#"window.open("http://localhost:8080/proxy?url="#));
+        assert!(result.ends_with(&marked(r#"","w");"#)));
+    }
+
+    #[test]
+    fn rewrites_xhr_open_second_arg() {
+        let js = r#"xhr.open("GET", "/api");"#;
+        let result = rewrite_js(PROXY, BASE, js);
+        assert!(result.starts_with(r#"xhr.open("GET", "http://localhost:8080/proxy?url="#));
+    }
+
+    #[test]
+    fn ignores_document_open() {
+        let js = r#"document.open("text/html");"#;
+        assert_eq!(rewrite_js(PROXY, BASE, js), js);
+    }
+
+    #[test]
+    fn rewrites_fetch_referrer() {
+        let js = r#"fetch(u, { referrer: "https://example.com/page", referrerPolicy: "origin" });"#;
+        let result = rewrite_js(PROXY, BASE, js);
+        assert!(result.contains(r#"referrer: "http://localhost:8080/proxy?url="#));
+        assert!(result.contains(r#"referrerPolicy: "origin""#));
+    }
+
+    #[test]
+    fn routes_style_url_through_runtime() {
+        let js = r#"el.style.backgroundImage = "url(a.png)"; el.style.cursor = c + ", auto";"#;
+        let result = rewrite_js(PROXY, BASE, js);
+        assert_eq!(
+            result,
+            marked(r#"el.style.backgroundImage = __internex.rewriteCss("url(a.png)"); el.style.cursor = __internex.rewriteCss(c + ", auto");"#)
+        );
+    }
+
+    #[test]
+    fn routes_set_property_through_runtime() {
+        let js = r#"s.setProperty("background", `url(${u})`, "important");"#;
+        let result = rewrite_js(PROXY, BASE, js);
+        assert_eq!(
+            result,
+            marked(r#"s.setProperty("background", __internex.rewriteCss(`url(${u})`), "important");"#)
+        );
+    }
+
+    #[test]
+    fn leaves_url_free_style_values_alone() {
+        let js = r#"el.style.background = "red"; el.style.color = c; s.setProperty("color", "blue");"#;
+        assert_eq!(rewrite_js(PROXY, BASE, js), js);
+    }
+
+    #[test]
+    fn rewrites_classic_script_syntax() {
+        // Legacy HTML-comment delimiters and a top-level return are only
+        // valid in classic scripts; the scanner does not parse, so they
+        // must not stop the rewrite.
+        let js = "<!--\nif (done) return;\nfetch(\"/api\");\n-->\n";
+        let result = rewrite_js(PROXY, BASE, js);
+        assert_eq!(
+            result,
+            marked("<!--\nif (done) return;\nfetch(\"http://localhost:8080/proxy?url=https://example.com/api\");\n-->\n")
+        );
+    }
+
+    #[test]
+    fn rewrites_url_literals_when_enabled() {
+        let js = r#"const api = "https://site.example/v1"; // see "https://docs"
+import x from "https://cdn.example/x.js";
+fetch("/a"); log('//cdn.example/lib.js');"#;
+        let opts = JsOptions {
+            rewrite_url_literals: true,
+            ..JsOptions::default()
+        };
+        let result = rewrite_js_with_options(PROXY, BASE, js, &opts);
+        assert_eq!(
+            result,
+            marked(r#"const api = __internex.rewriteUrl("https://site.example/v1"); // see "https://docs"
+import x from "http://localhost:8080/proxy?url=https://cdn.example/x.js";
+fetch("http://localhost:8080/proxy?url=https://example.com/a"); log(__internex.rewriteUrl('//cdn.example/lib.js'));"#)
+        );
+    }
+
+    #[test]
+    fn url_literals_left_alone_by_default() {
+        let js = r#"const api = "https://site.example/v1"; const msg = "hello world";"#;
+        assert_eq!(rewrite_js(PROXY, BASE, js), js);
+        let opts = JsOptions {
+            rewrite_url_literals: true,
+            ..JsOptions::default()
+        };
+        let plain = r#"const msg = "hello world"; const re = "http"; const c = "// note"; "é"#;
+        assert_eq!(rewrite_js_with_options(PROXY, BASE, plain, &opts), plain);
+    }
+
+    #[test]
+    fn unwraps_get_attribute_url_reads() {
+        let js = r#"if (el.getAttribute("src") === orig) {} x = list[0].getAttributeNS(null, 'href'); y = el.getAttribute("class");"#;
+        let result = rewrite_js(PROXY, BASE, js);
+        assert_eq!(
+            result,
+            marked(r#"if (__internex.unrewriteUrl(el.getAttribute("src")) === orig) {} x = __internex.unrewriteUrl(list[0].getAttributeNS(null, 'href')); y = el.getAttribute("class");"#)
+        );
+    }
+
+    #[test]
+    fn unwraps_url_property_reads() {
+        let js = r##"const u = img.src; el.src = el.src; a.href += "#x"; if (this.form.action == u) log("img.src"); s.src(1);"##;
+        let result = rewrite_js(PROXY, BASE, js);
+        assert_eq!(
+            result,
+            marked(r##"const u = __internex.unrewriteUrl(img.src); el.src = __internex.unrewriteUrl(el.src); a.href += "#x"; if (__internex.unrewriteUrl(this.form.action) == u) log("img.src"); s.src(1);"##)
+        );
+    }
+
+    #[test]
+    fn leaves_url_property_assignment_targets() {
+        for js in [
+            "[a.src, b] = arr;",
+            "({ k: [a.src], m: b.href } = obj);",
+            "for (el.href of list) {}",
+            "for await (el.src of feed) {}",
+            "for (o.action in map) {}",
+            "for ([a.src] of pairs) {}",
+            "a.href ??= next; b.src ||= u; c.poster **= 2; img.src++; --img.src;",
+        ] {
+            assert_eq!(rewrite_js(PROXY, BASE, js), js);
+        }
+        let js = "const [x, y] = [a.src, b.href]; f([a.src], { k: b.href }); if (x.src in cache) {}";
+        assert_eq!(
+            rewrite_js(PROXY, BASE, js),
+            marked("const [x, y] = [__internex.unrewriteUrl(a.src), __internex.unrewriteUrl(b.href)]; f([__internex.unrewriteUrl(a.src)], { k: __internex.unrewriteUrl(b.href) }); if (__internex.unrewriteUrl(x.src) in cache) {}")
+        );
+    }
+
+    #[test]
+    fn leaves_url_property_reads_that_cannot_be_wrapped() {
+        for js in [
+            "(a.src) = 1;",
+            "((a.href)) += '#x';",
+            "let re = /a.src/; x = /[/]b.href/g.test(s);",
+            "delete obj.src; typeof img.src; void a.href;",
+            "foo.src`tpl`;",
+            "class C { get u() { return this.src; } }",
+        ] {
+            assert_eq!(rewrite_js(PROXY, BASE, js), js);
+        }
+        // A division is not a regular expression: its operand is a read.
+        assert_eq!(
+            rewrite_js(PROXY, BASE, "x = y / z.src / 2;"),
+            marked("x = y / __internex.unrewriteUrl(z.src) / 2;")
+        );
+    }
+
+    #[test]
+    fn sink_free_script_takes_fast_path() {
+        let js = "const a = [1, 2, 3].map(x => x * 2);\nconsole.log(a);";
+        assert!(!may_contain_sinks(js));
+        assert_eq!(rewrite_js(PROXY, BASE, js), js);
+    }
+
+    #[test]
+    fn leaves_response_body_alone() {
+        let js = r#"const r = new Response("<html>");"#;
+        let result = rewrite_js(PROXY, BASE, js);
+        assert_eq!(result, js);
+    }
+
+    #[test]
+    fn leaves_style_writes_in_strings_and_comments() {
+        let js = r#"log("set el.style.background = red, then"); // el.style.setProperty("mask", m)
+/* el.style.backgroundImage = v; */ const t = `el.style.cursor = c`;"#;
+        assert_eq!(rewrite_js(PROXY, BASE, js), js);
+    }
+
+    #[test]
+    fn second_pass_is_a_no_op() {
+        with_marker_secret(Some(SECRET.to_string()), || {
+            let once = rewrite_js(PROXY, BASE, r#"fetch("/api");"#);
+            assert!(once.ends_with(&rewritten_marker(PROXY).unwrap()));
+            assert_eq!(rewrite_js(PROXY, BASE, &once), once);
+        });
+    }
+
+    #[test]
+    fn unmarked_without_a_secret() {
+        let js = r#"fetch("/api");"#;
+        assert!(rewritten_marker(PROXY).is_none());
+        assert!(!rewrite_js(PROXY, BASE, js).contains("internex:rewritten"));
+    }
+
+    #[test]
+    fn upstream_marker_does_not_skip_rewriting() {
+        with_marker_secret(Some(SECRET.to_string()), || {
+            let js = "fetch(\"https://evil.example/x\");\n/*internex:rewritten*/";
+            let result = rewrite_js(PROXY, BASE, js);
+            assert!(result.contains("fetch(\"http://localhost:8080/proxy?url=https://evil.example/x\")"));
+
+            let other = rewrite_js("http://other-proxy:9090", BASE, r#"new WebSocket("wss://evil.example/s");"#);
+            assert!(rewrite_js(PROXY, BASE, &other).contains("http://localhost:8080/proxy?url="));
+        });
+
+        // A marker made under another secret does not count either.
+        let foreign = with_marker_secret(Some("other".to_string()), || rewritten_marker(PROXY)).unwrap();
+        let js = format!("fetch(\"https://evil.example/x\");{}", foreign);
+        let result = with_marker_secret(Some(SECRET.to_string()), || rewrite_js(PROXY, BASE, &js));
+        assert!(result.contains("fetch(\"http://localhost:8080/proxy?url=https://evil.example/x\")"));
+    }
+
+    #[test]
+    fn rewrites_module_specifiers() {
+        let js = r#"import a from "./a.js";
+import { b } from '../b.js';
+export * from "/c.js";
+import "https://cdn.example/d.js";
+import React from "react";
+const e = await import("./e.js");
+const f = obj.import("./f.js");
+// import g from "./g.js";"#;
+        let result = rewrite_js(PROXY, BASE, js);
+        assert_eq!(
+            result,
+            marked(r#"import a from "http://localhost:8080/proxy?url=https://example.com/app/a.js";
+import { b } from 'http://localhost:8080/proxy?url=https://example.com/b.js';
+export * from "http://localhost:8080/proxy?url=https://example.com/c.js";
+import "http://localhost:8080/proxy?url=https://cdn.example/d.js";
+import React from "react";
+const e = await import("http://localhost:8080/proxy?url=https://example.com/app/e.js");
+const f = obj.import("./f.js");
+// import g from "./g.js";"#)
+        );
+    }
+
+    #[test]
+    fn keeps_import_attributes() {
+        let js = r#"import cfg from "./c.json" with { type: "json" };
+import sheet from "./s.css" assert { type: "css" };
+const d = await import("./d.json", { with: { type: "json" } });"#;
+        let result = rewrite_js(PROXY, BASE, js);
+        assert_eq!(
+            result,
+            marked(r#"import cfg from "http://localhost:8080/proxy?url=https://example.com/app/c.json" with { type: "json" };
+import sheet from "http://localhost:8080/proxy?url=https://example.com/app/s.css" assert { type: "css" };
+const d = await import("http://localhost:8080/proxy?url=https://example.com/app/d.json", { with: { type: "json" } });"#)
+        );
+    }
+
+    #[test]
+    fn routes_tagged_templates_through_runtime() {
+        let js = r#"const t = html`<img src=${x}>`;
+const s = css `background: url(${y});`;
+const n = this.html`<a>`; const k = `plain`; // html`<b>`"#;
+        let opts = JsOptions {
+            rewrite_tagged_templates: true,
+            ..JsOptions::default()
+        };
+        let result = rewrite_js_with_options(PROXY, BASE, js, &opts);
+        assert_eq!(
+            result,
+            marked(r#"const t = __internex.tagged(html, "html")`<img src=${x}>`;
+const s = __internex.tagged(css, "css") `background: url(${y});`;
+const n = this.html`<a>`; const k = `plain`; // html`<b>`"#)
+        );
+        assert_eq!(rewrite_js_with_options(PROXY, BASE, &result, &opts), result);
+    }
+
+    #[test]
+    fn tagged_templates_left_alone_by_default() {
+        let js = "const t = html`<img src=${x}>`;";
+        assert_eq!(rewrite_js(PROXY, BASE, js), js);
+    }
+
+    #[test]
+    fn rewrites_literal_href_assignments() {
+        let js = r#"u.href = "https://other.example/x"; a.href = base + "/y"; if (u.href == "z") {} n.hrefs = "w";"#;
+        let result = rewrite_js(PROXY, BASE, js);
+        assert_eq!(
+            result,
+            marked(r#"u.href = "http://localhost:8080/proxy?url=https://other.example/x"; a.href = base + "/y"; if (__internex.unrewriteUrl(u.href) == "z") {} n.hrefs = "w";"#)
+        );
+    }
+
+    #[test]
+    fn leaves_url_search_params_alone() {
+        let js = r#"const p = new URLSearchParams("a=1&b=/x"); const u = new URL("/api", location);"#;
+        let result = rewrite_js(PROXY, BASE, js);
+        assert!(result.contains(r#"new URLSearchParams("a=1&b=/x")"#));
+        assert!(result.contains(r#"new URL("http://localhost:8080/proxy?url=https://example.com/api", location)"#));
+    }
+
+    #[test]
+    fn reads_upstream_document_and_location_values() {
+        let js = r#"const b = new URL(path, document.baseURI); if (e.origin === location.origin) go(window.location.hostname, document.URL);"#;
+        let result = rewrite_js(PROXY, BASE, js);
+        assert!(result.contains(r#"new URL(path, __internex.upstream(document, "baseURI"))"#));
+        assert!(result.contains(r#"e.origin === __internex.upstream(location, "origin")"#));
+        assert!(result.contains(r#"go(__internex.upstream(window.location, "hostname"), __internex.upstream(document, "URL"))"#));
+    }
+
+    #[test]
+    fn leaves_upstream_property_writes_and_lookalikes() {
+        let js = r#"location.host = h; mydocument.URL; a.location.origin; const s = "document.URL"; u.host;"#;
+        assert_eq!(rewrite_js(PROXY, BASE, js), js);
+    }
+
+    #[test]
+    fn form_action_properties_match_dom_casing() {
+        let js = r#"btn.formAction = "/submit"; form.action = next; const a = btn.formAction; const b = btn.formaction; const c = form.getAttribute("FormAction");"#;
+        let result = rewrite_js(PROXY, BASE, js);
+        // Writes are left to the runtime's formAction / action setters.
+        assert!(result.contains(r#"btn.formAction = "/submit";"#));
+        assert!(result.contains("form.action = next;"));
+        assert!(result.contains("const a = __internex.unrewriteUrl(btn.formAction);"));
+        // `formaction` is the attribute name, not a DOM property.
+        assert!(result.contains("const b = btn.formaction;"));
+        assert!(result.contains(r#"const c = __internex.unrewriteUrl(form.getAttribute("FormAction"));"#));
+    }
+
+    #[test]
+    fn wraps_style_css_text_assignments() {
+        let js = r#"el.style.cssText = "background:url(x)"; el.style.cssText = "color:red";"#;
+        let result = rewrite_js(PROXY, BASE, js);
+        assert!(result.contains(r#"el.style.cssText = __internex.rewriteCss("background:url(x)");"#));
+        assert!(result.contains(r#"el.style.cssText = "color:red";"#));
+    }
+
+    #[test]
+    fn rewrites_function_constructor_bodies() {
+        let js = r#"const f = new Function("x", "return x.src"); const g = Function('el', 'el.style.background = "url(a.png)";\nreturn el');"#;
+        let result = rewrite_js(PROXY, BASE, js);
+        assert!(result.contains(r#"new Function("x", "return __internex.unrewriteUrl(x.src)");"#));
+        assert!(result.contains(r#"Function('el', 'el.style.background = __internex.rewriteCss("url(a.png)");\nreturn el');"#));
+        assert!(!result.contains("rewriteCss(__internex.rewriteCss"));
+    }
+
+    #[test]
+    fn leaves_plain_function_constructor_bodies() {
+        for js in [
+            r#"const f = new Function("x", "return x+1");"#,
+            r#"const f = new Function("a", "b", "return a + b");"#,
+            r#"const f = new Function("x", "return " + "x.src");"#,
+            r#"const f = obj.Function("x", "return x.src");"#,
+        ] {
+            assert_eq!(rewrite_js(PROXY, BASE, js), js);
+        }
+    }
+
+    #[test]
+    fn rewrites_eval_code_literals() {
+        let js = r#"eval("x.src"); window.eval("x.src"); (0, eval)("x.src"); globalThis.eval('fetch("/a")');"#;
+        let result = rewrite_js(PROXY, BASE, js);
+        assert!(result.contains(r#"eval("__internex.unrewriteUrl(x.src)"); window.eval("#));
+        assert!(result.contains(r#"window.eval("__internex.unrewriteUrl(x.src)");"#));
+        assert!(result.contains(r#"(0, eval)("__internex.unrewriteUrl(x.src)");"#));
+        assert!(result.contains(r#"globalThis.eval('fetch("http://localhost:8080/proxy?url=https://example.com/a")');"#));
+
+        for js in [r#"window.eval("x");"#, r#"(0, eval)("x");"#, r#"obj.eval("x.src");"#, r#"(f, g.eval)("x.src");"#] {
+            assert_eq!(rewrite_js(PROXY, BASE, js), js);
+        }
+    }
+
+    #[test]
+    fn sink_categories_can_be_disabled() {
+        let js = r#"eval("x.src"); new WebSocket("/ws"); const s = img.src; fetch("/a");"#;
+        let result = rewrite_js(PROXY, BASE, js);
+        assert!(result.contains(r#"eval("__internex.unrewriteUrl(x.src)");"#));
+        assert!(result.contains("new WebSocket(\"http://localhost:8080/proxy?url=https://example.com/ws"));
+        assert!(result.contains("__internex.unrewriteUrl(img.src)"));
+
+        let opts = JsOptions { rewrite_eval_sinks: false, ..JsOptions::default() };
+        let result = rewrite_js_with_options(PROXY, BASE, js, &opts);
+        assert!(result.contains(r#"eval("x.src");"#));
+        assert!(result.contains("__internex.unrewriteUrl(img.src)"));
+
+        let opts = JsOptions {
+            rewrite_url_constructors: false,
+            rewrite_eval_sinks: false,
+            rewrite_dom_properties: false,
+            ..JsOptions::default()
+        };
+        let result = rewrite_js_with_options(PROXY, BASE, js, &opts);
+        assert!(result.starts_with(r#"eval("x.src"); new WebSocket("/ws"); const s = img.src; fetch("http://localhost:8080/proxy?url="#));
+    }
+
+    #[test]
+    fn wraps_multi_line_assignment_values() {
+        let js = "el.dataset.src = cond\n  ? a\n  : b;\nel.style.background = base +\n  suffix\nnext();\nel.dataset.src = u\nother.src++";
+        assert_eq!(
+            rewrite_js(PROXY, BASE, js),
+            marked("el.dataset.src = __internex.rewriteUrl(cond\n  ? a\n  : b);\nel.style.background = __internex.rewriteCss(base +\n  suffix)\nnext();\nel.dataset.src = __internex.rewriteUrl(u)\nother.src++")
+        );
+    }
+
+    #[test]
+    fn wraps_url_dataset_assignments() {
+        let js = r#"el.dataset.src = u; el.dataset.srcset = s; el.dataset.bg = "/b.png"; el.dataset.id = "/x"; if (el.dataset.src == u) {}"#;
+        let result = rewrite_js(PROXY, BASE, js);
+        assert!(result.contains("el.dataset.src = __internex.rewriteUrl(u);"));
+        assert!(result.contains("el.dataset.srcset = __internex.rewriteSrcset(s);"));
+        assert!(result.contains(r#"el.dataset.bg = __internex.rewriteUrl("/b.png");"#));
+        assert!(result.contains(r#"el.dataset.id = "/x";"#));
+        assert!(result.contains("(el.dataset.src) == u"));
+
+        let opts = JsOptions {
+            data_url_attrs: vec!["data-hero-image".to_string()],
+            ..JsOptions::default()
+        };
+        let result = rewrite_js_with_options(PROXY, BASE, "a.dataset.heroImage = h; a.dataset.src = u;", &opts);
+        assert!(result.contains("a.dataset.heroImage = __internex.rewriteUrl(h);"));
+        assert!(result.contains("a.dataset.src = u;"));
+    }
+
+    #[test]
+    fn websocket_keeps_protocols_argument() {
+        let js = r#"const a = new WebSocket("wss://x/s", ["proto1", "wss://not-a-url"]); const b = new WebSocket("wss://" + host + "/s", "p");"#;
+        let result = rewrite_js(PROXY, BASE, js);
+        assert!(result.contains(r#"new WebSocket("http://localhost:8080/proxy?url=wss://x/s", ["proto1", "wss://not-a-url"])"#));
+        // Computed URLs are left to the runtime's WebSocket wrapper.
+        assert!(result.contains(r#"new WebSocket("wss://" + host + "/s", "p")"#));
+    }
+
+    #[test]
+    fn rewrites_response_redirect() {
+        let js = r#"return Response.redirect("/go", 302); res.redirect("/x"); MyResponse.redirect("/y");"#;
+        let result = rewrite_js(PROXY, BASE, js);
+        assert!(result.contains(r#"Response.redirect("http://localhost:8080/proxy?url=https://example.com/go", 302)"#));
+        assert!(result.contains(r#"res.redirect("/x")"#));
+        assert!(result.contains(r#"MyResponse.redirect("/y")"#));
+    }
+
+    #[test]
+    fn event_source_url_is_stable_across_passes() {
+        let js = r#"const es = new EventSource("/sse?topic=a&last=1", { withCredentials: true });"#;
+        let once = rewrite_js(PROXY, BASE, js);
+        assert!(once.contains(
+            r#"new EventSource("http://localhost:8080/proxy?url=https://example.com/sse?topic%3Da%26last%3D1", { withCredentials: true })"#
+        ));
+        // An already-proxied SSE URL is not wrapped again, with or without
+        // the marker that short-circuits cached output.
+        with_marker_secret(Some(SECRET.to_string()), || {
+            let marked = rewrite_js(PROXY, BASE, js);
+            let stripped = marked.trim_end_matches(&rewritten_marker(PROXY).unwrap());
+            assert_eq!(stripped, once);
+            assert_eq!(rewrite_js(PROXY, BASE, stripped), stripped);
+            assert_eq!(rewrite_js(PROXY, BASE, &marked), marked);
+        });
+    }
+
+    #[test]
+    fn wraps_cross_frame_location_assignments() {
+        let js = r#"top.location = "/x"; window.opener.location = next; parent.frames[0].location = u;"#;
+        let result = rewrite_js(PROXY, BASE, js);
+        assert!(result.contains(r#"top.location = __internex.rewriteUrl("/x");"#));
+        assert!(result.contains("window.opener.location = __internex.rewriteUrl(next);"));
+        assert!(result.contains("parent.frames[0].location = __internex.rewriteUrl(u);"));
+    }
+
+    #[test]
+    fn leaves_other_frame_accesses_alone() {
+        let js = r#"parent.postMessage({ a: 1 }, "*"); if (top.location == self.location) x = top.location; state.location = "/x";"#;
+        assert_eq!(rewrite_js(PROXY, BASE, js), js);
+    }
+
+    #[test]
+    fn wraps_history_url_argument() {
+        let js = r#"window.history.replaceState(s, t, next + "?q=1"); history.pushState({}, "", "/new"); history.pushState({ a: [1, 2] }, "x"); h.replaceState(null, "");"#;
+        let result = rewrite_js(PROXY, BASE, js);
+        assert_eq!(
+            result,
+            marked(r#"window.history.replaceState(s, t, __internex.rewriteUrl(next + "?q=1")); history.pushState({}, "", __internex.rewriteUrl("/new")); history.pushState({ a: [1, 2] }, "x"); h.replaceState(null, "");"#)
+        );
+    }
+
+    #[test]
+    fn worker_blob_urls_pass_through() {
+        let js = r#"new Worker(URL.createObjectURL(b)); new SharedWorker("blob:http://localhost:8080/1f2e"); new Worker("/w.js", { type: "module" });"#;
+        let result = rewrite_js(PROXY, BASE, js);
+        assert_eq!(
+            result,
+            marked(r#"new Worker(URL.createObjectURL(b)); new SharedWorker("blob:http://localhost:8080/1f2e"); new Worker("http://localhost:8080/proxy?url=https://example.com/w.js", { type: "module" });"#)
+        );
+    }
+
+    #[test]
+    fn rewrites_web_transport_urls() {
+        let js = r#"const wt = new WebTransport("https://x/wt", { allowPooling: true });"#;
+        assert_eq!(
+            rewrite_js(PROXY, BASE, js),
+            marked(r#"const wt = new WebTransport("http://localhost:8080/proxy?url=https://x/wt", { allowPooling: true });"#)
+        );
+    }
+
+    #[test]
+    fn fetch_of_request_is_rewritten_once() {
+        let js = r#"fetch(new Request("/a", { method: "POST" }));"#;
+        assert_eq!(
+            rewrite_js(PROXY, BASE, js),
+            marked(r#"fetch(new Request("http://localhost:8080/proxy?url=https://example.com/a", { method: "POST" }));"#)
+        );
+    }
+
+    #[test]
+    fn keeps_bom_and_directive_prologue_first() {
+        let js = "\u{FEFF}\"use strict\";\nfetch(\"/a\");";
+        assert_eq!(
+            rewrite_js(PROXY, BASE, js),
+            marked("\u{FEFF}\"use strict\";\nfetch(\"http://localhost:8080/proxy?url=https://example.com/a\");")
+        );
+    }
+}
@@ -0,0 +1,240 @@
+// internex_rewriter::json
+//
+// JSON rewriter for REST / GraphQL responses.  SPAs routinely fetch JSON
+// whose string fields hold absolute URLs (image CDNs, pagination links,
+// media manifests); left alone, the app would load those straight from the
+// upstream.
+//
+// Rewriting is opt-in per response: the server only calls in here for
+// endpoints known to carry URLs, optionally restricted to a key allowlist.
+// Only string values that parse as absolute http(s) URLs are touched.
+
+use serde::de::IgnoredAny;
+use url::Url;
+
+use crate::url::encode_url_with_base;
+
+/// Rewrite every absolute http(s) URL string in a JSON document.
+///
+/// Invalid JSON is returned unchanged.
+pub fn rewrite_json(proxy_origin: &str, base_url: &str, json: &str) -> String {
+    rewrite_json_inner(proxy_origin, base_url, json, None)
+}
+
+/// Like [`rewrite_json`], but only string values stored under one of `keys`
+/// (at any depth, including arrays directly under such a key) are rewritten.
+pub fn rewrite_json_with_keys(
+    proxy_origin: &str,
+    base_url: &str,
+    json: &str,
+    keys: &[&str],
+) -> String {
+    rewrite_json_inner(proxy_origin, base_url, json, Some(keys))
+}
+
+fn rewrite_json_inner(
+    proxy_origin: &str,
+    base_url: &str,
+    json: &str,
+    keys: Option<&[&str]>,
+) -> String {
+    if serde_json::from_str::<IgnoredAny>(json).is_err() {
+        return json.to_string();
+    }
+
+    // Only the rewritten string literals are replaced; key order, number
+    // spellings and whitespace stay as the upstream wrote them.
+    let mut walker = Walker { src: json, proxy: proxy_origin, base: base_url, keys, edits: Vec::new() };
+    // Without an allowlist every string is in scope.
+    walker.value(0, keys.is_none());
+
+    let mut out = String::with_capacity(json.len());
+    let mut copied = 0;
+    for (start, end, literal) in walker.edits {
+        out.push_str(&json[copied..start]);
+        out.push_str(&literal);
+        copied = end;
+    }
+    out.push_str(&json[copied..]);
+    out
+}
+
+/// Walks a document already known to be valid JSON, recording the string
+/// literals to replace.
+struct Walker<'a> {
+    src: &'a str,
+    proxy: &'a str,
+    base: &'a str,
+    keys: Option<&'a [&'a str]>,
+    /// `(start, end, replacement)` byte ranges of string literals, in order.
+    edits: Vec<(usize, usize, String)>,
+}
+
+impl Walker<'_> {
+    /// Walk the value starting at or after `i`; returns the index past it.
+    fn value(&mut self, i: usize, in_scope: bool) -> usize {
+        let bytes = self.src.as_bytes();
+        let i = self.skip_whitespace(i);
+        match bytes.get(i) {
+            Some(b'"') => {
+                let end = self.string_end(i);
+                if in_scope {
+                    self.rewrite_string(i, end);
+                }
+                end
+            }
+            Some(b'{') => {
+                let mut j = self.skip_whitespace(i + 1);
+                while bytes.get(j) == Some(&b'"') {
+                    let key_end = self.string_end(j);
+                    let child_in_scope = match self.keys {
+                        Some(keys) => self.string_at(j, key_end).is_some_and(|k| keys.contains(&k.as_str())),
+                        None => true,
+                    };
+                    // Past the `:`.
+                    let colon = self.skip_whitespace(key_end);
+                    j = self.value(colon + 1, child_in_scope);
+                    j = self.skip_whitespace(j);
+                    if bytes.get(j) == Some(&b',') {
+                        j = self.skip_whitespace(j + 1);
+                    }
+                }
+                j + 1
+            }
+            Some(b'[') => {
+                let mut j = self.skip_whitespace(i + 1);
+                while j < bytes.len() && bytes[j] != b']' {
+                    let end = self.value(j, in_scope);
+                    j = self.skip_whitespace(end);
+                    if bytes.get(j) == Some(&b',') {
+                        j += 1;
+                    }
+                }
+                j + 1
+            }
+            // Numbers, `true`, `false`, `null`.
+            _ => {
+                let len = bytes[i..]
+                    .iter()
+                    .take_while(|b| !matches!(b, b',' | b']' | b'}') && !b.is_ascii_whitespace())
+                    .count();
+                i + len
+            }
+        }
+    }
+
+    fn rewrite_string(&mut self, start: usize, end: usize) {
+        let Some(s) = self.string_at(start, end) else {
+            return;
+        };
+        if !is_absolute_http_url(&s) {
+            return;
+        }
+        let encoded = encode_url_with_base(self.proxy, self.base, &s);
+        if let Some(literal) = encoded.and_then(|e| serde_json::to_string(&e).ok()) {
+            self.edits.push((start, end, literal));
+        }
+    }
+
+    /// The decoded value of the string literal at `start..end`.
+    fn string_at(&self, start: usize, end: usize) -> Option<String> {
+        serde_json::from_str(&self.src[start..end]).ok()
+    }
+
+    /// For the string literal opening at `start`, the index past its
+    /// closing quote.
+    fn string_end(&self, start: usize) -> usize {
+        let bytes = self.src.as_bytes();
+        let mut j = start + 1;
+        while j < bytes.len() && bytes[j] != b'"' {
+            j += if bytes[j] == b'\\' { 2 } else { 1 };
+        }
+        (j + 1).min(bytes.len())
+    }
+
+    fn skip_whitespace(&self, mut i: usize) -> usize {
+        let bytes = self.src.as_bytes();
+        while bytes.get(i).is_some_and(|b| matches!(b, b' ' | b'\t' | b'\n' | b'\r')) {
+            i += 1;
+        }
+        i
+    }
+}
+
+fn is_absolute_http_url(s: &str) -> bool {
+    match Url::parse(s) {
+        Ok(u) => u.scheme() == "http" || u.scheme() == "https",
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROXY: &str = "http://localhost:8080";
+    const BASE: &str = "https://api.example.com/v1/items";
+
+    #[test]
+    fn rewrites_nested_objects() {
+        let json = r#"{"item":{"image":{"url":"https://cdn.example.com/a.png"},"title":"hi"}}"#;
+        let result = rewrite_json(PROXY, BASE, json);
+        assert_eq!(
+            result,
+            r#"{"item":{"image":{"url":"http://localhost:8080/proxy?url=https://cdn.example.com/a.png"},"title":"hi"}}"#
+        );
+    }
+
+    #[test]
+    fn rewrites_arrays_of_urls() {
+        let json = r#"{"pages":["https://example.com/1","https://example.com/2","/relative"]}"#;
+        let result = rewrite_json(PROXY, BASE, json);
+        assert_eq!(
+            result,
+            r#"{"pages":["http://localhost:8080/proxy?url=https://example.com/1","http://localhost:8080/proxy?url=https://example.com/2","/relative"]}"#
+        );
+    }
+
+    #[test]
+    fn key_allowlist_limits_rewriting() {
+        let json = r#"{"next":"https://example.com/p2","canonical":"https://example.com/"}"#;
+        let result = rewrite_json_with_keys(PROXY, BASE, json, &["next"]);
+        assert_eq!(
+            result,
+            r#"{"next":"http://localhost:8080/proxy?url=https://example.com/p2","canonical":"https://example.com/"}"#
+        );
+    }
+
+    #[test]
+    fn keeps_document_formatting() {
+        let json = "{ \"z\": 12345678901234567890123, \"a\": [1.10, 1e3, -0.0],\n  \"img\" : \"https:\\/\\/cdn.example.com\\/a.png\" }";
+        let result = rewrite_json(PROXY, BASE, json);
+        assert_eq!(
+            result,
+            "{ \"z\": 12345678901234567890123, \"a\": [1.10, 1e3, -0.0],\n  \"img\" : \"http://localhost:8080/proxy?url=https://cdn.example.com/a.png\" }"
+        );
+    }
+
+    #[test]
+    fn document_without_urls_is_returned_as_is() {
+        let json = "{\"b\": 1.50, \"a\": {\"x\": [true, null, \"/relative\"]}}";
+        assert_eq!(rewrite_json(PROXY, BASE, json), json);
+        assert_eq!(rewrite_json_with_keys(PROXY, BASE, json, &["x"]), json);
+    }
+
+    #[test]
+    fn allowlist_reaches_nested_arrays() {
+        let json = r#"[{"links":["https://example.com/1",{"href":"https://example.com/2"}],"other":"https://example.com/3"}]"#;
+        let result = rewrite_json_with_keys(PROXY, BASE, json, &["links"]);
+        assert_eq!(
+            result,
+            r#"[{"links":["http://localhost:8080/proxy?url=https://example.com/1",{"href":"https://example.com/2"}],"other":"https://example.com/3"}]"#
+        );
+    }
+
+    #[test]
+    fn invalid_json_unchanged() {
+        let json = "{not json";
+        assert_eq!(rewrite_json(PROXY, BASE, json), json);
+    }
+}
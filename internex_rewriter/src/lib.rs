@@ -5,25 +5,58 @@
 //
 // Exposed functions:
 //   rewrite_html(input: *const c_char) -> *mut c_char
+//   rewrite_html_resources(input: *const c_char) -> *mut c_char
+//   rewrite_html_bytes(input: *const c_char, content: *const u8, len: usize, out_len: *mut usize) -> *mut u8
 //   rewrite_css(input: *const c_char) -> *mut c_char
+//   rewrite_css_bytes(input: *const c_char, content: *const u8, len: usize, out_len: *mut usize) -> *mut u8
 //   rewrite_js(input: *const c_char) -> *mut c_char
+//   rewrite_js_bytes(input: *const c_char, content: *const u8, len: usize, out_len: *mut usize) -> *mut u8
+//   rewrite_json(input: *const c_char) -> *mut c_char
+//   rewrite_hls(input: *const c_char) -> *mut c_char
+//   rewrite_dash(input: *const c_char) -> *mut c_char
+//   rewrite_appcache(input: *const c_char) -> *mut c_char
+//   rewrite_vtt(input: *const c_char) -> *mut c_char
+//   rewrite_with_blobs(input: *const c_char) -> *mut c_char
+//   rewrite_url(input: *const c_char) -> *mut c_char
+//   decode_url_ffi(input: *const c_char) -> *mut c_char
+//   analyze(input: *const c_char) -> *mut c_char
+//   internex_version() -> *mut c_char
 //
 // Input is a JSON-encoded object:
 //   { "proxy_origin": "…", "base_url": "…", "content": "…" }
+// plus an optional `config` object (see `config::Config`) and optional
+// per-function settings documented on each export, which take precedence.
+// Every rewrite also takes an optional top-level `marker_secret`, the
+// deployment secret that keys the "already rewritten" markers; without one
+// no markers are written and every pass rewrites in full.
 //
 // Return value is a NUL-terminated C string allocated with CString.
 // The caller MUST free it by calling `free_string`.
+//
+// The `*_bytes` variants take the content out of band as a pointer+length,
+// so compressed-then-decompressed bodies in any charset and bodies with
+// interior NULs cross the boundary unchanged.  The envelope then carries only
+// `proxy_origin`, `base_url`, an optional `charset` and `marker_secret`.
+// They return a UTF-8 buffer whose length is written to `out_len`; the
+// caller MUST free it by calling `free_bytes` with that length.
 
 pub mod url;
+pub mod config;
+pub mod analyze;
 pub mod csp;
 pub mod html;
 pub mod css;
 pub mod js;
+pub mod headers;
+pub mod json;
+pub mod manifest;
+mod digest;
 
 use std::ffi::{CStr, CString};
 use std::os::raw::c_char;
 use std::ptr;
 
+use encoding_rs::{Encoding, UTF_8};
 use serde_json::Value;
 
 // ---------------------------------------------------------------------------
@@ -39,14 +72,105 @@ fn parse_input(json: &str) -> Option<(String, String, String)> {
     Some((proxy_origin, base_url, content))
 }
 
-/// Convert a Rust String into a heap-allocated C string.
+/// Read an optional array-of-strings field from the JSON envelope.
+fn parse_string_list(v: &Value, field: &str) -> Option<Vec<String>> {
+    let items = v.get(field)?.as_array()?;
+    Some(
+        items
+            .iter()
+            .filter_map(|item| item.as_str().map(str::to_string))
+            .collect(),
+    )
+}
+
+/// Read the settings from the JSON envelope: its optional `config` object,
+/// then the top-level per-function fields, which win.  Absent or mistyped
+/// fields keep their defaults.
+fn parse_config(json: &str) -> config::Config {
+    let v: Value = match serde_json::from_str(json) {
+        Ok(v) => v,
+        Err(_) => return config::Config::default(),
+    };
+    let mut config = config::Config::from_envelope(&v).unwrap_or_default();
+    let flag = |field: &str| v.get(field).and_then(Value::as_bool);
+    if let Some(on) = flag("rewrite_inline_scripts") {
+        config.html.rewrite_inline_scripts = on;
+    }
+    if let Some(on) = flag("rewrite_inline_styles") {
+        config.html.rewrite_inline_styles = on;
+    }
+    if let Some(on) = flag("rewrite_event_handlers") {
+        config.html.rewrite_event_handlers = on;
+    }
+    if let Some(csp) = v.get("csp").and_then(Value::as_str) {
+        config.html.style_nonce = csp::extract_style_nonce(csp);
+    }
+    if let Some(keys) = parse_string_list(&v, "keys") {
+        config.json_keys = Some(keys);
+    }
+    if let Some(max) = v.get("max_data_len").and_then(Value::as_u64) {
+        config.max_data_len = Some(max as usize);
+    }
+    if let Some(secret) = v.get("marker_secret").and_then(Value::as_str) {
+        config.marker_secret = Some(secret.to_string());
+    }
+    config
+}
+
+/// Parse the content-less envelope of a `*_bytes` call and return
+/// (proxy_origin, base_url, charset).
+fn parse_bytes_input(json: &str) -> Option<(String, String, Option<String>)> {
+    let v: Value = serde_json::from_str(json).ok()?;
+    let proxy_origin = v.get("proxy_origin")?.as_str()?.to_string();
+    let base_url = v.get("base_url")?.as_str()?.to_string();
+    let charset = v.get("charset").and_then(Value::as_str).map(str::to_string);
+    Some((proxy_origin, base_url, charset))
+}
+
+/// Decode CSS / JS bytes: a BOM wins, then the `charset` label, then UTF-8.
+/// Malformed sequences become U+FFFD rather than failing the rewrite.  The
+/// BOM itself is dropped, since the output is always UTF-8; the string
+/// entry points, given already-decoded text, keep a leading U+FEFF as is.
+fn decode_text(bytes: &[u8], charset: Option<&str>) -> String {
+    let encoding = charset
+        .and_then(|c| Encoding::for_label(c.trim().as_bytes()))
+        .unwrap_or(UTF_8);
+    let (text, _, _) = encoding.decode(bytes);
+    text.into_owned()
+}
+
+/// Convert a Rust String into a heap-allocated C string.  Interior NULs are
+/// replaced with U+FFFD so one stray byte cannot null out the whole result;
+/// use a `*_bytes` function where the exact bytes matter.
 fn to_c_string(s: String) -> *mut c_char {
+    let s = if s.contains('\0') {
+        s.replace('\0', "\u{FFFD}")
+    } else {
+        s
+    };
     match CString::new(s) {
         Ok(cs) => cs.into_raw(),
         Err(_) => ptr::null_mut(),
     }
 }
 
+/// Hand a Rust String to the caller as a length-prefixed byte buffer, writing
+/// the length to `out_len`.  Unlike [`to_c_string`] this never fails on
+/// interior NULs.
+unsafe fn to_byte_buffer(s: String, out_len: *mut usize) -> *mut u8 {
+    let buf = s.into_bytes().into_boxed_slice();
+    *out_len = buf.len();
+    Box::into_raw(buf) as *mut u8
+}
+
+/// Read a `*const u8` + length into a byte slice.  Returns `None` on null.
+unsafe fn read_bytes<'a>(ptr: *const u8, len: usize) -> Option<&'a [u8]> {
+    if ptr.is_null() {
+        return None;
+    }
+    Some(std::slice::from_raw_parts(ptr, len))
+}
+
 /// Read a `*const c_char` into a `&str`.  Returns `None` on null or invalid
 /// UTF-8.
 unsafe fn read_c_str<'a>(ptr: *const c_char) -> Option<&'a str> {
@@ -62,67 +186,862 @@ unsafe fn read_c_str<'a>(ptr: *const c_char) -> Option<&'a str> {
 
 /// Rewrite an HTML document.
 ///
-/// Input: JSON `{ "proxy_origin": "…", "base_url": "…", "content": "…" }`
+/// Input: JSON `{ "proxy_origin": "…", "base_url": "…", "content": "…" }`,
+/// optionally with `rewrite_inline_scripts`, `rewrite_inline_styles` and
+/// `rewrite_event_handlers` booleans (all default `true`) to leave those
+/// inline transforms to a client-side runtime (URL attributes are always
+/// rewritten), and the upstream `csp` header, whose style nonce is stamped
+/// onto rewritten `<style>` elements.
 /// Returns: rewritten HTML as a NUL-terminated C string, or null on error.
+///
+/// # Safety
+/// `input` must be null or point to a valid NUL-terminated C string.
 #[no_mangle]
 pub unsafe extern "C" fn rewrite_html(input: *const c_char) -> *mut c_char {
     let json = match read_c_str(input) {
         Some(s) => s,
         None => return ptr::null_mut(),
     };
-    let (proxy_origin, base_url, content) = match parse_input(json) {
+    let (_, base_url, content) = match parse_input(json) {
         Some(t) => t,
         None => return ptr::null_mut(),
     };
 
-    let result = html::rewrite_html(&proxy_origin, &base_url, &content);
+    let result = parse_config(json).rewrite_html(&base_url, &content);
     to_c_string(result)
 }
 
+/// Rewrite an HTML document and report the sub-resources it loads and how
+/// many URLs were rewritten.
+///
+/// Input: JSON `{ "proxy_origin": "…", "base_url": "…", "content": "…" }`,
+/// with the same optional settings as [`rewrite_html`].
+/// Returns: JSON `{ "html": "…", "resources": [{ "url": "…", "kind": "…" }],
+/// "urls_rewritten": n }` as a NUL-terminated C string, or null on error.
+/// `url` is the original, pre-proxy absolute URL; `kind` is one of
+/// `script`, `style`, `image`, `font`, `media`, `frame`, `other`.
+///
+/// # Safety
+/// `input` must be null or point to a valid NUL-terminated C string.
+#[no_mangle]
+pub unsafe extern "C" fn rewrite_html_resources(input: *const c_char) -> *mut c_char {
+    let json = match read_c_str(input) {
+        Some(s) => s,
+        None => return ptr::null_mut(),
+    };
+    let (_, base_url, content) = match parse_input(json) {
+        Some(t) => t,
+        None => return ptr::null_mut(),
+    };
+
+    let rewritten = parse_config(json).rewrite_html_with_resources(&base_url, &content);
+    let result = serde_json::json!({
+        "html": rewritten.html,
+        "resources": rewritten.resources,
+        "urls_rewritten": rewritten.urls_rewritten,
+    });
+    to_c_string(result.to_string())
+}
+
+/// Rewrite an HTML document supplied as raw bytes in any charset.
+///
+/// Input: JSON `{ "proxy_origin": "…", "base_url": "…", "charset": "…" }`,
+/// where the optional `charset` is e.g. the `Content-Type` charset, plus the
+/// document bytes in `content` / `len`.
+/// Returns: rewritten HTML, always UTF-8, as a byte buffer whose length is
+/// written to `out_len`, or null on error.  Free with `free_bytes`.
+///
+/// # Safety
+/// `input` must be null or point to a valid NUL-terminated C string.
+/// `content` must be null or point to `len` readable bytes.
+/// `out_len` must be null or point to a writable `usize`.
+#[no_mangle]
+pub unsafe extern "C" fn rewrite_html_bytes(
+    input: *const c_char,
+    content: *const u8,
+    len: usize,
+    out_len: *mut usize,
+) -> *mut u8 {
+    let json = match read_c_str(input) {
+        Some(s) => s,
+        None => return ptr::null_mut(),
+    };
+    let (_, base_url, charset) = match parse_bytes_input(json) {
+        Some(t) => t,
+        None => return ptr::null_mut(),
+    };
+    let bytes = match read_bytes(content, len) {
+        Some(b) if !out_len.is_null() => b,
+        _ => return ptr::null_mut(),
+    };
+
+    let result = parse_config(json).rewrite_html_bytes(&base_url, bytes, charset.as_deref());
+    to_byte_buffer(result, out_len)
+}
+
 /// Rewrite a CSS stylesheet / fragment.
 ///
 /// Input: JSON `{ "proxy_origin": "…", "base_url": "…", "content": "…" }`
 /// Returns: rewritten CSS as a NUL-terminated C string, or null on error.
+///
+/// # Safety
+/// `input` must be null or point to a valid NUL-terminated C string.
 #[no_mangle]
 pub unsafe extern "C" fn rewrite_css(input: *const c_char) -> *mut c_char {
     let json = match read_c_str(input) {
         Some(s) => s,
         None => return ptr::null_mut(),
     };
-    let (proxy_origin, base_url, content) = match parse_input(json) {
+    let (_, base_url, content) = match parse_input(json) {
         Some(t) => t,
         None => return ptr::null_mut(),
     };
 
-    let result = css::rewrite_css(&proxy_origin, &base_url, &content);
+    let result = parse_config(json).rewrite_css(&base_url, &content);
     to_c_string(result)
 }
 
+/// Rewrite a CSS stylesheet supplied as raw bytes.
+///
+/// Input: JSON `{ "proxy_origin": "…", "base_url": "…", "charset": "…" }`,
+/// where the optional `charset` defaults to UTF-8, plus the stylesheet bytes in
+/// `content` / `len`.
+/// Returns: rewritten CSS, always UTF-8, as a byte buffer whose length is
+/// written to `out_len`, or null on error.  Free with `free_bytes`.
+///
+/// # Safety
+/// `input` must be null or point to a valid NUL-terminated C string.
+/// `content` must be null or point to `len` readable bytes.
+/// `out_len` must be null or point to a writable `usize`.
+#[no_mangle]
+pub unsafe extern "C" fn rewrite_css_bytes(
+    input: *const c_char,
+    content: *const u8,
+    len: usize,
+    out_len: *mut usize,
+) -> *mut u8 {
+    let json = match read_c_str(input) {
+        Some(s) => s,
+        None => return ptr::null_mut(),
+    };
+    let (_, base_url, charset) = match parse_bytes_input(json) {
+        Some(t) => t,
+        None => return ptr::null_mut(),
+    };
+    let bytes = match read_bytes(content, len) {
+        Some(b) if !out_len.is_null() => b,
+        _ => return ptr::null_mut(),
+    };
+
+    let text = decode_text(bytes, charset.as_deref());
+    let result = parse_config(json).rewrite_css(&base_url, &text);
+    to_byte_buffer(result, out_len)
+}
+
 /// Rewrite a JavaScript source file.
 ///
 /// Input: JSON `{ "proxy_origin": "…", "base_url": "…", "content": "…" }`
 /// Returns: rewritten JS as a NUL-terminated C string, or null on error.
+///
+/// # Safety
+/// `input` must be null or point to a valid NUL-terminated C string.
 #[no_mangle]
 pub unsafe extern "C" fn rewrite_js(input: *const c_char) -> *mut c_char {
     let json = match read_c_str(input) {
         Some(s) => s,
         None => return ptr::null_mut(),
     };
-    let (proxy_origin, base_url, content) = match parse_input(json) {
+    let (_, base_url, content) = match parse_input(json) {
+        Some(t) => t,
+        None => return ptr::null_mut(),
+    };
+
+    let result = parse_config(json).rewrite_js(&base_url, &content);
+    to_c_string(result)
+}
+
+/// Rewrite a JavaScript source file supplied as raw bytes.
+///
+/// Input: JSON `{ "proxy_origin": "…", "base_url": "…", "charset": "…" }`,
+/// where the optional `charset` defaults to UTF-8, plus the script bytes in
+/// `content` / `len`.
+/// Returns: rewritten JS, always UTF-8, as a byte buffer whose length is
+/// written to `out_len`, or null on error.  Free with `free_bytes`.
+///
+/// # Safety
+/// `input` must be null or point to a valid NUL-terminated C string.
+/// `content` must be null or point to `len` readable bytes.
+/// `out_len` must be null or point to a writable `usize`.
+#[no_mangle]
+pub unsafe extern "C" fn rewrite_js_bytes(
+    input: *const c_char,
+    content: *const u8,
+    len: usize,
+    out_len: *mut usize,
+) -> *mut u8 {
+    let json = match read_c_str(input) {
+        Some(s) => s,
+        None => return ptr::null_mut(),
+    };
+    let (_, base_url, charset) = match parse_bytes_input(json) {
+        Some(t) => t,
+        None => return ptr::null_mut(),
+    };
+    let bytes = match read_bytes(content, len) {
+        Some(b) if !out_len.is_null() => b,
+        _ => return ptr::null_mut(),
+    };
+
+    let text = decode_text(bytes, charset.as_deref());
+    let result = parse_config(json).rewrite_js(&base_url, &text);
+    to_byte_buffer(result, out_len)
+}
+
+/// Rewrite absolute URLs inside a JSON document.
+///
+/// Input: JSON `{ "proxy_origin": "…", "base_url": "…", "content": "…",
+/// "keys": ["…"] }` where the optional `keys` restricts rewriting to values
+/// stored under those object keys.
+/// Returns: rewritten JSON as a NUL-terminated C string, or null on error.
+///
+/// # Safety
+/// `input` must be null or point to a valid NUL-terminated C string.
+#[no_mangle]
+pub unsafe extern "C" fn rewrite_json(input: *const c_char) -> *mut c_char {
+    let json = match read_c_str(input) {
+        Some(s) => s,
+        None => return ptr::null_mut(),
+    };
+    let (_, base_url, content) = match parse_input(json) {
+        Some(t) => t,
+        None => return ptr::null_mut(),
+    };
+
+    let result = parse_config(json).rewrite_json(&base_url, &content);
+    to_c_string(result)
+}
+
+/// Rewrite an HLS (`.m3u8`) playlist.
+///
+/// Input: JSON `{ "proxy_origin": "…", "base_url": "…", "content": "…" }`
+/// Returns: rewritten playlist as a NUL-terminated C string, or null on error.
+///
+/// # Safety
+/// `input` must be null or point to a valid NUL-terminated C string.
+#[no_mangle]
+pub unsafe extern "C" fn rewrite_hls(input: *const c_char) -> *mut c_char {
+    let json = match read_c_str(input) {
+        Some(s) => s,
+        None => return ptr::null_mut(),
+    };
+    let (_, base_url, content) = match parse_input(json) {
+        Some(t) => t,
+        None => return ptr::null_mut(),
+    };
+
+    let result = parse_config(json).rewrite_hls(&base_url, &content);
+    to_c_string(result)
+}
+
+/// Rewrite a DASH (`.mpd`) manifest.
+///
+/// Input: JSON `{ "proxy_origin": "…", "base_url": "…", "content": "…" }`
+/// Returns: rewritten manifest as a NUL-terminated C string, or null on error.
+///
+/// # Safety
+/// `input` must be null or point to a valid NUL-terminated C string.
+#[no_mangle]
+pub unsafe extern "C" fn rewrite_dash(input: *const c_char) -> *mut c_char {
+    let json = match read_c_str(input) {
+        Some(s) => s,
+        None => return ptr::null_mut(),
+    };
+    let (_, base_url, content) = match parse_input(json) {
+        Some(t) => t,
+        None => return ptr::null_mut(),
+    };
+
+    let result = parse_config(json).rewrite_dash(&base_url, &content);
+    to_c_string(result)
+}
+
+/// Rewrite an AppCache (`text/cache-manifest`) manifest.
+///
+/// Input: JSON `{ "proxy_origin": "…", "base_url": "…", "content": "…" }`
+/// Returns: rewritten manifest as a NUL-terminated C string, or null on error.
+///
+/// # Safety
+/// `input` must be null or point to a valid NUL-terminated C string.
+#[no_mangle]
+pub unsafe extern "C" fn rewrite_appcache(input: *const c_char) -> *mut c_char {
+    let json = match read_c_str(input) {
+        Some(s) => s,
+        None => return ptr::null_mut(),
+    };
+    let (_, base_url, content) = match parse_input(json) {
         Some(t) => t,
         None => return ptr::null_mut(),
     };
 
-    let result = js::rewrite_js(&proxy_origin, &base_url, &content);
+    let result = parse_config(json).rewrite_appcache_manifest(&base_url, &content);
     to_c_string(result)
 }
 
+/// Rewrite a WebVTT (`.vtt`) text track.
+///
+/// Input: JSON `{ "proxy_origin": "…", "base_url": "…", "content": "…" }`
+/// Returns: rewritten track as a NUL-terminated C string, or null on error.
+///
+/// # Safety
+/// `input` must be null or point to a valid NUL-terminated C string.
+#[no_mangle]
+pub unsafe extern "C" fn rewrite_vtt(input: *const c_char) -> *mut c_char {
+    let json = match read_c_str(input) {
+        Some(s) => s,
+        None => return ptr::null_mut(),
+    };
+    let (_, base_url, content) = match parse_input(json) {
+        Some(t) => t,
+        None => return ptr::null_mut(),
+    };
+
+    let result = parse_config(json).rewrite_vtt(&base_url, &content);
+    to_c_string(result)
+}
+
+/// Rewrite a document, moving its large `data:` URLs to the blob route.
+///
+/// Input: JSON `{ "proxy_origin": "…", "base_url": "…", "content": "…",
+/// "kind": "html" | "css" | "js" | "json", "max_data_len": n }`, with the
+/// same optional settings as the per-kind export.  `data:` URLs longer than
+/// `max_data_len` bytes (which may also come from the `config` object) are
+/// extracted, see [`url::collect_blobs`].
+/// Returns: JSON `{ "content": "…", "blobs": [{ "id": "…", "mime": "…",
+/// "data": "<base64>" }] }` as a NUL-terminated C string, or null on error or
+/// an unknown `kind`.  The server stores each blob and serves it at
+/// `{proxy_origin}/blob/<id>`.
+///
+/// # Safety
+/// `input` must be null or point to a valid NUL-terminated C string.
+#[no_mangle]
+pub unsafe extern "C" fn rewrite_with_blobs(input: *const c_char) -> *mut c_char {
+    use base64::Engine;
+
+    let json = match read_c_str(input) {
+        Some(s) => s,
+        None => return ptr::null_mut(),
+    };
+    let (_, base_url, content) = match parse_input(json) {
+        Some(t) => t,
+        None => return ptr::null_mut(),
+    };
+    let kind = serde_json::from_str::<Value>(json)
+        .ok()
+        .and_then(|v| v.get("kind").and_then(Value::as_str).map(str::to_string));
+
+    let config = parse_config(json);
+    let max_data_len = config.max_data_len.unwrap_or(usize::MAX);
+    let (result, blobs) = url::collect_blobs(max_data_len, || match kind.as_deref() {
+        Some("html") => Some(config.rewrite_html(&base_url, &content)),
+        Some("css") => Some(config.rewrite_css(&base_url, &content)),
+        Some("js") => Some(config.rewrite_js(&base_url, &content)),
+        Some("json") => Some(config.rewrite_json(&base_url, &content)),
+        _ => None,
+    });
+    let result = match result {
+        Some(r) => r,
+        None => return ptr::null_mut(),
+    };
+    let blobs: Vec<Value> = blobs
+        .into_iter()
+        .map(|blob| {
+            serde_json::json!({
+                "id": blob.id,
+                "mime": blob.mime,
+                "data": base64::engine::general_purpose::STANDARD.encode(&blob.data),
+            })
+        })
+        .collect();
+    to_c_string(serde_json::json!({ "content": result, "blobs": blobs }).to_string())
+}
+
+/// Route a single URL through the proxy.
+///
+/// Input: JSON `{ "proxy_origin": "…", "base_url": "…", "url": "…" }`
+/// Returns: the proxy URL as a NUL-terminated C string, or null on error or
+/// when the URL cannot be proxied (e.g. `file:`).  `data:` and
+/// `javascript:` URLs come back unchanged.
+///
+/// # Safety
+/// `input` must be null or point to a valid NUL-terminated C string.
+#[no_mangle]
+pub unsafe extern "C" fn rewrite_url(input: *const c_char) -> *mut c_char {
+    let json = match read_c_str(input) {
+        Some(s) => s,
+        None => return ptr::null_mut(),
+    };
+    let v: Value = match serde_json::from_str(json) {
+        Ok(v) => v,
+        Err(_) => return ptr::null_mut(),
+    };
+    let (base_url, raw) = match (
+        v.get("proxy_origin").and_then(Value::as_str),
+        v.get("base_url").and_then(Value::as_str),
+        v.get("url").and_then(Value::as_str),
+    ) {
+        (Some(_), Some(b), Some(u)) => (b, u),
+        _ => return ptr::null_mut(),
+    };
+
+    let config = parse_config(json);
+    match config.encode_url(base_url, raw) {
+        Some(result) => to_c_string(result),
+        None => ptr::null_mut(),
+    }
+}
+
+/// Turn a `url=` query value back into the upstream URL.
+///
+/// Input: JSON `{ "encoded": "…", "base": "…", "proxy_origin": "…" }`; with
+/// the optional `base`, relative values are resolved against it.  With the
+/// optional `proxy_origin`, nested proxy URLs belonging to it are unwrapped
+/// (see [`url::decode_proxy_url`]).
+/// Returns: the upstream URL as a NUL-terminated C string, or null when the
+/// value does not decode to a URL.
+///
+/// # Safety
+/// `input` must be null or point to a valid NUL-terminated C string.
+#[no_mangle]
+pub unsafe extern "C" fn decode_url_ffi(input: *const c_char) -> *mut c_char {
+    let json = match read_c_str(input) {
+        Some(s) => s,
+        None => return ptr::null_mut(),
+    };
+    let v: Value = match serde_json::from_str(json) {
+        Ok(v) => v,
+        Err(_) => return ptr::null_mut(),
+    };
+    let encoded = match v.get("encoded").and_then(Value::as_str) {
+        Some(e) => e,
+        None => return ptr::null_mut(),
+    };
+
+    let proxy_origin = v.get("proxy_origin").and_then(Value::as_str);
+
+    let decoded = match (proxy_origin, v.get("base").and_then(Value::as_str)) {
+        (Some(origin), Some(base)) => url::decode_proxy_url_with_base(origin, encoded, base),
+        (Some(origin), None) => url::decode_proxy_url(origin, encoded),
+        (None, Some(base)) => url::decode_url_with_base(encoded, base),
+        (None, None) => url::decode_url(encoded),
+    };
+    match decoded {
+        Some(result) => to_c_string(result),
+        None => ptr::null_mut(),
+    }
+}
+
+/// Report what a rewrite would change, without returning the rewritten text.
+///
+/// Input: JSON `{ "proxy_origin": "…", "base_url": "…", "content": "…",
+/// "kind": "html" | "css" | "js" | "csp" }`, with the same `config` and
+/// per-function settings as the matching rewrite.  For `"csp"` the content
+/// is the header value and `base_url` the upstream page.
+/// Returns: JSON `{ "urls": ["…"], "runtime_calls": { "rewriteUrl": n },
+/// "stripped_directives": ["…"], "changed": bool }` as a NUL-terminated C
+/// string, or null on error or an unknown `kind`.  `urls` are the original,
+/// pre-proxy absolute URLs.
+///
+/// # Safety
+/// `input` must be null or point to a valid NUL-terminated C string.
+#[no_mangle]
+pub unsafe extern "C" fn analyze(input: *const c_char) -> *mut c_char {
+    let json = match read_c_str(input) {
+        Some(s) => s,
+        None => return ptr::null_mut(),
+    };
+    let (_, base_url, content) = match parse_input(json) {
+        Some(t) => t,
+        None => return ptr::null_mut(),
+    };
+    let kind = serde_json::from_str::<Value>(json)
+        .ok()
+        .and_then(|v| v.get("kind").and_then(Value::as_str).map(str::to_string));
+
+    let config = parse_config(json);
+    let report = match kind.as_deref() {
+        Some("html") => config.analyze_html(&base_url, &content),
+        Some("css") => config.analyze_css(&base_url, &content),
+        Some("js") => config.analyze_js(&base_url, &content),
+        Some("csp") => {
+            let upstream_origin = ::url::Url::parse(&base_url)
+                .map(|u| u.origin().ascii_serialization())
+                .unwrap_or_default();
+            config.analyze_csp(&upstream_origin, &content)
+        }
+        _ => return ptr::null_mut(),
+    };
+    match serde_json::to_string(&report) {
+        Ok(s) => to_c_string(s),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+/// Return the crate version as a NUL-terminated C string, e.g. `"0.1.0"`.
+///
+/// When built from a git checkout the short commit hash is appended as
+/// semver build metadata (`"0.1.0+1a2b3c4"`).  Free with `free_string`.
+#[no_mangle]
+pub extern "C" fn internex_version() -> *mut c_char {
+    to_c_string(version())
+}
+
+fn version() -> String {
+    match option_env!("INTERNEX_GIT_HASH") {
+        Some(hash) if !hash.is_empty() => format!("{}+{}", env!("CARGO_PKG_VERSION"), hash),
+        _ => env!("CARGO_PKG_VERSION").to_string(),
+    }
+}
+
 /// Free a C string previously returned by one of the rewrite_* functions.
 ///
-/// The Go side MUST call this to avoid memory leaks.
+/// The Go side MUST call this to avoid memory leaks.  Every export returns
+/// a single string (JSON for structured results), so one call frees it all.
+///
+/// # Safety
+/// `ptr` must be null or a pointer returned by this library that has not
+/// already been freed.  Anything else (a Go or `C.CString` pointer, a
+/// `*_bytes` buffer, a second free) is undefined behaviour that cannot be
+/// detected here: even reading a sentinel next to a foreign pointer would
+/// be out of bounds.
 #[no_mangle]
 pub unsafe extern "C" fn free_string(ptr: *mut c_char) {
     if !ptr.is_null() {
         let _ = CString::from_raw(ptr);
     }
 }
+
+/// Free a buffer previously returned by one of the `*_bytes` functions.
+///
+/// # Safety
+/// `ptr` must be null or a pointer returned by this library that has not
+/// already been freed, and `len` must be the length reported with it.
+#[no_mangle]
+pub unsafe extern "C" fn free_bytes(ptr: *mut u8, len: usize) {
+    if !ptr.is_null() {
+        let _ = Box::from_raw(ptr::slice_from_raw_parts_mut(ptr, len));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+
+    /// Counts live allocations per thread, so leak checks are not disturbed
+    /// by tests running in parallel.
+    struct CountingAlloc;
+
+    thread_local! {
+        static LIVE_ALLOCATIONS: Cell<isize> = const { Cell::new(0) };
+    }
+
+    unsafe impl GlobalAlloc for CountingAlloc {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let p = System.alloc(layout);
+            if !p.is_null() {
+                let _ = LIVE_ALLOCATIONS.try_with(|n| n.set(n.get() + 1));
+            }
+            p
+        }
+
+        unsafe fn dealloc(&self, p: *mut u8, layout: Layout) {
+            let _ = LIVE_ALLOCATIONS.try_with(|n| n.set(n.get() - 1));
+            System.dealloc(p, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAlloc = CountingAlloc;
+
+    fn live_allocations() -> isize {
+        LIVE_ALLOCATIONS.with(Cell::get)
+    }
+
+    #[test]
+    fn free_functions_release_results() {
+        let input = CString::new(
+            r#"{"proxy_origin":"http://localhost:8080","base_url":"https://example.com/","content":".a { background: url(a.png) }"}"#,
+        )
+        .unwrap();
+        // Warm up any lazily initialised state before counting.
+        unsafe { free_string(rewrite_css(input.as_ptr())) };
+
+        let before = live_allocations();
+        for _ in 0..16 {
+            let ptr = unsafe { rewrite_css(input.as_ptr()) };
+            assert!(!ptr.is_null());
+            unsafe { free_string(ptr) };
+
+            let mut len = 0usize;
+            let content = b".b { background: url(b.png) }";
+            let buf = unsafe { rewrite_css_bytes(ENVELOPE.as_ptr().cast(), content.as_ptr(), content.len(), &mut len) };
+            assert!(!buf.is_null());
+            unsafe { free_bytes(buf, len) };
+        }
+        assert_eq!(live_allocations(), before);
+    }
+
+    #[test]
+    fn version_is_semver() {
+        let ptr = internex_version();
+        assert!(!ptr.is_null());
+        let v = unsafe { CStr::from_ptr(ptr) }.to_str().unwrap().to_string();
+        unsafe { free_string(ptr) };
+
+        let core = v.split('+').next().unwrap();
+        let parts: Vec<&str> = core.split('.').collect();
+        assert_eq!(parts.len(), 3);
+        assert!(parts.iter().all(|p| p.parse::<u64>().is_ok()));
+    }
+
+    const ENVELOPE: &[u8] =
+        b"{\"proxy_origin\":\"http://localhost:8080\",\"base_url\":\"https://example.com/\"}\0";
+
+    fn call_bytes(
+        f: unsafe extern "C" fn(*const c_char, *const u8, usize, *mut usize) -> *mut u8,
+        content: &[u8],
+    ) -> Vec<u8> {
+        let mut len = 0usize;
+        unsafe {
+            let out = f(ENVELOPE.as_ptr().cast(), content.as_ptr(), content.len(), &mut len);
+            assert!(!out.is_null());
+            let bytes = std::slice::from_raw_parts(out, len).to_vec();
+            free_bytes(out, len);
+            bytes
+        }
+    }
+
+    #[test]
+    fn c_string_replaces_interior_nul() {
+        let ptr = to_c_string("a\0b".to_string());
+        assert!(!ptr.is_null());
+        let s = unsafe { CStr::from_ptr(ptr) }.to_str().unwrap().to_string();
+        unsafe { free_string(ptr) };
+        assert_eq!(s, "a\u{FFFD}b");
+    }
+
+    #[test]
+    fn string_api_survives_embedded_nul() {
+        let input = b"{\"proxy_origin\":\"http://localhost:8080\",\"base_url\":\"https://example.com/\",\"content\":\"fetch(\\\"/a\\\");\\u0000var x;\"}\0";
+        let ptr = unsafe { rewrite_js(input.as_ptr().cast()) };
+        assert!(!ptr.is_null());
+        let s = unsafe { CStr::from_ptr(ptr) }.to_str().unwrap().to_string();
+        unsafe { free_string(ptr) };
+        assert!(s.contains("proxy?url=https://example.com/a"));
+        assert!(s.contains("\u{FFFD}var x;"));
+    }
+
+    #[test]
+    fn bytes_api_keeps_interior_nul() {
+        let out = call_bytes(rewrite_js_bytes, b"fetch(\"/a\");\0var x;");
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("fetch(\"http://localhost:8080/proxy?url=https://example.com/a\");\0var x;"));
+    }
+
+    #[test]
+    fn bytes_api_decodes_charset() {
+        let envelope = b"{\"proxy_origin\":\"http://localhost:8080\",\"base_url\":\"https://example.com/\",\"charset\":\"latin1\"}\0";
+        let content = b"/* caf\xe9 */ a { background: url(b.png) }";
+        let mut len = 0usize;
+        let out = unsafe {
+            let out = rewrite_css_bytes(envelope.as_ptr().cast(), content.as_ptr(), content.len(), &mut len);
+            let bytes = std::slice::from_raw_parts(out, len).to_vec();
+            free_bytes(out, len);
+            bytes
+        };
+        assert!(String::from_utf8(out).unwrap().starts_with("/* café */"));
+    }
+
+    #[test]
+    fn bytes_api_strips_bom() {
+        let out = call_bytes(rewrite_css_bytes, b"\xEF\xBB\xBF@charset \"utf-8\"; a { background: url(b.png) }");
+        assert!(String::from_utf8(out).unwrap().starts_with("@charset \"utf-8\";"));
+
+        let out = call_bytes(rewrite_js_bytes, b"\xFF\xFE\"\0u\0s\0e\0 \0s\0t\0r\0i\0c\0t\0\"\0;\0");
+        assert_eq!(String::from_utf8(out).unwrap(), "\"use strict\";");
+    }
+
+    #[test]
+    fn bytes_api_rejects_null_out_len() {
+        let content = b"x";
+        let out = unsafe {
+            rewrite_js_bytes(ENVELOPE.as_ptr().cast(), content.as_ptr(), 1, ptr::null_mut())
+        };
+        assert!(out.is_null());
+    }
+
+    #[test]
+    fn html_envelope_toggles_inline_passes() {
+        let input = b"{\"proxy_origin\":\"http://localhost:8080\",\"base_url\":\"https://example.com/\",\"rewrite_inline_scripts\":false,\"content\":\"<script>go()</script><img src=\\\"/i.png\\\">\"}\0";
+        let ptr = unsafe { rewrite_html(input.as_ptr().cast()) };
+        assert!(!ptr.is_null());
+        let s = unsafe { CStr::from_ptr(ptr) }.to_str().unwrap().to_string();
+        unsafe { free_string(ptr) };
+        assert!(s.contains("<script>go()</script>"));
+        assert!(s.contains("proxy?url=https://example.com/i.png"));
+    }
+
+    fn call_string(f: unsafe extern "C" fn(*const c_char) -> *mut c_char, input: serde_json::Value) -> String {
+        let input = CString::new(input.to_string()).unwrap();
+        let ptr = unsafe { f(input.as_ptr()) };
+        assert!(!ptr.is_null());
+        let s = unsafe { CStr::from_ptr(ptr) }.to_str().unwrap().to_string();
+        unsafe { free_string(ptr) };
+        s
+    }
+
+    #[test]
+    fn envelope_config_reaches_rewriters() {
+        let js = call_string(rewrite_js, serde_json::json!({
+            "proxy_origin": "http://localhost:8080",
+            "base_url": "https://example.com/",
+            "content": "const api = \"https://api.example.com/\";",
+            "config": { "js": { "rewrite_url_literals": true } },
+        }));
+        assert!(js.contains("__internex.rewriteUrl(\"https://api.example.com/\")"));
+
+        // Top-level per-function fields win over the config object.
+        let html = call_string(rewrite_html, serde_json::json!({
+            "proxy_origin": "http://localhost:8080",
+            "base_url": "https://example.com/",
+            "content": "<script>go()</script>",
+            "config": { "html": { "rewrite_inline_scripts": false } },
+            "rewrite_inline_scripts": true,
+        }));
+        assert!(!html.contains("<script>go()</script>"));
+    }
+
+    #[test]
+    fn analyze_reports_urls_as_json() {
+        let report = call_string(analyze, serde_json::json!({
+            "proxy_origin": "http://localhost:8080",
+            "base_url": "https://example.com/",
+            "content": "fetch(\"/api\");",
+            "kind": "js",
+        }));
+        let report: Value = serde_json::from_str(&report).unwrap();
+        assert_eq!(report["urls"], serde_json::json!(["https://example.com/api"]));
+        assert_eq!(report["changed"], Value::Bool(true));
+    }
+
+    #[test]
+    fn analyze_honours_config_and_reports_csp() {
+        let report = call_string(analyze, serde_json::json!({
+            "proxy_origin": "http://localhost:8080",
+            "base_url": "https://example.com/",
+            "content": "<html><body><div data-hero=\"/h.png\"></div></body></html>",
+            "kind": "html",
+            "config": { "data_url_attrs": ["data-hero"] },
+        }));
+        let report: Value = serde_json::from_str(&report).unwrap();
+        assert_eq!(report["urls"], serde_json::json!(["https://example.com/h.png"]));
+
+        let report = call_string(analyze, serde_json::json!({
+            "proxy_origin": "http://localhost:8080",
+            "base_url": "https://example.com/page",
+            "content": "script-src https://cdn.example.com; upgrade-insecure-requests",
+            "kind": "csp",
+        }));
+        let report: Value = serde_json::from_str(&report).unwrap();
+        assert_eq!(report["stripped_directives"], serde_json::json!(["upgrade-insecure-requests"]));
+    }
+
+    #[test]
+    fn rewrite_with_blobs_returns_extracted_payloads() {
+        let result = call_string(rewrite_with_blobs, serde_json::json!({
+            "proxy_origin": "http://localhost:8080",
+            "base_url": "https://example.com/",
+            "content": "a { background: url(data:image/png;base64,aGVsbG8gd29ybGQ=) } b { background: url(data:,x) }",
+            "kind": "css",
+            "max_data_len": 16,
+        }));
+        let result: Value = serde_json::from_str(&result).unwrap();
+        let blobs = result["blobs"].as_array().unwrap();
+        assert_eq!(blobs.len(), 1);
+        assert_eq!(blobs[0]["mime"], "image/png");
+        assert_eq!(blobs[0]["data"], "aGVsbG8gd29ybGQ=");
+        let id = blobs[0]["id"].as_str().unwrap();
+        let content = result["content"].as_str().unwrap();
+        assert!(content.contains(&format!("url(http://localhost:8080/blob/{})", id)), "{}", content);
+        assert!(content.contains("url(data:,x)"));
+    }
+
+    fn call_rewrite_url(raw: &str) -> Option<String> {
+        let input = serde_json::json!({
+            "proxy_origin": "http://localhost:8080",
+            "base_url": "https://example.com/dir/page",
+            "url": raw,
+        });
+        let input = CString::new(input.to_string()).unwrap();
+        let ptr = unsafe { rewrite_url(input.as_ptr()) };
+        if ptr.is_null() {
+            return None;
+        }
+        let s = unsafe { CStr::from_ptr(ptr) }.to_str().unwrap().to_string();
+        unsafe { free_string(ptr) };
+        Some(s)
+    }
+
+    #[test]
+    fn rewrite_url_encodes_single_urls() {
+        assert_eq!(
+            call_rewrite_url("https://other.example/a?b=1").as_deref(),
+            Some("http://localhost:8080/proxy?url=https://other.example/a?b%3D1")
+        );
+        assert_eq!(
+            call_rewrite_url("../x.png").as_deref(),
+            Some("http://localhost:8080/proxy?url=https://example.com/x.png")
+        );
+        assert_eq!(call_rewrite_url("file:///etc/passwd"), None);
+        assert!(unsafe { rewrite_url(c"{}".as_ptr()) }.is_null());
+    }
+
+    fn call_decode_url(input: serde_json::Value) -> Option<String> {
+        let input = CString::new(input.to_string()).unwrap();
+        let ptr = unsafe { decode_url_ffi(input.as_ptr()) };
+        if ptr.is_null() {
+            return None;
+        }
+        let s = unsafe { CStr::from_ptr(ptr) }.to_str().unwrap().to_string();
+        unsafe { free_string(ptr) };
+        Some(s)
+    }
+
+    #[test]
+    fn decode_url_ffi_roundtrip() {
+        let encoded = call_rewrite_url("https://example.com/path?q=1").unwrap();
+        let query = encoded.split("url=").nth(1).unwrap();
+        assert_eq!(
+            call_decode_url(serde_json::json!({ "encoded": query })).as_deref(),
+            Some("https://example.com/path?q=1")
+        );
+        assert_eq!(
+            call_decode_url(serde_json::json!({ "encoded": "a%2Fb.png", "base": "https://example.com/x/" })).as_deref(),
+            Some("https://example.com/x/a/b.png")
+        );
+        assert_eq!(call_decode_url(serde_json::json!({ "encoded": "a%2Fb.png" })), None);
+        let foreign = "https%3A%2F%2Fother.site%2Fproxy%3Furl%3Dhttps://x/";
+        assert_eq!(
+            call_decode_url(serde_json::json!({ "encoded": foreign, "proxy_origin": "http://localhost:8080" })).as_deref(),
+            Some("https://other.site/proxy?url=https://x/")
+        );
+        let nested = "%2Fproxy%3Furl%3Dhttps://x/";
+        assert_eq!(
+            call_decode_url(serde_json::json!({ "encoded": nested, "proxy_origin": "http://localhost:8080" })).as_deref(),
+            Some("https://x/")
+        );
+        assert_eq!(call_decode_url(serde_json::json!({ "encoded": nested })), None);
+        assert_eq!(call_decode_url(serde_json::json!({})), None);
+    }
+}
@@ -0,0 +1,503 @@
+// internex_rewriter::manifest
+//
+// Streaming-manifest rewriter.  Proxying the manifest URL alone is not
+// enough: the segment and variant URIs listed inside point at the real CDN.
+//
+//   HLS   (.m3u8)  every non-comment URI line, plus URI="…" attributes in
+//                  EXT-X tags (KEY, MAP, MEDIA, I-FRAME-STREAM-INF, …)
+//   DASH  (.mpd)   <BaseURL> text, and the media / initialization /
+//                  sourceURL attributes of segment templates and lists
+//   AppCache       URL lines of the CACHE / NETWORK / FALLBACK sections
+//                  of a (deprecated) `text/cache-manifest`
+//   WebVTT (.vtt)  image cue payloads (thumbnail sprites) and url()s in
+//                  STYLE blocks
+//
+// HLS, AppCache and WebVTT are rewritten line by line; DASH is scanned as
+// text for those elements and attributes rather than parsed as XML.  All
+// four leave everything else byte-for-byte.
+
+use url::Url;
+
+use crate::css::rewrite_css_string;
+use crate::url::encode_url_with_base;
+
+// ---------------------------------------------------------------------------
+// HLS
+// ---------------------------------------------------------------------------
+
+/// Rewrite an HLS playlist (master or media) so every URI routes through
+/// the proxy.  `base_url` is the playlist's own URL.
+pub fn rewrite_hls(proxy_origin: &str, base_url: &str, text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for line in text.split_inclusive('\n') {
+        let body = line.trim_end_matches(['\r', '\n']);
+        let ending = &line[body.len()..];
+
+        if body.trim().is_empty() {
+            out.push_str(body);
+        } else if body.starts_with('#') {
+            out.push_str(&rewrite_uri_attrs(proxy_origin, base_url, body));
+        } else {
+            out.push_str(&encode(proxy_origin, base_url, body.trim()));
+        }
+        out.push_str(ending);
+    }
+    out
+}
+
+/// Rewrite `URI="…"` attributes inside an EXT-X tag line.
+fn rewrite_uri_attrs(proxy: &str, base: &str, line: &str) -> String {
+    let mut out = String::with_capacity(line.len());
+    let mut rest = line;
+    while let Some(pos) = rest.find("URI=\"") {
+        let value_start = pos + 5;
+        let value_end = match rest[value_start..].find('"') {
+            Some(end) => value_start + end,
+            None => break,
+        };
+        out.push_str(&rest[..value_start]);
+        out.push_str(&encode(proxy, base, &rest[value_start..value_end]));
+        rest = &rest[value_end..];
+    }
+    out.push_str(rest);
+    out
+}
+
+// ---------------------------------------------------------------------------
+// DASH
+// ---------------------------------------------------------------------------
+
+/// DASH attributes whose value is a (possibly templated) segment URL.
+const DASH_URL_ATTRS: &[&str] = &[" media=\"", " initialization=\"", " sourceURL=\""];
+
+/// Rewrite a DASH MPD so `<BaseURL>` and segment URLs route through the
+/// proxy.  `base_url` is the MPD's own URL.
+///
+/// A `<BaseURL>` applies to its parent element (MPD, Period, AdaptationSet,
+/// Representation) and that element's descendants only, resolved against
+/// the base inherited from further up; further `<BaseURL>` siblings are
+/// alternatives, not nested paths.  Segment templates are resolved to
+/// absolute URLs before encoding, since a relative template can no longer
+/// resolve against a proxied `<BaseURL>`.  `$Number$`-style identifiers,
+/// format tags included (`$Number%05d$`), survive encoding so the player
+/// can still substitute them.
+pub fn rewrite_dash(proxy_origin: &str, base_url: &str, xml: &str) -> String {
+    /// An open element: the base it inherited and the one it applies.
+    struct Scope<'a> {
+        name: &'a str,
+        inherited: String,
+        base: Option<String>,
+    }
+
+    let root = Scope { name: "", inherited: base_url.to_string(), base: None };
+    let mut scopes = vec![root];
+    let mut out = String::with_capacity(xml.len());
+    let mut copied = 0;
+    let mut i = 0;
+
+    while let Some(pos) = xml[i..].find('<') {
+        let lt = i + pos;
+        let rest = &xml[lt..];
+        let skip_to = |end: &str| rest.find(end).map_or(xml.len(), |p| lt + p + end.len());
+        if rest.starts_with("<!--") {
+            i = skip_to("-->");
+            continue;
+        }
+        if rest.starts_with("<![CDATA[") {
+            i = skip_to("]]>");
+            continue;
+        }
+        let Some(gt) = rest.find('>').map(|p| lt + p) else {
+            break;
+        };
+        i = gt + 1;
+
+        if let Some(name) = xml[lt + 1..gt].strip_prefix('/') {
+            let name = name.trim();
+            if let Some(open) = scopes.iter().rposition(|s| s.name == name) {
+                scopes.truncate(open.max(1));
+            }
+            continue;
+        }
+        if rest.starts_with("<?") || rest.starts_with("<!") {
+            continue;
+        }
+
+        let tag = &xml[lt + 1..gt];
+        let name_len = tag.find(|c: char| c.is_ascii_whitespace() || c == '/').unwrap_or(tag.len());
+        let name = &tag[..name_len];
+        let scope = scopes.last().expect("root scope");
+        let current = scope.base.clone().unwrap_or_else(|| scope.inherited.clone());
+
+        if name == "BaseURL" {
+            let text_end = match xml[i..].find("</BaseURL>") {
+                Some(end) => i + end,
+                None => break,
+            };
+            let raw = unescape_xml(xml[i..text_end].trim());
+            let resolved = resolve(&scope.inherited, &raw).unwrap_or_else(|| scope.inherited.clone());
+            out.push_str(&xml[copied..i]);
+            out.push_str(&escape_xml(&encode(proxy_origin, &resolved, &resolved)));
+            copied = text_end;
+            i = text_end;
+            let scope = scopes.last_mut().expect("root scope");
+            scope.base.get_or_insert(resolved);
+            continue;
+        }
+
+        out.push_str(&xml[copied..lt]);
+        out.push_str(&rewrite_dash_url_attrs(proxy_origin, &current, &xml[lt..=gt]));
+        copied = gt + 1;
+        if !tag.ends_with('/') {
+            scopes.push(Scope { name, inherited: current, base: None });
+        }
+    }
+
+    out.push_str(&xml[copied..]);
+    out
+}
+
+/// Rewrite the segment URL attributes in one start tag.
+fn rewrite_dash_url_attrs(proxy: &str, base: &str, tag: &str) -> String {
+    let mut out = String::with_capacity(tag.len());
+    let mut rest = tag;
+    loop {
+        let next = DASH_URL_ATTRS
+            .iter()
+            .filter_map(|a| rest.find(a).map(|p| p + a.len()))
+            .min();
+        let Some(value_start) = next else {
+            break;
+        };
+        let Some(value_end) = rest[value_start..].find('"').map(|e| value_start + e) else {
+            break;
+        };
+        let raw = unescape_xml(&rest[value_start..value_end]);
+        out.push_str(&rest[..value_start]);
+        out.push_str(&escape_xml(&keep_template_identifiers(&encode(proxy, base, &raw))));
+        rest = &rest[value_end..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Undo the percent-encoding of `%` inside `$…$` template identifiers, so
+/// width tags like `$Number%05d$` reach the player intact.
+fn keep_template_identifiers(encoded: &str) -> String {
+    let parts: Vec<&str> = encoded.split('$').collect();
+    let closed = parts.len() - (parts.len() + 1) % 2;
+    parts
+        .iter()
+        .enumerate()
+        .map(|(idx, part)| {
+            if idx % 2 == 1 && idx < closed {
+                part.replace("%25", "%")
+            } else {
+                part.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("$")
+}
+
+// ---------------------------------------------------------------------------
+// AppCache
+// ---------------------------------------------------------------------------
+
+/// Rewrite an AppCache manifest so every listed URL routes through the
+/// proxy.  `base_url` is the manifest's own URL.
+///
+/// The `CACHE MANIFEST` signature, section headers, comments, the `*`
+/// network wildcard, and `SETTINGS:` entries are kept as-is; `FALLBACK:`
+/// lines have both their namespace and fallback URL rewritten.
+pub fn rewrite_appcache_manifest(proxy_origin: &str, base_url: &str, text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut section = "CACHE";
+    for (idx, line) in text.split_inclusive('\n').enumerate() {
+        let body = line.trim_end_matches(['\r', '\n']);
+        let ending = &line[body.len()..];
+        let trimmed = body.trim();
+
+        if idx == 0 || trimmed.is_empty() || trimmed.starts_with('#') || trimmed == "*" {
+            out.push_str(body);
+        } else if let Some(name) = trimmed.strip_suffix(':') {
+            section = match name {
+                "CACHE" | "NETWORK" | "FALLBACK" | "SETTINGS" => name,
+                _ => "UNKNOWN",
+            };
+            out.push_str(body);
+        } else if section == "SETTINGS" || section == "UNKNOWN" {
+            out.push_str(body);
+        } else {
+            let urls: Vec<String> = trimmed
+                .split_ascii_whitespace()
+                .map(|url| encode(proxy_origin, base_url, url))
+                .collect();
+            out.push_str(&urls.join(" "));
+        }
+        out.push_str(ending);
+    }
+    out
+}
+
+// ---------------------------------------------------------------------------
+// WebVTT
+// ---------------------------------------------------------------------------
+
+/// Image extensions recognised in relative cue payloads.
+const VTT_IMAGE_EXTENSIONS: &[&str] = &[".jpg", ".jpeg", ".png", ".gif", ".webp", ".avif", ".svg"];
+
+/// Rewrite a WebVTT file so the URLs it references route through the
+/// proxy.  `base_url` is the file's own URL.
+///
+/// Thumbnail tracks use cues whose whole payload is an image URL, usually
+/// with a sprite fragment (`sprite.jpg#xywh=0,0,160,90`); those are
+/// rewritten, keeping the fragment.  `STYLE` blocks go through the CSS rewriter.  Ordinary
+/// caption text, including `<v>` voice spans, is left as-is.
+pub fn rewrite_vtt(proxy_origin: &str, base_url: &str, text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut block: Vec<&str> = Vec::new();
+    for line in text.split_inclusive('\n') {
+        block.push(line);
+        if line.trim().is_empty() {
+            out.push_str(&rewrite_vtt_block(proxy_origin, base_url, &block));
+            block.clear();
+        }
+    }
+    out.push_str(&rewrite_vtt_block(proxy_origin, base_url, &block));
+    out
+}
+
+fn rewrite_vtt_block(proxy: &str, base: &str, lines: &[&str]) -> String {
+    let first = lines.first().map_or("", |l| l.trim());
+    if first == "STYLE" {
+        let block: String = lines[1..].concat();
+        let css = block.trim_end();
+        let trailing = &block[css.len()..];
+        return format!("{}{}{}", lines[0], rewrite_css_string(proxy, base, css), trailing);
+    }
+
+    let timing = match lines.iter().position(|l| l.contains("-->")) {
+        Some(idx) => idx,
+        None => return lines.concat(),
+    };
+
+    let mut out = lines[..=timing].concat();
+    for line in &lines[timing + 1..] {
+        let body = line.trim_end_matches(['\r', '\n']);
+        let ending = &line[body.len()..];
+        if is_vtt_image_payload(body.trim()) {
+            // The player reads the sprite fragment itself, so it stays a
+            // real fragment on the proxied URL.
+            let (url, fragment) = match body.trim().split_once('#') {
+                Some((url, fragment)) => (url, Some(fragment)),
+                None => (body.trim(), None),
+            };
+            out.push_str(&encode(proxy, base, url));
+            if let Some(fragment) = fragment {
+                out.push('#');
+                out.push_str(fragment);
+            }
+        } else {
+            out.push_str(body);
+        }
+        out.push_str(ending);
+    }
+    out
+}
+
+/// Whether a cue payload line is an image reference rather than caption
+/// text: one token that is an absolute http(s) URL or ends in an image
+/// extension.
+fn is_vtt_image_payload(line: &str) -> bool {
+    if line.is_empty() || line.contains(char::is_whitespace) || line.starts_with('<') {
+        return false;
+    }
+    if let Ok(url) = Url::parse(line) {
+        return url.scheme() == "http" || url.scheme() == "https";
+    }
+    let path = line.split(['?', '#']).next().unwrap_or("").to_ascii_lowercase();
+    VTT_IMAGE_EXTENSIONS.iter().any(|ext| path.ends_with(ext))
+}
+
+// ---------------------------------------------------------------------------
+// Helpers
+// ---------------------------------------------------------------------------
+
+fn encode(proxy: &str, base: &str, raw: &str) -> String {
+    encode_url_with_base(proxy, base, raw).unwrap_or_else(|| raw.to_string())
+}
+
+fn resolve(base: &str, raw: &str) -> Option<String> {
+    Url::parse(base).ok()?.join(raw).ok().map(|u| u.to_string())
+}
+
+fn unescape_xml(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+fn escape_xml(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROXY: &str = "http://localhost:8080";
+
+    #[test]
+    fn rewrites_hls_master_playlist() {
+        let base = "https://cdn.example.com/video/master.m3u8";
+        let text = "#EXTM3U\n\
+#EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID=\"aud\",NAME=\"en\",URI=\"audio/en.m3u8\"\n\
+#EXT-X-STREAM-INF:BANDWIDTH=1280000,AUDIO=\"aud\"\n\
+low/index.m3u8\n\
+#EXT-X-STREAM-INF:BANDWIDTH=2560000\n\
+https://other.example.com/high/index.m3u8\n";
+        let result = rewrite_hls(PROXY, base, text);
+        assert_eq!(
+            result,
+            "#EXTM3U\n\
+#EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID=\"aud\",NAME=\"en\",URI=\"http://localhost:8080/proxy?url=https://cdn.example.com/video/audio/en.m3u8\"\n\
+#EXT-X-STREAM-INF:BANDWIDTH=1280000,AUDIO=\"aud\"\n\
+http://localhost:8080/proxy?url=https://cdn.example.com/video/low/index.m3u8\n\
+#EXT-X-STREAM-INF:BANDWIDTH=2560000\n\
+http://localhost:8080/proxy?url=https://other.example.com/high/index.m3u8\n"
+        );
+    }
+
+    #[test]
+    fn rewrites_hls_media_playlist() {
+        let base = "https://cdn.example.com/video/low/index.m3u8";
+        let text = "#EXTM3U\r\n\
+#EXT-X-TARGETDURATION:10\r\n\
+#EXT-X-KEY:METHOD=AES-128,URI=\"/keys/k1\"\r\n\
+#EXT-X-MAP:URI=\"init.mp4\"\r\n\
+#EXTINF:9.009,\r\n\
+seg0.ts\r\n\
+\r\n\
+#EXT-X-ENDLIST\r\n";
+        let result = rewrite_hls(PROXY, base, text);
+        assert_eq!(
+            result,
+            "#EXTM3U\r\n\
+#EXT-X-TARGETDURATION:10\r\n\
+#EXT-X-KEY:METHOD=AES-128,URI=\"http://localhost:8080/proxy?url=https://cdn.example.com/keys/k1\"\r\n\
+#EXT-X-MAP:URI=\"http://localhost:8080/proxy?url=https://cdn.example.com/video/low/init.mp4\"\r\n\
+#EXTINF:9.009,\r\n\
+http://localhost:8080/proxy?url=https://cdn.example.com/video/low/seg0.ts\r\n\
+\r\n\
+#EXT-X-ENDLIST\r\n"
+        );
+    }
+
+    #[test]
+    fn rewrites_appcache_manifest() {
+        let base = "https://example.com/app/offline.appcache";
+        let text = "CACHE MANIFEST\n\
+# v1\n\
+CACHE:\n\
+style.css\n\
+FALLBACK:\n\
+/pages/ /offline.html\n\
+NETWORK:\n\
+*\n";
+        let result = rewrite_appcache_manifest(PROXY, base, text);
+        assert_eq!(
+            result,
+            "CACHE MANIFEST\n\
+# v1\n\
+CACHE:\n\
+http://localhost:8080/proxy?url=https://example.com/app/style.css\n\
+FALLBACK:\n\
+http://localhost:8080/proxy?url=https://example.com/pages/ http://localhost:8080/proxy?url=https://example.com/offline.html\n\
+NETWORK:\n\
+*\n"
+        );
+    }
+
+    #[test]
+    fn rewrites_vtt_thumbnails_and_style() {
+        let base = "https://cdn.example.com/video/thumbs.vtt";
+        let text = "WEBVTT\n\
+\n\
+STYLE\n\
+::cue { background-image: url(bg.png) }\n\
+\n\
+00:00.000 --> 00:05.000\n\
+sprite.jpg#xywh=0,0,160,90\n\
+\n\
+2\n\
+00:05.000 --> 00:10.000\n\
+<v Roger>See example.png</v>\n";
+        let result = rewrite_vtt(PROXY, base, text);
+        assert_eq!(
+            result,
+            "WEBVTT\n\
+\n\
+STYLE\n\
+::cue { background-image: url(http://localhost:8080/proxy?url=https://cdn.example.com/video/bg.png) }\n\
+\n\
+00:00.000 --> 00:05.000\n\
+http://localhost:8080/proxy?url=https://cdn.example.com/video/sprite.jpg#xywh=0,0,160,90\n\
+\n\
+2\n\
+00:05.000 --> 00:10.000\n\
+<v Roger>See example.png</v>\n"
+        );
+    }
+
+    #[test]
+    fn rewrites_dash_mpd() {
+        let base = "https://cdn.example.com/dash/manifest.mpd";
+        let xml = r#"<MPD><Period><BaseURL>media/</BaseURL><AdaptationSet><SegmentTemplate media="seg-$Number$.m4s" initialization="init.mp4"/></AdaptationSet></Period></MPD>"#;
+        let result = rewrite_dash(PROXY, base, xml);
+        assert_eq!(
+            result,
+            r#"<MPD><Period><BaseURL>http://localhost:8080/proxy?url=https://cdn.example.com/dash/media/</BaseURL><AdaptationSet><SegmentTemplate media="http://localhost:8080/proxy?url=https://cdn.example.com/dash/media/seg-$Number$.m4s" initialization="http://localhost:8080/proxy?url=https://cdn.example.com/dash/media/init.mp4"/></AdaptationSet></Period></MPD>"#
+        );
+    }
+
+    #[test]
+    fn dash_base_urls_are_scoped_to_their_element() {
+        let base = "https://cdn.example.com/dash/manifest.mpd";
+        let xml = r#"<MPD>
+<Period id="1"><AdaptationSet>
+<Representation id="v"><BaseURL>video/</BaseURL><SegmentTemplate media="a-$Number$.m4s"/></Representation>
+<Representation id="a"><SegmentTemplate media="b-$Number$.m4s"/></Representation>
+</AdaptationSet></Period>
+<Period id="2"><BaseURL>p2/</BaseURL><AdaptationSet><SegmentTemplate media="c.m4s"/></AdaptationSet></Period>
+</MPD>"#;
+        let result = rewrite_dash(PROXY, base, xml);
+        assert!(result.contains(r#"<BaseURL>http://localhost:8080/proxy?url=https://cdn.example.com/dash/video/</BaseURL><SegmentTemplate media="http://localhost:8080/proxy?url=https://cdn.example.com/dash/video/a-$Number$.m4s"/>"#));
+        assert!(result.contains(r#"<Representation id="a"><SegmentTemplate media="http://localhost:8080/proxy?url=https://cdn.example.com/dash/b-$Number$.m4s"/>"#));
+        assert!(result.contains(r#"<BaseURL>http://localhost:8080/proxy?url=https://cdn.example.com/dash/p2/</BaseURL>"#));
+        assert!(result.contains(r#"media="http://localhost:8080/proxy?url=https://cdn.example.com/dash/p2/c.m4s""#));
+    }
+
+    #[test]
+    fn dash_alternative_base_urls_resolve_independently() {
+        let base = "https://cdn.example.com/dash/manifest.mpd";
+        let xml = r#"<MPD><BaseURL>https://a.example/v/</BaseURL><BaseURL>https://b.example/v/</BaseURL><Period><SegmentList><SegmentURL media="s1.m4s"/></SegmentList></Period></MPD>"#;
+        let result = rewrite_dash(PROXY, base, xml);
+        assert!(result.contains("<BaseURL>http://localhost:8080/proxy?url=https://b.example/v/</BaseURL>"));
+        assert!(result.contains(r#"media="http://localhost:8080/proxy?url=https://a.example/v/s1.m4s""#));
+    }
+
+    #[test]
+    fn dash_keeps_template_format_tags() {
+        let base = "https://cdn.example.com/dash/manifest.mpd";
+        let xml = r#"<MPD><Period><SegmentTemplate media="$RepresentationID$/seg-$Number%05d$.m4s?t=100%25" initialization="$RepresentationID$/init.mp4"/></Period></MPD>"#;
+        let result = rewrite_dash(PROXY, base, xml);
+        assert!(result.contains(r#"media="http://localhost:8080/proxy?url=https://cdn.example.com/dash/$RepresentationID$/seg-$Number%05d$.m4s?t%3D100%2525""#));
+        assert!(result.contains(r#"initialization="http://localhost:8080/proxy?url=https://cdn.example.com/dash/$RepresentationID$/init.mp4""#));
+    }
+}
@@ -5,22 +5,29 @@
 // always talks through our server.
 //
 // Encoding scheme:  /proxy?url=<percent-encoded original>
+//   (prefixed by the proxy origin, or protocol-/root-relative, see
+//   ProxyUrlStyle)
 //
 // Supported inputs:
 //   absolute        https://example.com/path
 //   protocol-rel    //example.com/path
-//   relative        /path  or  ../path
+//   relative        /path  or  ../path   (encode_url_with_base only)
 //   blob:           blob:https://...
-//   data:           data:text/html,...
+//   data:           data:text/html,...   (or extracted to a blob when large,
+//                                         see collect_blobs)
 //   javascript:     javascript:...   (left as-is)
 //   file:           file:///...      (BLOCKED)
 //
 // The proxy_origin is the origin of OUR proxy server, e.g.
 // "http://localhost:8080".
 
+use std::cell::RefCell;
+
 use percent_encoding::{utf8_percent_encode, percent_decode_str, AsciiSet, CONTROLS};
 use url::Url;
 
+use crate::digest;
+
 /// Characters that must be percent-encoded inside the `url=` query value.
 const QUERY_ENCODE_SET: &AsciiSet = &CONTROLS
     .add(b' ')
@@ -33,16 +40,99 @@ const QUERY_ENCODE_SET: &AsciiSet = &CONTROLS
     .add(b'+')
     .add(b'%');
 
+/// Scheme assumed for protocol-relative (`//host/path`) inputs when there is
+/// no base URL to inherit one from, unless [`UrlSettings::default_scheme`]
+/// says otherwise.
+pub const DEFAULT_SCHEME: &str = "https";
+
+/// How the proxy part of an encoded URL is written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ProxyUrlStyle {
+    /// `{proxy_origin}/proxy?url=...`
+    #[default]
+    Absolute,
+    /// `//{proxy host}/proxy?url=...`, inheriting the page's scheme.
+    ProtocolRelative,
+    /// `/proxy?url=...`, for documents served from the proxy's own origin.
+    RootRelative,
+}
+
+impl ProxyUrlStyle {
+    /// The style's name in a `config` object: `"absolute"`,
+    /// `"protocol_relative"` or `"root_relative"`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ProxyUrlStyle::Absolute => "absolute",
+            ProxyUrlStyle::ProtocolRelative => "protocol_relative",
+            ProxyUrlStyle::RootRelative => "root_relative",
+        }
+    }
+
+    /// The inverse of [`ProxyUrlStyle::as_str`].
+    pub fn from_name(name: &str) -> Option<Self> {
+        [ProxyUrlStyle::Absolute, ProxyUrlStyle::ProtocolRelative, ProxyUrlStyle::RootRelative]
+            .into_iter()
+            .find(|style| style.as_str() == name)
+    }
+
+    /// The `.../proxy?url=` prefix for `proxy_origin` in this style.
+    fn prefix(self, proxy_origin: &str) -> String {
+        self.route(proxy_origin, "/proxy?url=")
+    }
+
+    /// `route` (e.g. `"/proxy?url="`) on `proxy_origin` in this style.
+    fn route(self, proxy_origin: &str, route: &str) -> String {
+        let origin = proxy_origin.trim_end_matches('/');
+        match self {
+            ProxyUrlStyle::Absolute => format!("{}{}", origin, route),
+            ProxyUrlStyle::ProtocolRelative => {
+                let authority = origin.split_once("://").map_or(origin, |(_, rest)| rest);
+                format!("//{}{}", authority, route)
+            }
+            ProxyUrlStyle::RootRelative => route.to_string(),
+        }
+    }
+}
+
 /// Encode an arbitrary URL so it routes through our proxy.
 ///
 /// Returns `None` for `file:` URLs (blocked) and for inputs that cannot be
 /// meaningfully proxied (empty strings, bare fragments, etc.).
+///
+/// Relative inputs (`/path`, `../x`, `img.png`) also return `None`: there is
+/// no base to resolve them against, and passing them through would leak an
+/// unproxied URL.  Use [`encode_url_with_base`] for those.
 pub fn encode_url(proxy_origin: &str, raw: &str) -> Option<String> {
+    let default_scheme = current_settings(|s| s.default_scheme.clone());
+    encode_url_with_scheme(proxy_origin, raw, &default_scheme)
+}
+
+/// Like [`encode_url`], but protocol-relative inputs are resolved with
+/// `default_scheme` (e.g. `"http"` when proxying an http-only upstream).
+pub fn encode_url_with_scheme(proxy_origin: &str, raw: &str, default_scheme: &str) -> Option<String> {
+    encode_url_styled(proxy_origin, raw, default_scheme, current_settings(|s| s.style))
+}
+
+/// Like [`encode_url`], but the proxy prefix is written in `style`, e.g.
+/// root-relative `/proxy?url=...` so documents do not hardcode the proxy
+/// origin.  The `url=` value is the same in every style, so it still
+/// round-trips through [`decode_url`].
+pub fn encode_url_with_style(proxy_origin: &str, raw: &str, style: ProxyUrlStyle) -> Option<String> {
+    let default_scheme = current_settings(|s| s.default_scheme.clone());
+    encode_url_styled(proxy_origin, raw, &default_scheme, style)
+}
+
+fn encode_url_styled(proxy_origin: &str, raw: &str, default_scheme: &str, style: ProxyUrlStyle) -> Option<String> {
     let trimmed = raw.trim();
     if trimmed.is_empty() || trimmed.starts_with('#') {
         return None;
     }
 
+    // Already routed through us – wrapping it again would nest proxies.
+    if is_own_output(proxy_origin, trimmed) {
+        return Some(trimmed.to_string());
+    }
+
     // Block file: scheme outright.
     if trimmed.to_ascii_lowercase().starts_with("file:") {
         return None;
@@ -53,15 +143,19 @@ pub fn encode_url(proxy_origin: &str, raw: &str) -> Option<String> {
         return Some(trimmed.to_string());
     }
 
-    // data: URLs are self-contained – pass-through.
+    // data: URLs are self-contained – pass-through, unless large enough to
+    // be served from the blob route instead.
     if trimmed.to_ascii_lowercase().starts_with("data:") {
+        if let Some(blob) = extract_large_data_url(trimmed) {
+            return Some(format!("{}{}", style.route(proxy_origin, BLOB_ROUTE), blob));
+        }
         return Some(trimmed.to_string());
     }
 
     // blob: URLs – encode the inner URL portion.
     if trimmed.to_ascii_lowercase().starts_with("blob:") {
         let inner = &trimmed[5..];
-        if let Some(encoded_inner) = encode_url(proxy_origin, inner) {
+        if let Some(encoded_inner) = encode_url_styled(proxy_origin, inner, default_scheme, style) {
             return Some(format!("blob:{}", encoded_inner));
         }
         return Some(trimmed.to_string());
@@ -69,46 +163,349 @@ pub fn encode_url(proxy_origin: &str, raw: &str) -> Option<String> {
 
     // Protocol-relative: //example.com/path  → https://example.com/path
     let absolute = if trimmed.starts_with("//") {
-        format!("https:{}", trimmed)
-    } else if trimmed.starts_with('/') || !trimmed.contains("://") {
-        // Relative path – we cannot resolve it without a base, so we return
-        // it as-is.  The caller (html / css rewriter) is responsible for
-        // resolving against the page's base URL before calling encode_url.
-        if Url::parse(trimmed).is_err() {
-            // Truly relative – cannot proxy without a base URL.
-            return Some(trimmed.to_string());
-        }
-        trimmed.to_string()
+        format!("{}:{}", default_scheme, trimmed)
     } else {
         trimmed.to_string()
     };
 
-    // Validate.
+    // Validate.  Anything that does not parse on its own is relative (or
+    // garbage) and cannot be proxied without a base URL.
     if Url::parse(&absolute).is_err() {
-        return Some(trimmed.to_string());
+        return None;
+    }
+
+    if let Some(hooked) = call_url_hook(&absolute) {
+        return hooked;
     }
 
     let encoded_target = utf8_percent_encode(&absolute, QUERY_ENCODE_SET).to_string();
-    Some(format!("{}/proxy?url={}", proxy_origin.trim_end_matches('/'), encoded_target))
+    Some(format!("{}{}", style.prefix(proxy_origin), encoded_target))
+}
+
+/// Encoding settings for the rewrites run inside [`with_url_settings`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UrlSettings {
+    /// How the proxy part of each encoded URL is written.
+    pub style: ProxyUrlStyle,
+    /// Scheme for protocol-relative inputs with no base URL to inherit one
+    /// from; [`DEFAULT_SCHEME`] by default.
+    pub default_scheme: String,
+}
+
+impl Default for UrlSettings {
+    fn default() -> Self {
+        UrlSettings {
+            style: ProxyUrlStyle::default(),
+            default_scheme: DEFAULT_SCHEME.to_string(),
+        }
+    }
+}
+
+thread_local! {
+    static URL_SETTINGS: RefCell<UrlSettings> = RefCell::new(UrlSettings::default());
+}
+
+/// Run `f` with `settings` applied to every URL the rewriters encode,
+/// without threading them through each module.  Per thread; the previous
+/// settings are restored when `f` returns or unwinds.
+pub fn with_url_settings<R>(settings: UrlSettings, f: impl FnOnce() -> R) -> R {
+    struct Restore(Option<UrlSettings>);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            if let Some(previous) = self.0.take() {
+                URL_SETTINGS.with(|s| *s.borrow_mut() = previous);
+            }
+        }
+    }
+
+    let previous = URL_SETTINGS.with(|s| std::mem::replace(&mut *s.borrow_mut(), settings));
+    let _restore = Restore(Some(previous));
+    f()
+}
+
+/// Read the settings installed by [`with_url_settings`], or the defaults.
+fn current_settings<R>(f: impl FnOnce(&UrlSettings) -> R) -> R {
+    URL_SETTINGS.with(|s| f(&s.borrow()))
+}
+
+/// Per-URL encoding override; see [`with_url_hook`].
+pub type UrlHook = dyn Fn(&str) -> Option<String>;
+
+thread_local! {
+    static URL_HOOK: RefCell<Option<Box<UrlHook>>> = const { RefCell::new(None) };
+}
+
+/// Run `f` with `hook` deciding how each URL is proxied.
+///
+/// Every rewriter encodes through this module, so the hook sees every URL
+/// they route through the proxy: it is called with the absolute upstream URL
+/// and returns the final URL, or `None` to leave the original in place.
+/// `file:`, `data:` and `javascript:` URLs and already-proxied URLs never
+/// reach it.  Inside the hook, [`encode_url`] gives the default encoding, so
+/// a hook can decorate it (add a token, pick a backend per host, log).
+///
+/// The hook is per thread and nests: the previous one is restored when `f`
+/// returns or unwinds.
+pub fn with_url_hook<R>(hook: impl Fn(&str) -> Option<String> + 'static, f: impl FnOnce() -> R) -> R {
+    struct Restore(Option<Box<UrlHook>>);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            let previous = self.0.take();
+            URL_HOOK.with(|h| *h.borrow_mut() = previous);
+        }
+    }
+
+    let previous = URL_HOOK.with(|h| h.borrow_mut().replace(Box::new(hook)));
+    let _restore = Restore(previous);
+    f()
+}
+
+/// Apply the installed hook, if any, to `absolute`.  The hook is taken out
+/// while it runs so its own `encode_url` calls get the default encoding.
+fn call_url_hook(absolute: &str) -> Option<Option<String>> {
+    let hook = URL_HOOK.with(|h| h.borrow_mut().take())?;
+    let result = hook(absolute);
+    URL_HOOK.with(|h| *h.borrow_mut() = Some(hook));
+    Some(result)
 }
 
 /// Encode a URL resolved against a known base.
+///
+/// Protocol-relative inputs inherit the base URL's scheme, so `//cdn/x`
+/// under an `http:` page stays on http.
 pub fn encode_url_with_base(proxy_origin: &str, base: &str, raw: &str) -> Option<String> {
     let trimmed = raw.trim();
     if trimmed.is_empty() || trimmed.starts_with('#') {
         return None;
     }
 
+    if is_own_output(proxy_origin, trimmed) {
+        return Some(trimmed.to_string());
+    }
+
     // Resolve relative URLs against the base.
-    let resolved = match Url::parse(base) {
-        Ok(base_url) => match base_url.join(trimmed) {
-            Ok(full) => full.to_string(),
-            Err(_) => return Some(trimmed.to_string()),
-        },
+    let base_url = match Url::parse(base) {
+        Ok(u) => u,
         Err(_) => return encode_url(proxy_origin, trimmed),
     };
+    let resolved = match base_url.join(trimmed) {
+        Ok(full) => full.to_string(),
+        Err(_) => return Some(trimmed.to_string()),
+    };
+
+    encode_url_with_scheme(proxy_origin, &resolved, base_url.scheme())
+}
+
+/// Route the server serves extracted `data:` payloads from:
+/// `{proxy_origin}/blob/<id>`.
+pub const BLOB_ROUTE: &str = "/blob/";
+
+/// A `data:` URL payload pulled out of a document by [`collect_blobs`].
+/// The server stores `data` under `id` and serves it with `mime` as the
+/// content type.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtractedBlob {
+    pub id: String,
+    pub mime: String,
+    pub data: Vec<u8>,
+}
+
+/// The blobs gathered by the innermost [`collect_blobs`] call.
+struct BlobCollector {
+    max_data_len: usize,
+    blobs: Vec<ExtractedBlob>,
+}
+
+thread_local! {
+    static BLOBS: RefCell<Option<BlobCollector>> = const { RefCell::new(None) };
+}
+
+/// Run `f`, replacing each `data:` URL it encodes that is longer than
+/// `max_data_len` bytes with a [`BLOB_ROUTE`] URL, and return alongside its
+/// result the payloads moved there, one per distinct id.  Large inline
+/// images then stop bloating every proxied document and can be cached on
+/// their own.  Outside `collect_blobs`, `data:` URLs always stay inline,
+/// since nothing would store what they point at.
+///
+/// Only passive media types (images other than SVG, audio, video, fonts,
+/// plain text) are extracted: the blob route serves from the proxy's own
+/// origin, where an HTML, SVG or script payload could run.
+pub fn collect_blobs<R>(max_data_len: usize, f: impl FnOnce() -> R) -> (R, Vec<ExtractedBlob>) {
+    struct Restore(Option<BlobCollector>);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            let previous = self.0.take();
+            BLOBS.with(|b| *b.borrow_mut() = previous);
+        }
+    }
+
+    let collector = BlobCollector { max_data_len, blobs: Vec::new() };
+    let previous = BLOBS.with(|b| b.borrow_mut().replace(collector));
+    let restore = Restore(previous);
+    let result = f();
+    let blobs = BLOBS.with(|b| b.borrow_mut().take()).map_or_else(Vec::new, |c| c.blobs);
+    drop(restore);
+    (result, blobs)
+}
+
+/// Move `url` to the active [`collect_blobs`] call if it is over that
+/// call's limit.  Returns the blob id.
+fn extract_large_data_url(url: &str) -> Option<String> {
+    let max = BLOBS.with(|b| b.borrow().as_ref().map(|c| c.max_data_len))?;
+    if url.len() <= max {
+        return None;
+    }
+    let blob = extract_data_url(url)?;
+    let id = blob.id.clone();
+    BLOBS.with(|b| {
+        if let Some(collector) = b.borrow_mut().as_mut() {
+            if !collector.blobs.iter().any(|known| known.id == id) {
+                collector.blobs.push(blob);
+            }
+        }
+    });
+    Some(id)
+}
+
+/// Split a `data:[<mime>][;base64],<payload>` URL and decode its payload.
+/// `None` for malformed URLs and for media types that are not passive.
+fn extract_data_url(url: &str) -> Option<ExtractedBlob> {
+    use base64::Engine;
+
+    if !url.get(..5).is_some_and(|s| s.eq_ignore_ascii_case("data:")) {
+        return None;
+    }
+    let (meta, payload) = url[5..].split_once(',')?;
+    let (mime, is_base64) = match meta.len().checked_sub(7) {
+        Some(i) if meta.get(i..).is_some_and(|t| t.eq_ignore_ascii_case(";base64")) => (&meta[..i], true),
+        _ => (meta, false),
+    };
+    let mime = if mime.is_empty() { "text/plain;charset=US-ASCII" } else { mime };
+    if !is_passive_mime(mime) {
+        return None;
+    }
+
+    let data = if is_base64 {
+        let compact: String = payload.chars().filter(|c| !c.is_ascii_whitespace()).collect();
+        base64::engine::general_purpose::STANDARD.decode(compact).ok()?
+    } else {
+        percent_decode_str(payload).collect()
+    };
+
+    Some(ExtractedBlob { id: content_id(&data), mime: mime.to_string(), data })
+}
+
+/// Whether a payload of this media type cannot run script when served from
+/// the proxy's origin.
+fn is_passive_mime(mime: &str) -> bool {
+    let essence = mime.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+    match essence.split_once('/') {
+        Some(("image", sub)) => !sub.starts_with("svg"),
+        Some(("audio" | "video" | "font", _)) => true,
+        _ => matches!(essence.as_str(), "text/plain" | "application/font-woff" | "application/octet-stream"),
+    }
+}
+
+/// Content-derived id (SHA-256, hex) so identical payloads map to the same
+/// blob URL across documents and no page can craft a payload that takes
+/// over another's id.
+fn content_id(data: &[u8]) -> String {
+    digest::hex(&digest::sha256(data))
+}
+
+thread_local! {
+    static MARKER_SECRET: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Run `f` with `secret` keying the rewriters' "already rewritten" markers
+/// (see [`rewritten_token`]).  Every proxy instance of one deployment should
+/// pass the same secret, so output cached or chained through any of them is
+/// recognised by all.  Per thread; the previous secret is restored when `f`
+/// returns or unwinds.
+pub fn with_marker_secret<R>(secret: Option<String>, f: impl FnOnce() -> R) -> R {
+    struct Restore(Option<String>);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            let previous = self.0.take();
+            MARKER_SECRET.with(|s| *s.borrow_mut() = previous);
+        }
+    }
+
+    let previous = MARKER_SECRET.with(|s| std::mem::replace(&mut *s.borrow_mut(), secret));
+    let _restore = Restore(previous);
+    f()
+}
+
+/// Token embedded in the rewriters' "already rewritten" markers: an
+/// HMAC-SHA-256 of `proxy_origin` under the secret installed by
+/// [`with_marker_secret`], so upstream content cannot carry a marker that
+/// makes us skip rewriting it.  `None` without a secret, in which case no
+/// marker is written or honoured.
+pub(crate) fn rewritten_token(proxy_origin: &str) -> Option<String> {
+    MARKER_SECRET.with(|s| {
+        let secret = s.borrow();
+        let mac = digest::hmac_sha256(secret.as_ref()?.as_bytes(), proxy_origin.trim_end_matches('/').as_bytes());
+        Some(digest::hex(&mac[..16]))
+    })
+}
+
+/// Whether `url` already points at our own proxy route, in any
+/// [`ProxyUrlStyle`].
+pub fn is_proxied(proxy_origin: &str, url: &str) -> bool {
+    own_proxy_target(proxy_origin, url).is_some()
+}
+
+/// Whether the encoder should leave `url` alone as one of its own outputs.
+/// A root-relative `/proxy?url=…` is only ours when that is the style in
+/// use; otherwise it is an upstream path and still gets proxied.
+fn is_own_output(proxy_origin: &str, url: &str) -> bool {
+    let is_own_blob = [ProxyUrlStyle::Absolute, ProxyUrlStyle::ProtocolRelative].into_iter().any(|style| {
+        let prefix = style.route(proxy_origin, BLOB_ROUTE);
+        url.get(..prefix.len()).is_some_and(|head| head.eq_ignore_ascii_case(&prefix))
+    });
+    if is_own_blob {
+        return true;
+    }
+    match own_proxy_target(proxy_origin, url) {
+        Some(_) if url.starts_with("//") || !url.starts_with('/') => true,
+        Some(_) => current_settings(|s| s.style) == ProxyUrlStyle::RootRelative,
+        None => false,
+    }
+}
 
-    encode_url(proxy_origin, &resolved)
+/// Number of our proxy URLs in `text`, in any [`ProxyUrlStyle`]: a
+/// `/proxy?url=` preceded by our `//host`, or, under the root-relative
+/// style, standing at the start of a value (`srcset` lists included).
+pub(crate) fn count_proxied(proxy_origin: &str, text: &str) -> usize {
+    let origin = proxy_origin.trim_end_matches('/');
+    let authority = origin.split_once("://").map_or(origin, |(_, rest)| rest);
+    let host = format!("//{}", authority);
+    let route = ProxyUrlStyle::RootRelative.prefix(proxy_origin);
+    let root_relative = current_settings(|s| s.style) == ProxyUrlStyle::RootRelative;
+    text.match_indices(route.as_str())
+        .filter(|&(at, _)| {
+            let head = &text[..at];
+            root_relative && (head.is_empty() || head.ends_with(|c: char| c.is_whitespace() || c == ','))
+                || head
+                    .len()
+                    .checked_sub(host.len())
+                    .and_then(|i| head.get(i..))
+                    .is_some_and(|tail| tail.eq_ignore_ascii_case(&host))
+        })
+        .count()
+}
+
+/// The still-encoded `url=` value of `url` if it is one of `proxy_origin`'s
+/// own proxy URLs, in any [`ProxyUrlStyle`].
+fn own_proxy_target<'a>(proxy_origin: &str, url: &'a str) -> Option<&'a str> {
+    [ProxyUrlStyle::Absolute, ProxyUrlStyle::ProtocolRelative, ProxyUrlStyle::RootRelative]
+        .into_iter()
+        .find_map(|style| {
+            let prefix = style.prefix(proxy_origin);
+            url.get(..prefix.len())
+                .filter(|head| head.eq_ignore_ascii_case(&prefix))
+                .map(|_| &url[prefix.len()..])
+        })
 }
 
 /// Decode a proxied URL back to the original upstream URL.
@@ -124,12 +521,100 @@ pub fn decode_url(encoded: &str) -> Option<String> {
     }
 }
 
+/// Like [`decode_url`], but if the decoded value is itself one of
+/// `proxy_origin`'s proxy URLs (accidentally wrapped twice, or root-relative
+/// `/proxy?url=…`) the inner layers are peeled until the upstream URL is
+/// reached.  A `/proxy?url=` URL on any other host is a real upstream URL
+/// and is kept.
+pub fn decode_proxy_url(proxy_origin: &str, encoded: &str) -> Option<String> {
+    let decoded = percent_decode_str(encoded).decode_utf8().ok()?;
+    let decoded = decoded.as_ref();
+    if let Some(inner) = own_proxy_target(proxy_origin, decoded) {
+        // Our own encoding leaves `scheme://` readable, so an inner value
+        // that only parses after another round of decoding (`https%3A%2F…`)
+        // is an upstream query parameter and must be kept byte-for-byte.
+        if Url::parse(inner).is_ok() || own_proxy_target(proxy_origin, inner).is_some() {
+            return decode_proxy_url(proxy_origin, inner);
+        }
+    }
+    decode_url(encoded)
+}
+
+/// Like [`decode_url`], but a value that is not an absolute URL on its own is
+/// resolved against `base`, e.g. a relative target built by client code.
+pub fn decode_url_with_base(encoded: &str, base: &str) -> Option<String> {
+    decode_url(encoded).or_else(|| resolve_encoded(encoded, base))
+}
+
+/// [`decode_proxy_url`] with relative values resolved against `base`, as in
+/// [`decode_url_with_base`].
+pub fn decode_proxy_url_with_base(proxy_origin: &str, encoded: &str, base: &str) -> Option<String> {
+    decode_proxy_url(proxy_origin, encoded).or_else(|| resolve_encoded(encoded, base))
+}
+
+fn resolve_encoded(encoded: &str, base: &str) -> Option<String> {
+    let decoded = percent_decode_str(encoded).decode_utf8().ok()?;
+    if decoded.trim().is_empty() {
+        return None;
+    }
+    let joined = Url::parse(base).ok()?.join(decoded.trim()).ok()?;
+    Some(joined.to_string())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     const ORIGIN: &str = "http://localhost:8080";
 
+    #[test]
+    fn small_data_url_passes_through() {
+        let raw = "data:image/gif;base64,R0lGODlhAQABAAAAACw=";
+        let (url, blobs) = collect_blobs(1024, || encode_url(ORIGIN, raw));
+        assert_eq!(url.as_deref(), Some(raw));
+        assert!(blobs.is_empty());
+    }
+
+    #[test]
+    fn large_data_url_is_extracted() {
+        let raw = "data:image/png;base64,aGVsbG8gd29ybGQ=";
+        let plain = "data:,hello%20world%20again";
+        let (urls, blobs) = collect_blobs(16, || {
+            [raw, raw, plain].map(|u| encode_url_with_base(ORIGIN, "https://example.com/", u).unwrap())
+        });
+        assert_eq!(blobs.len(), 2);
+        assert_eq!(urls[0], format!("http://localhost:8080/blob/{}", blobs[0].id));
+        assert_eq!(urls[1], urls[0]);
+        assert_eq!(blobs[0].id, "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9");
+        assert_eq!(blobs[0].mime, "image/png");
+        assert_eq!(blobs[0].data, b"hello world");
+        assert_eq!(blobs[1].data, b"hello world again");
+        assert_eq!(blobs[1].mime, "text/plain;charset=US-ASCII");
+
+        // Re-encoding our own blob URL leaves it alone.
+        assert_eq!(encode_url(ORIGIN, &urls[0]).as_deref(), Some(urls[0].as_str()));
+    }
+
+    #[test]
+    fn data_urls_stay_inline_without_a_collector() {
+        let raw = "data:image/png;base64,aGVsbG8gd29ybGQ=";
+        assert_eq!(encode_url(ORIGIN, raw).as_deref(), Some(raw));
+    }
+
+    #[test]
+    fn active_data_urls_stay_inline() {
+        for raw in [
+            "data:text/html,<script>alert(1)</script>",
+            "data:image/svg+xml;base64,PHN2ZyBvbmxvYWQ9YWxlcnQoMSk+",
+            "data:text/javascript,alert(1)",
+            "data:application/xhtml+xml,<x/>",
+        ] {
+            let (url, blobs) = collect_blobs(8, || encode_url(ORIGIN, raw));
+            assert_eq!(url.as_deref(), Some(raw));
+            assert!(blobs.is_empty());
+        }
+    }
+
     #[test]
     fn absolute_url() {
         let result = encode_url(ORIGIN, "https://example.com/page").unwrap();
@@ -143,6 +628,24 @@ mod tests {
         assert!(result.contains("proxy?url="));
     }
 
+    #[test]
+    fn protocol_relative_custom_scheme() {
+        let result = encode_url_with_scheme(ORIGIN, "//cdn.example.com/lib.js", "http").unwrap();
+        assert!(result.ends_with("url=http://cdn.example.com/lib.js"));
+    }
+
+    #[test]
+    fn protocol_relative_inherits_http_base() {
+        let result = encode_url_with_base(ORIGIN, "http://example.com/", "//cdn.example.com/a").unwrap();
+        assert!(result.ends_with("url=http://cdn.example.com/a"));
+    }
+
+    #[test]
+    fn protocol_relative_inherits_https_base() {
+        let result = encode_url_with_base(ORIGIN, "https://example.com/", "//cdn.example.com/a").unwrap();
+        assert!(result.ends_with("url=https://cdn.example.com/a"));
+    }
+
     #[test]
     fn data_url_passthrough() {
         let result = encode_url(ORIGIN, "data:text/html,<h1>hi</h1>").unwrap();
@@ -168,9 +671,199 @@ mod tests {
         assert_eq!(decoded, "https://example.com/path?q=1");
     }
 
+    #[test]
+    fn decode_with_base_resolves_relative() {
+        assert_eq!(
+            decode_url_with_base("..%2Fimg%2Fa.png", "https://example.com/css/site.css").as_deref(),
+            Some("https://example.com/img/a.png")
+        );
+        assert_eq!(
+            decode_url_with_base("https%3A%2F%2Fother.com%2Fx", "https://example.com/").as_deref(),
+            Some("https://other.com/x")
+        );
+        assert_eq!(decode_url_with_base("", "https://example.com/"), None);
+    }
+
+    #[test]
+    fn pre_encoded_query_roundtrip() {
+        let original = "https://site/go?to=https%3A%2F%2Fother.com%2Fx";
+        let encoded = encode_url(ORIGIN, original).unwrap();
+        let query = encoded.split_once("url=").unwrap().1;
+        assert_eq!(decode_url(query).unwrap(), original);
+    }
+
+    #[test]
+    fn upstream_proxy_path_not_unwrapped() {
+        let original = "https://site/proxy?url=https%3A%2F%2Fother.com%2Fx";
+        let encoded = encode_url(ORIGIN, original).unwrap();
+        let query = encoded.split_once("url=").unwrap().1;
+        assert_eq!(decode_url(query).unwrap(), original);
+    }
+
+    #[test]
+    fn reserved_query_chars_roundtrip() {
+        let original = "https://site/search?q=a+b%20c&tag=%23x&n=100%25";
+        let encoded = encode_url(ORIGIN, original).unwrap();
+        let query = encoded.split_once("url=").unwrap().1;
+        assert_eq!(decode_url(query).unwrap(), original);
+    }
+
+    #[test]
+    fn root_relative_style() {
+        let original = "https://example.com/path?q=a&b=%23";
+        let encoded = encode_url_with_style(ORIGIN, original, ProxyUrlStyle::RootRelative).unwrap();
+        assert!(encoded.starts_with("/proxy?url=https://example.com/path"));
+        let query = encoded.split_once("url=").unwrap().1;
+        assert_eq!(decode_url(query).unwrap(), original);
+    }
+
+    #[test]
+    fn protocol_relative_style() {
+        let original = "https://example.com/path?q=a&b=%23";
+        let encoded = encode_url_with_style(ORIGIN, original, ProxyUrlStyle::ProtocolRelative).unwrap();
+        assert!(encoded.starts_with("//localhost:8080/proxy?url=https://example.com/path"));
+        let query = encoded.split_once("url=").unwrap().1;
+        assert_eq!(decode_url(query).unwrap(), original);
+    }
+
+    #[test]
+    fn absolute_style_matches_encode_url() {
+        let raw = "https://example.com/a";
+        assert_eq!(
+            encode_url_with_style(ORIGIN, raw, ProxyUrlStyle::Absolute),
+            encode_url(ORIGIN, raw)
+        );
+    }
+
+    #[test]
+    fn url_hook_decorates_default_encoding() {
+        let token = |u: &str| encode_url(ORIGIN, u).map(|p| format!("{}&token=abc", p));
+        let (html, css) = with_url_hook(token, || {
+            (
+                crate::html::rewrite_html(ORIGIN, "https://example.com/", r#"<img src="/a.png">"#),
+                crate::css::rewrite_css(ORIGIN, "https://example.com/", ".a { background: url(b.png) }"),
+            )
+        });
+        assert!(html.contains(r#"src="http://localhost:8080/proxy?url=https://example.com/a.png&amp;token=abc""#));
+        assert!(css.contains("url(http://localhost:8080/proxy?url=https://example.com/b.png&token=abc)"));
+
+        // The hook is gone once the closure returns.
+        assert!(!encode_url(ORIGIN, "https://example.com/c").unwrap().contains("token"));
+    }
+
+    #[test]
+    fn url_hook_can_decline() {
+        let encoded = with_url_hook(|_| None, || encode_url_with_base(ORIGIN, "https://example.com/", "/x"));
+        assert_eq!(encoded, None);
+    }
+
+    #[test]
+    fn already_proxied_not_rewrapped() {
+        let once = encode_url(ORIGIN, "https://example.com/a").unwrap();
+        assert_eq!(encode_url(ORIGIN, &once).unwrap(), once);
+    }
+
+    #[test]
+    fn decode_double_wrapped() {
+        let once = encode_url(ORIGIN, "https://example.com/a?b=c").unwrap();
+        let twice = encode_url("http://other-proxy:9000", &once).unwrap();
+        let query = twice.split_once("url=").unwrap().1;
+        assert_eq!(decode_proxy_url(ORIGIN, query).unwrap(), "https://example.com/a?b=c");
+        // The plain decoder peels only the outer layer.
+        assert_eq!(decode_url(query).unwrap(), once);
+    }
+
+    #[test]
+    fn url_settings_choose_the_style() {
+        let settings = UrlSettings { style: ProxyUrlStyle::RootRelative, ..UrlSettings::default() };
+        let encoded = with_url_settings(settings, || encode_url_with_base(ORIGIN, "https://example.com/", "/a"));
+        assert_eq!(encoded.as_deref(), Some("/proxy?url=https://example.com/a"));
+        // Restored once the closure returns.
+        assert!(encode_url(ORIGIN, "https://example.com/a").unwrap().starts_with(ORIGIN));
+    }
+
+    #[test]
+    fn url_settings_choose_the_default_scheme() {
+        let settings = UrlSettings { default_scheme: "http".to_string(), ..UrlSettings::default() };
+        let encoded = with_url_settings(settings, || encode_url(ORIGIN, "//cdn.example.com/a")).unwrap();
+        assert!(encoded.ends_with("url=http://cdn.example.com/a"));
+    }
+
+    #[test]
+    fn is_proxied_recognises_every_style() {
+        for style in [ProxyUrlStyle::Absolute, ProxyUrlStyle::ProtocolRelative, ProxyUrlStyle::RootRelative] {
+            let encoded = encode_url_with_style(ORIGIN, "https://example.com/a", style).unwrap();
+            assert!(is_proxied(ORIGIN, &encoded), "{}", encoded);
+        }
+        assert!(!is_proxied(ORIGIN, "https://other.site/proxy?url=x"));
+    }
+
+    #[test]
+    fn styled_output_is_not_rewrapped() {
+        for style in [ProxyUrlStyle::ProtocolRelative, ProxyUrlStyle::RootRelative] {
+            let settings = UrlSettings { style, ..UrlSettings::default() };
+            let once = with_url_settings(settings.clone(), || encode_url_with_base(ORIGIN, "https://example.com/", "/a")).unwrap();
+            let twice = with_url_settings(settings, || encode_url_with_base(ORIGIN, "https://example.com/", &once));
+            assert_eq!(twice.as_deref(), Some(once.as_str()));
+        }
+        // Under the default style a root-relative `/proxy` is an upstream path.
+        let upstream = encode_url_with_base(ORIGIN, "https://example.com/", "/proxy?url=x").unwrap();
+        assert_eq!(upstream, "http://localhost:8080/proxy?url=https://example.com/proxy?url%3Dx");
+    }
+
+    #[test]
+    fn counts_proxied_urls_in_every_style() {
+        let text = "http://localhost:8080/proxy?url=a 1x, //localhost:8080/proxy?url=b 2x,/proxy?url=c";
+        assert_eq!(count_proxied(ORIGIN, text), 2);
+        let settings = UrlSettings { style: ProxyUrlStyle::RootRelative, ..UrlSettings::default() };
+        assert_eq!(with_url_settings(settings, || count_proxied(ORIGIN, text)), 3);
+        assert_eq!(count_proxied(ORIGIN, "https://other.site/proxy?url=a"), 0);
+    }
+
+    #[test]
+    fn decode_peels_root_relative_wrapping() {
+        let query = "%2Fproxy%3Furl%3Dhttps://example.com/a";
+        assert_eq!(decode_proxy_url(ORIGIN, query).unwrap(), "https://example.com/a");
+        assert_eq!(
+            decode_proxy_url_with_base(ORIGIN, query, "https://example.com/").as_deref(),
+            Some("https://example.com/a")
+        );
+    }
+
+    #[test]
+    fn foreign_proxy_url_is_preserved() {
+        let original = "https://other.site/proxy?url=https://x/";
+        let encoded = encode_url(ORIGIN, original).unwrap();
+        let query = encoded.split_once("url=").unwrap().1;
+        assert_eq!(decode_proxy_url(ORIGIN, query).unwrap(), original);
+    }
+
+    #[test]
+    fn relative_without_base_is_none() {
+        assert!(encode_url(ORIGIN, "/path/to/page").is_none());
+        assert!(encode_url(ORIGIN, "../x").is_none());
+        assert!(encode_url(ORIGIN, "img.png").is_none());
+    }
+
     #[test]
     fn empty_and_fragment_ignored() {
         assert!(encode_url(ORIGIN, "").is_none());
         assert!(encode_url(ORIGIN, "#top").is_none());
     }
+
+    #[test]
+    fn marker_token_is_shared_by_instances_with_one_secret() {
+        // Two proxy instances, each with its own thread-local state, agree
+        // on the token when configured with the same deployment secret.
+        let token = |secret: &'static str| {
+            std::thread::spawn(move || with_marker_secret(Some(secret.to_string()), || rewritten_token(ORIGIN)))
+                .join()
+                .unwrap()
+        };
+        let first = token("deployment-secret");
+        assert!(first.is_some());
+        assert_eq!(first, token("deployment-secret"));
+        assert_ne!(first, token("another-secret"));
+        assert_eq!(rewritten_token(ORIGIN), None);
+    }
 }